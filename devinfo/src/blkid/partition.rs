@@ -1,15 +1,35 @@
 use crate::{
     blkid::{
-        blkid_partition, blkid_partition_get_name, blkid_partition_get_type_string,
-        blkid_partition_get_uuid, blkid_partlist, blkid_partlist_get_partition,
-        blkid_partlist_get_partition_by_partno, blkid_partlist_numof_partitions, to_result,
+        blkid_partition, blkid_partition_get_flags, blkid_partition_get_name,
+        blkid_partition_get_partno, blkid_partition_get_size, blkid_partition_get_start,
+        blkid_partition_get_table, blkid_partition_get_type, blkid_partition_get_type_string,
+        blkid_partition_get_uuid, blkid_partition_is_extended, blkid_partition_is_logical,
+        blkid_partition_is_primary, blkid_partlist, blkid_partlist_get_partition,
+        blkid_partlist_get_partition_by_partno, blkid_partlist_get_table,
+        blkid_partlist_numof_partitions, blkid_parttable, blkid_parttable_get_id,
+        blkid_parttable_get_offset, blkid_parttable_get_parent, blkid_parttable_get_type,
+        probe::Probe, to_result,
     },
     DevInfoError,
 };
-use std::{ffi::CStr, os::raw::c_int};
-pub struct Partition(pub(crate) blkid_partition);
+use std::{
+    ffi::CStr,
+    marker::PhantomData,
+    os::raw::{c_char, c_int},
+};
+
+fn to_string(ptr: *const c_char) -> Option<String> {
+    if ptr.is_null() {
+        return None;
+    }
+
+    Some(unsafe { CStr::from_ptr(ptr) }.to_string_lossy().to_string())
+}
+
+/// A partition found by the probe, which owns it until the next probing.
+pub struct Partition<'a>(pub(crate) blkid_partition, PhantomData<&'a mut Probe>);
 
-impl Partition {
+impl<'a> Partition<'a> {
     pub fn get_name(&self) -> Option<String> {
         let ptr = unsafe { blkid_partition_get_name(self.0) };
         if ptr.is_null() {
@@ -36,34 +56,198 @@ impl Partition {
 
         Some(unsafe { CStr::from_ptr(ptr) }.to_string_lossy().to_string())
     }
+
+    /// Start of the partition in 512 byte sectors.
+    pub fn get_start(&self) -> u64 {
+        unsafe { blkid_partition_get_start(self.0) as u64 }
+    }
+
+    /// Size of the partition in 512 byte sectors.
+    pub fn get_size(&self) -> u64 {
+        unsafe { blkid_partition_get_size(self.0) as u64 }
+    }
+
+    /// The partition number, as used by the kernel for the device name.
+    pub fn get_partno(&self) -> Result<u32, DevInfoError> {
+        unsafe { to_result(blkid_partition_get_partno(self.0)).map(|v| v as u32) }
+    }
+
+    /// The numeric partition type, i.e 0x83 for a Linux partition in a dos
+    /// table. This is 0 for tables which use GUIDs, see `get_type_string`.
+    pub fn get_type(&self) -> u32 {
+        unsafe { blkid_partition_get_type(self.0) as u32 }
+    }
+
+    /// The partition flags, i.e the GPT attribute bits or the dos boot flag.
+    pub fn get_flags(&self) -> u64 {
+        unsafe { blkid_partition_get_flags(self.0) as u64 }
+    }
+
+    pub fn is_extended(&self) -> bool {
+        unsafe { blkid_partition_is_extended(self.0) == 1 }
+    }
+
+    pub fn is_logical(&self) -> bool {
+        unsafe { blkid_partition_is_logical(self.0) == 1 }
+    }
+
+    pub fn is_primary(&self) -> bool {
+        unsafe { blkid_partition_is_primary(self.0) == 1 }
+    }
+
+    /// The table which contains this partition, which is a nested table for
+    /// logical partitions.
+    pub fn get_table(&self) -> Option<PartTable<'a>> {
+        to_result(unsafe { blkid_partition_get_table(self.0) })
+            .ok()
+            .map(|table| PartTable(table, PhantomData))
+    }
+}
+
+/// A partition table as found by the probe, which owns it until the next
+/// probing.
+pub struct PartTable<'a>(pub(crate) blkid_parttable, PhantomData<&'a mut Probe>);
+
+impl<'a> PartTable<'a> {
+    /// The table type, i.e gpt or dos.
+    pub fn get_type(&self) -> Option<String> {
+        to_string(unsafe { blkid_parttable_get_type(self.0) })
+    }
+
+    /// The GPT disk GUID or the dos disk signature.
+    pub fn get_id(&self) -> Option<String> {
+        to_string(unsafe { blkid_parttable_get_id(self.0) })
+    }
+
+    /// Byte offset of the table on the device.
+    pub fn get_offset(&self) -> Result<u64, DevInfoError> {
+        unsafe { to_result(blkid_parttable_get_offset(self.0)).map(|v| v as u64) }
+    }
+
+    /// The partition which contains this table, for nested tables only.
+    pub fn get_parent(&self) -> Option<Partition<'a>> {
+        to_result(unsafe { blkid_parttable_get_parent(self.0) })
+            .ok()
+            .map(|partition| Partition(partition, PhantomData))
+    }
 }
 
-pub struct PartList(pub(crate) blkid_partlist);
+/// The partitions found by the probe, which owns the list.
+///
+/// ```compile_fail
+/// let mut probe = devinfo::blkid::probe::Probe::new_from_filename("/dev/sda").unwrap();
+/// let table = probe.partitions().unwrap().get_table().unwrap();
+/// drop(probe);
+/// table.get_type();
+/// ```
+///
+/// Probing again frees the list, so nothing found by the previous probing
+/// can be used after it:
+///
+/// ```compile_fail
+/// let mut probe = devinfo::blkid::probe::Probe::new_from_filename("/dev/sda").unwrap();
+/// let table = probe.partitions().unwrap().get_table().unwrap();
+/// probe.partitions().unwrap();
+/// table.get_type();
+/// ```
+pub struct PartList<'a>(
+    pub(crate) blkid_partlist,
+    pub(crate) PhantomData<&'a mut Probe>,
+);
 
-impl PartList {
-    pub fn get_partition(&self, partition: i32) -> Option<Partition> {
+impl<'a> PartList<'a> {
+    pub fn get_partition(&self, partition: i32) -> Option<Partition<'a>> {
         if let Ok(p) =
             to_result(unsafe { blkid_partlist_get_partition(self.0, partition as c_int) })
         {
-            return Some(Partition(p));
+            return Some(Partition(p, PhantomData));
         }
         {
             None
         }
     }
 
-    pub fn get_partition_by_partno(&self, partition: i32) -> Option<Partition> {
+    pub fn get_partition_by_partno(&self, partition: i32) -> Option<Partition<'a>> {
         if let Ok(p) =
             to_result(unsafe { blkid_partlist_get_partition_by_partno(self.0, partition as c_int) })
         {
-            return Some(Partition(p));
+            return Some(Partition(p, PhantomData));
         }
         {
             None
         }
     }
 
+    /// The partition table of the device.
+    pub fn get_table(&self) -> Option<PartTable<'a>> {
+        to_result(unsafe { blkid_partlist_get_table(self.0) })
+            .ok()
+            .map(|table| PartTable(table, PhantomData))
+    }
+
     pub fn numof_partitions(&self) -> Result<u32, DevInfoError> {
         unsafe { to_result(blkid_partlist_numof_partitions(self.0)).map(|v| v as u32) }
     }
 }
+
+#[test]
+fn partition_layout() {
+    use crate::{
+        blkid::probe::Probe,
+        parttable::{PartitionSpec, PartitionTable, TableKind, ATTR_LEGACY_BIOS_BOOTABLE},
+    };
+
+    let path = std::env::temp_dir().join("devinfo_partition_layout.img");
+    std::fs::File::create(&path)
+        .and_then(|f| f.set_len(32 * 1024 * 1024))
+        .unwrap();
+
+    for kind in [TableKind::Gpt, TableKind::Mbr] {
+        let mut table = PartitionTable::create(&path, kind).unwrap();
+        table
+            .add_partition(PartitionSpec {
+                size: Some(8 * 2048),
+                attributes: ATTR_LEGACY_BIOS_BOOTABLE,
+                ..Default::default()
+            })
+            .unwrap();
+        table.add_partition(PartitionSpec::default()).unwrap();
+        table.write().unwrap();
+
+        let mut probe = Probe::new_from_filename(&path).unwrap();
+        let list = probe.partitions().unwrap();
+        let pt = list.get_table().unwrap();
+        assert_eq!(pt.get_type().unwrap(), kind.to_string());
+        assert_eq!(pt.get_id().unwrap(), table.disk_id());
+        // the GPT header is in the second sector, dos entries follow the boot code
+        let offset = if kind == TableKind::Gpt { 512 } else { 446 };
+        assert_eq!(pt.get_offset().unwrap(), offset);
+        assert!(pt.get_parent().is_none());
+
+        let p1 = list.get_partition(0).unwrap();
+        assert_eq!((p1.get_start(), p1.get_size()), (2048, 8 * 2048));
+        assert_eq!(p1.get_partno().unwrap(), 1);
+        assert!(p1.is_primary() && !p1.is_extended() && !p1.is_logical());
+        assert!(p1.get_table().is_some());
+        match kind {
+            TableKind::Gpt => {
+                assert_eq!(p1.get_type(), 0);
+                assert_eq!(p1.get_flags(), ATTR_LEGACY_BIOS_BOOTABLE);
+                assert_eq!(
+                    p1.get_type_string().unwrap(),
+                    "0fc63daf-8483-4772-8e79-3d69d8477de4"
+                );
+            }
+            TableKind::Mbr => {
+                assert_eq!(p1.get_type(), 0x83);
+                assert_eq!(p1.get_flags(), 0x80);
+            }
+        }
+
+        let p2 = list.get_partition_by_partno(2).unwrap();
+        assert_eq!(p2.get_start(), 2048 + 8 * 2048);
+        assert_eq!(p2.get_start() + p2.get_size() - 1, table.last_usable());
+    }
+
+    let _ = std::fs::remove_file(path);
+}
@@ -1,20 +1,100 @@
 use crate::blkid::{
-    blkid_do_probe, blkid_do_safeprobe, blkid_free_probe, blkid_new_probe,
-    blkid_new_probe_from_filename, blkid_probe, blkid_probe_has_value, blkid_probe_lookup_value,
+    blkid_do_probe, blkid_do_safeprobe, blkid_do_wipe, blkid_free_probe, blkid_new_probe,
+    blkid_new_probe_from_filename, blkid_probe, blkid_probe_enable_partitions,
+    blkid_probe_enable_superblocks, blkid_probe_get_partitions, blkid_probe_get_topology,
+    blkid_probe_get_value, blkid_probe_has_value, blkid_probe_lookup_value,
+    blkid_probe_numof_values, blkid_probe_set_device, blkid_probe_set_partitions_flags,
+    blkid_probe_set_superblocks_flags, blkid_topology_get_alignment_offset,
+    blkid_topology_get_logical_sector_size, blkid_topology_get_minimum_io_size,
+    blkid_topology_get_optimal_io_size, blkid_topology_get_physical_sector_size,
+    partition::PartList,
 };
 use core::slice;
 use std::{
     ffi::{CStr, CString},
+    fs::{File, OpenOptions},
+    marker::PhantomData,
+    ops::BitOr,
+    os::{
+        raw::c_int,
+        unix::{fs::OpenOptionsExt, io::AsRawFd},
+    },
     path::Path,
 };
 
 use crate::blkid::to_result;
-pub struct Probe(blkid_probe);
+/// The probe, and the file it reads from when it was not opened by libblkid.
+pub struct Probe(blkid_probe, #[allow(dead_code)] Option<File>);
 use crate::DevInfoError;
 
+/// Values the superblocks chain returns, see blkid_probe_set_superblocks_flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SuperblocksFlags(c_int);
+
+impl SuperblocksFlags {
+    /// filesystem label, LABEL
+    pub const LABEL: Self = Self(1 << 1);
+    /// filesystem UUID, UUID
+    pub const UUID: Self = Self(1 << 3);
+    /// filesystem type, TYPE
+    pub const TYPE: Self = Self(1 << 5);
+    /// usage of the signature (filesystem, raid, crypto or other), USAGE
+    pub const USAGE: Self = Self(1 << 7);
+    /// magic string and its offset, SBMAGIC and SBMAGIC_OFFSET
+    pub const MAGIC: Self = Self(1 << 9);
+    /// also return signatures with a bad checksum
+    pub const BADCSUM: Self = Self(1 << 10);
+    /// the flags libblkid uses by default
+    pub const DEFAULT: Self = Self(Self::LABEL.0 | Self::UUID.0 | Self::TYPE.0 | 1 << 6);
+}
+
+impl BitOr for SuperblocksFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Return the magic string and its offset from the partitions chain, see
+/// blkid_probe_set_partitions_flags.
+const BLKID_PARTS_MAGIC: c_int = 1 << 3;
+
+/// A signature which was found, and unless it was a dry run, erased.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    /// the filesystem, raid or partition table type, i.e ext4 or gpt
+    pub kind: String,
+    /// filesystem, raid, crypto, other or partition_table
+    pub usage: Option<String>,
+    pub label: Option<String>,
+    pub uuid: Option<String>,
+    /// byte offset of the magic string on the device
+    pub offset: u64,
+    pub magic: Vec<u8>,
+}
+
+/// Result of `Probe::wipe_all`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WipeReport {
+    /// nothing was erased, the signatures are only reported
+    pub dry_run: bool,
+    pub signatures: Vec<Signature>,
+}
+
+/// I/O limits and alignment of the probed device, all values are in bytes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Topology {
+    pub alignment_offset: u64,
+    pub minimum_io_size: u64,
+    pub optimal_io_size: u64,
+    pub logical_sector_size: u64,
+    pub physical_sector_size: u64,
+}
+
 impl Probe {
     pub fn new() -> Result<Probe, DevInfoError> {
-        unsafe { Ok(Probe(to_result(blkid_new_probe())?)) }
+        unsafe { Ok(Probe(to_result(blkid_new_probe())?, None)) }
     }
 
     pub fn new_from_filename<P: AsRef<Path>>(path: P) -> Result<Probe, DevInfoError> {
@@ -22,29 +102,91 @@ impl Probe {
             .expect("provided path contained null bytes");
 
         unsafe {
-            Ok(Probe(to_result(blkid_new_probe_from_filename(
-                path.as_ptr(),
-            ))?))
+            Ok(Probe(
+                to_result(blkid_new_probe_from_filename(path.as_ptr()))?,
+                None,
+            ))
         }
     }
 
-    pub fn do_probe(&self) -> Result<bool, DevInfoError> {
+    /// Open the device for writing, which is required to wipe signatures.
+    /// Block devices are opened exclusively, so this fails with EBUSY when
+    /// the device is mounted or otherwise in use.
+    pub fn new_writable<P: AsRef<Path>>(path: P) -> Result<Probe, DevInfoError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(nix::libc::O_EXCL | nix::libc::O_CLOEXEC)
+            .open(path)
+            .map_err(|source| DevInfoError::Io { source })?;
+        let fd = file.as_raw_fd();
+        let probe = Probe(unsafe { to_result(blkid_new_probe())? }, Some(file));
+        unsafe { to_result(blkid_probe_set_device(probe.0, fd, 0, 0))? };
+        Ok(probe)
+    }
+
+    pub fn do_probe(&mut self) -> Result<bool, DevInfoError> {
         unsafe { to_result(blkid_do_probe(self.0)).map(|v| v == 1) }
     }
 
-    pub fn do_safe_probe(&self) -> Result<i32, DevInfoError> {
+    pub fn do_safe_probe(&mut self) -> Result<i32, DevInfoError> {
         unsafe { to_result(blkid_do_safeprobe(self.0)) }
     }
 
-    pub fn has_value(self, name: &str) -> bool {
+    /// Enable or disable the superblocks chain, which is enabled by default.
+    pub fn enable_superblocks(&self, enable: bool) -> Result<(), DevInfoError> {
+        unsafe { to_result(blkid_probe_enable_superblocks(self.0, enable as c_int)).map(|_| ()) }
+    }
+
+    /// Select the values the superblocks chain returns.
+    pub fn set_superblocks_flags(&self, flags: SuperblocksFlags) -> Result<(), DevInfoError> {
+        unsafe { to_result(blkid_probe_set_superblocks_flags(self.0, flags.0)).map(|_| ()) }
+    }
+
+    /// Enable or disable the partitions chain, which is disabled by default.
+    pub fn enable_partitions(&self, enable: bool) -> Result<(), DevInfoError> {
+        unsafe { to_result(blkid_probe_enable_partitions(self.0, enable as c_int)).map(|_| ()) }
+    }
+
+    /// Probe the topology of the device, this does not require the topology
+    /// chain to be enabled. Only block devices have a topology.
+    pub fn topology(&self) -> Result<Topology, DevInfoError> {
+        unsafe {
+            let tp = blkid_probe_get_topology(self.0);
+            if tp.is_null() {
+                return Err(DevInfoError::NotSupported {
+                    value: "topology of a device which is not a block device".to_string(),
+                });
+            }
+            Ok(Topology {
+                alignment_offset: blkid_topology_get_alignment_offset(tp) as u64,
+                minimum_io_size: blkid_topology_get_minimum_io_size(tp) as u64,
+                optimal_io_size: blkid_topology_get_optimal_io_size(tp) as u64,
+                logical_sector_size: blkid_topology_get_logical_sector_size(tp) as u64,
+                physical_sector_size: blkid_topology_get_physical_sector_size(tp) as u64,
+            })
+        }
+    }
+
+    /// Probe the partition table of the device, this does not require the
+    /// partitions chain to be enabled. The list is owned by the probe and
+    /// freed by the next probing, so it borrows the probe exclusively.
+    pub fn partitions(&mut self) -> Result<PartList<'_>, DevInfoError> {
+        let list = unsafe { to_result(blkid_probe_get_partitions(self.0))? };
+        Ok(PartList(list, PhantomData))
+    }
+
+    pub fn has_value(&self, name: &str) -> bool {
         let name = CString::new(name).unwrap();
         let ret = unsafe { blkid_probe_has_value(self.0, name.as_ptr()) };
         ret == 1
     }
 
     /// Fetch a value by name.
-    pub fn lookup_value(self, name: &str) -> Result<String, DevInfoError> {
-        let name = CString::new(name).unwrap();
+    pub fn lookup_value(&self, name: &str) -> Result<String, DevInfoError> {
+        let name = CString::new(name).map_err(|_| DevInfoError::InvalidStr {
+            value: name.to_string(),
+        })?;
         let mut data_ptr = std::ptr::null();
         let mut len = 0;
         unsafe {
@@ -55,14 +197,111 @@ impl Probe {
                 &mut len,
             ))?;
 
-            let str = CStr::from_bytes_with_nul(slice::from_raw_parts(data_ptr.cast(), len))
-                .map_err(|_e| DevInfoError::InvalidStr {})?
-                .to_str()
-                .map_err(|_e| DevInfoError::InvalidStr {})?
+            let bytes = slice::from_raw_parts(data_ptr.cast(), len);
+            let str = CStr::from_bytes_with_nul(bytes)
+                .ok()
+                .and_then(|s| s.to_str().ok())
+                .ok_or_else(|| DevInfoError::InvalidStr {
+                    value: String::from_utf8_lossy(bytes).to_string(),
+                })?
                 .to_string();
             Ok(str)
         }
     }
+
+    /// Fetch a binary value by name, i.e the superblock magic.
+    fn lookup_bytes(&self, name: &str) -> Option<Vec<u8>> {
+        let name = CString::new(name).ok()?;
+        let mut data_ptr = std::ptr::null();
+        let mut len = 0;
+        unsafe {
+            if blkid_probe_lookup_value(self.0, name.as_ptr(), &mut data_ptr, &mut len) != 0
+                || data_ptr.is_null()
+            {
+                return None;
+            }
+            Some(slice::from_raw_parts(data_ptr.cast::<u8>(), len).to_vec())
+        }
+    }
+
+    /// Find every filesystem, raid and partition table signature on the
+    /// device and erase its magic string, unless `dry_run` is set. Erasing
+    /// requires a probe created with `new_writable`.
+    pub fn wipe_all(&mut self, dry_run: bool) -> Result<WipeReport, DevInfoError> {
+        self.enable_superblocks(true)?;
+        self.set_superblocks_flags(
+            SuperblocksFlags::MAGIC
+                | SuperblocksFlags::TYPE
+                | SuperblocksFlags::USAGE
+                | SuperblocksFlags::LABEL
+                | SuperblocksFlags::UUID
+                | SuperblocksFlags::BADCSUM,
+        )?;
+        self.enable_partitions(true)?;
+        unsafe { to_result(blkid_probe_set_partitions_flags(self.0, BLKID_PARTS_MAGIC))? };
+
+        let mut report = WipeReport {
+            dry_run,
+            signatures: Vec::new(),
+        };
+
+        // a successful wipe steps the probe back, so the same area is probed
+        // again and any signature below the erased one is found as well
+        while unsafe { to_result(blkid_do_probe(self.0))? } == 0 {
+            let (kind, prefix) = match self.lookup_value("TYPE") {
+                Ok(kind) => (kind, "SB"),
+                Err(_) => match self.lookup_value("PTTYPE") {
+                    Ok(kind) => (kind, "PT"),
+                    Err(_) => continue,
+                },
+            };
+            // the values are reset by the wipe, so they are read before
+            let signature = Signature {
+                kind,
+                usage: self.lookup_value("USAGE").ok(),
+                label: self.lookup_value("LABEL").ok(),
+                uuid: self.lookup_value("UUID").ok(),
+                offset: self
+                    .lookup_value(&format!("{}MAGIC_OFFSET", prefix))
+                    .ok()
+                    .and_then(|o| o.parse().ok())
+                    .unwrap_or_default(),
+                magic: self
+                    .lookup_bytes(&format!("{}MAGIC", prefix))
+                    .unwrap_or_default(),
+            };
+
+            unsafe { to_result(blkid_do_wipe(self.0, dry_run as c_int))? };
+            report.signatures.push(signature);
+        }
+
+        Ok(report)
+    }
+
+    /// All name/value pairs found by the last probe. Binary values, such as
+    /// the superblock magic, are converted lossily.
+    pub fn values(&self) -> impl Iterator<Item = (String, String)> + '_ {
+        let count = unsafe { blkid_probe_numof_values(self.0) }.max(0);
+        (0 .. count).filter_map(move |n| {
+            let mut name_ptr = std::ptr::null();
+            let mut data_ptr = std::ptr::null();
+            let mut len = 0;
+            unsafe {
+                if blkid_probe_get_value(self.0, n, &mut name_ptr, &mut data_ptr, &mut len) != 0
+                    || name_ptr.is_null()
+                    || data_ptr.is_null()
+                {
+                    return None;
+                }
+                let data = slice::from_raw_parts(data_ptr.cast::<u8>(), len);
+                let data = data.strip_suffix(&[0]).unwrap_or(data);
+                Some((
+                    CStr::from_ptr(name_ptr).to_string_lossy().to_string(),
+                    String::from_utf8_lossy(data).to_string(),
+                ))
+            }
+        })
+    }
 }
 
 impl Drop for Probe {
@@ -76,3 +315,86 @@ impl Drop for Probe {
         }
     }
 }
+
+#[test]
+fn probe_values() {
+    let path = std::env::temp_dir().join("devinfo_probe_values.img");
+    std::fs::File::create(&path)
+        .and_then(|f| f.set_len(16 * 1024 * 1024))
+        .unwrap();
+    let mkfs = std::process::Command::new("mkfs.ext4")
+        .args(["-q", "-F", "-L", "probe"])
+        .arg(&path)
+        .status();
+    if !mkfs.map(|s| s.success()).unwrap_or(false) {
+        println!("mkfs.ext4 not available, skipping");
+        return;
+    }
+
+    let mut probe = Probe::new_from_filename(&path).unwrap();
+    probe
+        .set_superblocks_flags(
+            SuperblocksFlags::LABEL
+                | SuperblocksFlags::UUID
+                | SuperblocksFlags::TYPE
+                | SuperblocksFlags::USAGE,
+        )
+        .unwrap();
+    assert_eq!(probe.do_safe_probe().unwrap(), 0);
+
+    // the probe is not consumed by the lookups
+    assert!(probe.has_value("TYPE"));
+    assert_eq!(probe.lookup_value("TYPE").unwrap(), "ext4");
+    assert_eq!(probe.lookup_value("LABEL").unwrap(), "probe");
+    let values = probe.values().collect::<std::collections::HashMap<_, _>>();
+    assert_eq!(values.get("USAGE").map(String::as_str), Some("filesystem"));
+    assert!(values.contains_key("UUID"));
+
+    // a regular file has no topology
+    assert!(probe.topology().is_err());
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn probe_wipe_all() {
+    let path = std::env::temp_dir().join("devinfo_probe_wipe_all.img");
+    std::fs::File::create(&path)
+        .and_then(|f| f.set_len(16 * 1024 * 1024))
+        .unwrap();
+    let mkfs = std::process::Command::new("mkfs.ext4")
+        .args(["-q", "-F", "-L", "wipe"])
+        .arg(&path)
+        .status();
+    if !mkfs.map(|s| s.success()).unwrap_or(false) {
+        println!("mkfs.ext4 not available, skipping");
+        return;
+    }
+
+    let check = |report: &WipeReport| {
+        assert_eq!(report.signatures.len(), 1);
+        let sig = &report.signatures[0];
+        assert_eq!(sig.kind, "ext4");
+        assert_eq!(sig.usage.as_deref(), Some("filesystem"));
+        assert_eq!(sig.label.as_deref(), Some("wipe"));
+        assert_eq!(
+            (sig.offset, sig.magic.as_slice()),
+            (1080, &[0x53, 0xef][..])
+        );
+    };
+    let report = Probe::new_writable(&path).unwrap().wipe_all(true).unwrap();
+    assert!(report.dry_run);
+    check(&report);
+
+    // nothing was erased by the dry run
+    let mut probe = Probe::new_from_filename(&path).unwrap();
+    assert_eq!(probe.do_safe_probe().unwrap(), 0);
+
+    let report = Probe::new_writable(&path).unwrap().wipe_all(false).unwrap();
+    assert!(!report.dry_run);
+    check(&report);
+    let mut probe = Probe::new_from_filename(&path).unwrap();
+    assert_eq!(probe.do_safe_probe().unwrap(), 1);
+
+    let _ = std::fs::remove_file(path);
+}
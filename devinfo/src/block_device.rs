@@ -1,71 +1,161 @@
-use std::{convert::TryFrom, iter::Iterator};
+use snafu::ResultExt;
+use std::{convert::TryFrom, iter::Iterator, os::unix::fs::FileTypeExt, path::Path};
 #[cfg(target_os = "linux")]
 use udev::Enumerator;
 use url::Url;
 use uuid::Uuid;
 
 #[cfg(target_os = "linux")]
-use crate::DevInfoError::Udev;
-use crate::{DevInfoError, DevInfoError::NqnInvalid};
+use crate::{nvme::NvmeQuery, Udev};
+use crate::{
+    scsi::IscsiTarget, DevInfoError, InvalidParameter, InvalidPath, InvalidUri, InvalidUuid,
+    NqnInvalid, UnsupportedScheme,
+};
 
 pub(crate) type Failable<T, E = DevInfoError> = std::result::Result<T, E>;
 
+/// Optional query parameters of a device URI, i.e the uuid and blk_size in
+/// aio:///dev/sdb?blk_size=4096&uuid=9bcc7abd-5cd2-4d6e-a1d0-e4a2b9e5d2b7
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BlkDevParams {
+    pub uuid: Option<Uuid>,
+    pub blk_size: Option<u32>,
+    pub size_mb: Option<u64>,
+    /// the namespace id of an NVMe-oF device
+    pub nsid: Option<u32>,
+}
+
+impl TryFrom<&Url> for BlkDevParams {
+    type Error = DevInfoError;
+
+    fn try_from(url: &Url) -> Failable<Self> {
+        let mut params = BlkDevParams::default();
+        let uri = url.as_str();
+        for (key, value) in url.query_pairs() {
+            let invalid = || InvalidParameter {
+                uri,
+                key: key.as_ref(),
+                value: value.as_ref(),
+            };
+            match key.as_ref() {
+                "uuid" => params.uuid = Some(Uuid::parse_str(&value).context(InvalidUuid { uri })?),
+                "blk_size" => params.blk_size = Some(value.parse().with_context(|_| invalid())?),
+                "size_mb" => params.size_mb = Some(value.parse().with_context(|_| invalid())?),
+                "nsid" => params.nsid = Some(value.parse().with_context(|_| invalid())?),
+                // other parameters are of no interest to the host
+                _ => {}
+            }
+        }
+        Ok(params)
+    }
+}
+
 #[derive(Debug)]
 pub enum BlkDev {
-    Scsi(Uuid),
-    Nvmf(Uuid),
+    /// The LUN of an iSCSI target, the uuid is always set
+    Scsi(IscsiTarget),
+    /// The NQN of the subsystem, or only its prefix when the UUID was given
+    /// as the uuid parameter, which is always set
+    Nvmf(String, BlkDevParams),
     File(String),
+    Aio(String, BlkDevParams),
+    Uring(String, BlkDevParams),
+    /// PCI address of an NVMe device, i.e 0000:01:00.0
+    Pcie(String),
+    Malloc(String, BlkDevParams),
+    Bdev(String, BlkDevParams),
 }
 
 impl TryFrom<&str> for BlkDev {
     type Error = DevInfoError;
     /// convert a URI in the form of
     ///
-    /// scheme:://hostname/first_segment/second/segment
+    /// scheme:://hostname/first_segment/second/segment?key=value
     ///
-    /// When parsing the scheme iscsi:// or nvmf:// on the first segment is
-    /// considered when dealing with the file:// scheme, we dont do much but concatenate the two segments
+    /// When parsing the scheme iscsi://, nvmf:// or nvmf+tcp:// only the first
+    /// segment is considered, which is expected to be of the form nqn:uuid.
+    /// When the NQN does not contain a UUID, the uuid query parameter is used
+    /// instead. For NVMe-oF the namespace may be selected with the nsid query
+    /// parameter. For the file://, aio:// and uring:// schemes the full path is
+    /// used, the name of malloc:// and bdev:// devices is the first segment.
     fn try_from(uri: &str) -> Failable<Self> {
-        let value = Url::parse(uri).map_err(|e| DevInfoError::ParseError {
-            value: e.to_string(),
-        })?;
+        let value = Url::parse(uri).context(InvalidUri { uri })?;
 
-        let mut nq = value
-            .path_segments()
-            .ok_or_else(|| DevInfoError::NotSupported {
-                value: "The uri contains contains no path segments".to_string(),
-            })?;
-
-        // for files we dont have to do much, simply merge the first two
-        // elements of the path segment
-        if value.scheme() == "file" {
-            return Ok(BlkDev::File(format!(
-                "{}/{}",
-                nq.next().unwrap(),
-                nq.next().unwrap()
-            )));
-        }
+        let mut nq = value.path_segments().ok_or_else(|| {
+            InvalidPath {
+                uri,
+                expected: "path",
+            }
+            .build()
+        })?;
+        let params = BlkDevParams::try_from(&value)?;
 
-        // this is not a file scheme so we should have a nqn:uuid type layout
-        // here
-        let nq = nq.next().unwrap().split(':').collect::<Vec<_>>();
+        let path = || -> Failable<String> {
+            let path = value
+                .to_file_path()
+                .ok()
+                .filter(|p| p.parent().is_some())
+                .ok_or_else(|| {
+                    InvalidPath {
+                        uri,
+                        expected: "path",
+                    }
+                    .build()
+                })?;
+            path.to_str()
+                .map(str::to_string)
+                .ok_or_else(|| DevInfoError::InvalidStr {
+                    value: path.display().to_string(),
+                })
+        };
 
-        if nq.len() != 2 {
-            return Err(DevInfoError::NqnInvalid {
-                value: "the NQN does not contain the expected separator \':\'".to_string(),
-            });
+        match value.scheme() {
+            "file" => return Ok(BlkDev::File(path()?)),
+            "aio" => return Ok(BlkDev::Aio(path()?, params)),
+            "uring" => return Ok(BlkDev::Uring(path()?, params)),
+            "pcie" | "malloc" | "bdev" => {
+                let name = nq
+                    .next()
+                    .filter(|s| !s.is_empty())
+                    .ok_or_else(|| {
+                        InvalidPath {
+                            uri,
+                            expected: "device name",
+                        }
+                        .build()
+                    })?
+                    .to_string();
+                return Ok(match value.scheme() {
+                    "pcie" => BlkDev::Pcie(name),
+                    "malloc" => BlkDev::Malloc(name, params),
+                    _ => BlkDev::Bdev(name, params),
+                });
+            }
+            _ => {}
         }
 
-        let uuid = Uuid::parse_str(nq[1]).map_err(|e| NqnInvalid {
-            value: format!("the UUID is invalid {e}"),
-        })?;
+        // this is not a path based scheme so we should have a nqn:uuid type
+        // layout here, unless the uuid is given as parameter
+        let nqn = nq.next().unwrap_or_default();
+        let uuid = match (params.uuid, nqn.rsplit_once(':')) {
+            (Some(uuid), _) => uuid,
+            (None, Some((_, uuid))) => Uuid::parse_str(uuid).context(InvalidUuid { uri })?,
+            (None, None) => return NqnInvalid { uri }.fail(),
+        };
 
         match value.scheme() {
-            "iscsi" => Ok(BlkDev::Scsi(uuid)),
-            "nvmf" => Ok(BlkDev::Nvmf(uuid)),
-            scheme => Err(DevInfoError::NotSupported {
-                value: scheme.to_string(),
-            }),
+            "iscsi" => Ok(BlkDev::Scsi(IscsiTarget {
+                uuid: Some(uuid),
+                ..IscsiTarget::try_from(&value)?
+            })),
+            "nvmf" | "nvmf+tcp" => Ok(BlkDev::Nvmf(
+                nqn.to_string(),
+                BlkDevParams {
+                    uuid: Some(uuid),
+                    ..params
+                },
+            )),
+            scheme => UnsupportedScheme { uri, scheme }.fail(),
         }
     }
 }
@@ -75,40 +165,142 @@ impl BlkDev {
     /// based on the URI's path segment, look for properties that will match
     /// the UUID. Right now we try to match only one property, but an array
     /// of properties could be matched on as well.
+    ///
+    /// iSCSI devices are looked up through the sessions to their target, see
+    /// `IscsiTarget::lookup`, and failing that by the serial of the LUN which
+    /// has to contain the UUID.
+    ///
+    /// NVMe-oF devices are looked up by the NQN of their subsystem and their
+    /// namespace UUID (and id, when given), see `NvmeQuery::lookup`.
+    ///
+    /// Path based devices are resolved to the device node they point to, and
+    /// PCIe devices to the disk which is attached to the PCI function. Malloc
+    /// and bdev devices only exist within the io-engine so they can not be
+    /// looked up.
     #[cfg(target_os = "linux")]
     pub fn lookup(&self) -> Failable<String> {
-        let mut enumerator = Enumerator::new().map_err(|value| Udev {
-            value: value.to_string(),
-        })?;
+        match self {
+            BlkDev::Scsi(target) => Self::lookup_scsi(target),
+            BlkDev::Nvmf(nqn, params) => Self::lookup_nvmf(nqn, params),
+            BlkDev::File(path) | BlkDev::Aio(path, _) | BlkDev::Uring(path, _) => {
+                Self::lookup_path(path)
+            }
+            BlkDev::Pcie(addr) => Self::lookup_pcie(addr),
+            BlkDev::Malloc(name, _) | BlkDev::Bdev(name, _) => Err(DevInfoError::NotSupported {
+                value: format!("{name} has no device node on the host"),
+            }),
+        }
+    }
 
-        // we are only interested in disks not partitions
-        enumerator.match_subsystem("block").unwrap();
-        enumerator.match_property("DEVTYPE", "disk").unwrap();
+    #[cfg(target_os = "linux")]
+    fn lookup_scsi(target: &IscsiTarget) -> Failable<String> {
+        match (target.lookup(), target.uuid) {
+            (Err(DevInfoError::NotFound { .. }), Some(uuid)) => {
+                Self::lookup_property("SCSI_IDENT_SERIAL", &uuid.to_string())
+            }
+            (result, _) => result.map(|path| path.display().to_string()),
+        }
+    }
 
-        // look for a specific propertie(s) given the device type
-        let (prop, value) = match self {
-            BlkDev::Scsi(uuid) => ("SCSI_IDENT_SERIAL", uuid.to_string()),
-            BlkDev::Nvmf(uuid) => ("ID_WWN", uuid.to_string()),
-            BlkDev::File(uuid) => ("DEVNAME", uuid.to_string()),
-        };
+    /// The first disk which has a udev property containing the value.
+    #[cfg(target_os = "linux")]
+    fn lookup_property(prop: &str, value: &str) -> Failable<String> {
+        let mut enumerator = Self::disk_enumerator()?;
 
         // traverse the device tree and match the value, we stop at first match
-        for dev in enumerator.scan_devices().unwrap() {
-            if let Some(udev_value) = dev.property_value(prop) {
-                if udev_value.to_str().unwrap().contains(&value) {
-                    return Ok(dev
-                        .property_value("DEVNAME")
-                        .unwrap()
-                        .to_str()
-                        .unwrap()
-                        .to_string());
-                }
+        for dev in enumerator.scan_devices().context(Udev)? {
+            let matches = dev
+                .property_value(prop)
+                .is_some_and(|v| v.to_string_lossy().contains(value));
+            if let (true, Some(devname)) = (matches, dev.devnode()) {
+                return devname.to_str().map(str::to_string).ok_or_else(|| {
+                    DevInfoError::InvalidStr {
+                        value: devname.display().to_string(),
+                    }
+                });
             }
         }
 
         // fall through
-        Err(DevInfoError::NotFound { path: value })
+        Err(DevInfoError::NotFound {
+            path: value.to_string(),
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    fn lookup_nvmf(nqn: &str, params: &BlkDevParams) -> Failable<String> {
+        let path = Self::nvmf_query(nqn, params).lookup()?;
+        Ok(path.display().to_string())
+    }
+
+    /// The query for an NVMe-oF device. An NQN with a name, i.e nqn:uuid or
+    /// nqn:nexus-name, is the full NQN of the subsystem. Otherwise the uuid
+    /// parameter was given and the NQN is no more than a prefix, which is not
+    /// matched on.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn nvmf_query(nqn: &str, params: &BlkDevParams) -> NvmeQuery {
+        NvmeQuery {
+            nqn: Some(nqn.to_string()).filter(|nqn| nqn.contains(':')),
+            nsid: params.nsid,
+            uuid: params.uuid,
+            ..Default::default()
+        }
+    }
+
+    /// Enumerator which only matches disks, not partitions.
+    #[cfg(target_os = "linux")]
+    fn disk_enumerator() -> Failable<Enumerator> {
+        let mut enumerator = Enumerator::new().context(Udev)?;
+
+        enumerator.match_subsystem("block").context(Udev)?;
+        enumerator.match_property("DEVTYPE", "disk").context(Udev)?;
+        Ok(enumerator)
     }
+
+    /// Resolve any symlinks (i.e /dev/disk/by-id) to the device node.
+    #[cfg(target_os = "linux")]
+    fn lookup_path(path: &str) -> Failable<String> {
+        let io_error = |source: std::io::Error| match source.kind() {
+            std::io::ErrorKind::NotFound => DevInfoError::NotFound {
+                path: path.to_string(),
+            },
+            _ => DevInfoError::Io { source },
+        };
+        let canonical = Path::new(path).canonicalize().map_err(io_error)?;
+        let meta = std::fs::metadata(&canonical).map_err(io_error)?;
+        if !meta.file_type().is_block_device() {
+            return Err(DevInfoError::NotSupported {
+                value: format!("{path} is not a block device"),
+            });
+        }
+        canonical
+            .to_str()
+            .map(str::to_string)
+            .ok_or_else(|| DevInfoError::InvalidStr {
+                value: canonical.display().to_string(),
+            })
+    }
+
+    /// Find the disk which sits below the given PCI function in the device
+    /// tree. When the device is bound to vfio or uio, as is the case when the
+    /// io-engine is using it, there is no such disk.
+    #[cfg(target_os = "linux")]
+    fn lookup_pcie(addr: &str) -> Failable<String> {
+        let mut enumerator = Self::disk_enumerator()?;
+
+        for dev in enumerator.scan_devices().context(Udev)? {
+            if dev.syspath().components().any(|c| c.as_os_str() == addr) {
+                if let Some(name) = dev.devnode() {
+                    return Ok(name.display().to_string());
+                }
+            }
+        }
+
+        Err(DevInfoError::NotFound {
+            path: addr.to_string(),
+        })
+    }
+
     /// Dummy lookup impl.
     #[cfg(not(target_os = "linux"))]
     pub fn lookup(&self) -> Failable<String> {
@@ -117,3 +309,109 @@ impl BlkDev {
         })
     }
 }
+
+#[test]
+fn blkdev_uri_schemes() {
+    let uuid = "00000000-76b6-4fcf-864d-1027d4038756";
+
+    let nqn = format!("nqn.2019-05.io.openebs:{uuid}");
+    let dev = BlkDev::try_from(format!("nvmf+tcp://host:4420/{nqn}?nsid=2").as_str());
+    assert!(matches!(
+        dev,
+        Ok(BlkDev::Nvmf(n, BlkDevParams { uuid: Some(u), nsid: Some(2), .. }))
+            if n == nqn && u.to_string() == uuid
+    ));
+    let dev = BlkDev::try_from(format!("nvmf://host/nqn.2019-05.io.openebs?uuid={uuid}").as_str());
+    assert!(matches!(
+        dev,
+        Ok(BlkDev::Nvmf(n, BlkDevParams { uuid: Some(u), .. }))
+            if n == "nqn.2019-05.io.openebs" && u.to_string() == uuid
+    ));
+
+    // the uuid parameter takes precedence over the suffix of the NQN
+    let other = "9bcc7abd-5cd2-4d6e-a1d0-e4a2b9e5d2b7";
+    for nqn in [
+        "nqn.2019-05.io.openebs:nexus-foo".to_string(),
+        format!("nqn.2014-08.org.nvmexpress:uuid:{uuid}"),
+    ] {
+        let dev = BlkDev::try_from(format!("nvmf://host/{nqn}?uuid={other}").as_str());
+        assert!(matches!(
+            dev,
+            Ok(BlkDev::Nvmf(n, BlkDevParams { uuid: Some(u), .. }))
+                if n == nqn && u.to_string() == other
+        ));
+    }
+    let dev =
+        BlkDev::try_from(format!("nvmf://host/nqn.2014-08.org.nvmexpress:uuid:{uuid}").as_str());
+    assert!(matches!(
+        dev,
+        Ok(BlkDev::Nvmf(_, BlkDevParams { uuid: Some(u), .. })) if u.to_string() == uuid
+    ));
+
+    let dev =
+        BlkDev::try_from(format!("iscsi://10.1.0.4:3261/iqn.2019-05.io.openebs:{uuid}/2").as_str());
+    assert!(matches!(
+        dev,
+        Ok(BlkDev::Scsi(t)) if t.lun == 2 && t.port == 3261 && t.uuid.unwrap().to_string() == uuid
+    ));
+    let dev = BlkDev::try_from(
+        format!("iscsi://10.1.0.4/iqn.2019-05.io.openebs:{uuid}/2?uuid={other}").as_str(),
+    );
+    assert!(matches!(dev, Ok(BlkDev::Scsi(t)) if t.uuid.unwrap().to_string() == other));
+
+    let dev = BlkDev::try_from("file:///dev/disk/by-id/nvme-eui.0001").unwrap();
+    assert!(matches!(dev, BlkDev::File(p) if p == "/dev/disk/by-id/nvme-eui.0001"));
+
+    let dev = BlkDev::try_from(format!("aio:///dev/sdb?blk_size=4096&uuid={uuid}").as_str());
+    match dev.unwrap() {
+        BlkDev::Aio(path, params) => {
+            assert_eq!(path, "/dev/sdb");
+            assert_eq!(params.blk_size, Some(4096));
+            assert_eq!(params.uuid.unwrap().to_string(), uuid);
+        }
+        dev => panic!("unexpected device {:?}", dev),
+    }
+    assert!(matches!(
+        BlkDev::try_from("uring:///dev/mapper/vg-lv"),
+        Ok(BlkDev::Uring(p, _)) if p == "/dev/mapper/vg-lv"
+    ));
+    assert!(matches!(
+        BlkDev::try_from("pcie:///0000:01:00.0"),
+        Ok(BlkDev::Pcie(a)) if a == "0000:01:00.0"
+    ));
+    assert!(matches!(
+        BlkDev::try_from("malloc:///m0?size_mb=64"),
+        Ok(BlkDev::Malloc(n, BlkDevParams { size_mb: Some(64), .. })) if n == "m0"
+    ));
+    assert!(matches!(BlkDev::try_from("bdev:///b0"), Ok(BlkDev::Bdev(n, _)) if n == "b0"));
+
+    assert!(matches!(
+        BlkDev::try_from("not a uri"),
+        Err(DevInfoError::InvalidUri { .. })
+    ));
+    assert!(matches!(
+        BlkDev::try_from("aio:///dev/sdb?blk_size=abc"),
+        Err(DevInfoError::InvalidParameter { key, value, .. }) if key == "blk_size" && value == "abc"
+    ));
+    assert!(matches!(
+        BlkDev::try_from("nvmf://host/nqn.2019-05.io.openebs:abc"),
+        Err(DevInfoError::InvalidUuid { .. })
+    ));
+    assert!(matches!(
+        BlkDev::try_from("malloc:///"),
+        Err(DevInfoError::InvalidPath { .. })
+    ));
+    assert!(matches!(
+        BlkDev::try_from("nvmf://host/nqn.2019-05.io.openebs"),
+        Err(DevInfoError::NqnInvalid { .. })
+    ));
+    assert!(matches!(
+        BlkDev::try_from(format!("foo://host/nqn:{uuid}").as_str()),
+        Err(DevInfoError::UnsupportedScheme { scheme, .. }) if scheme == "foo"
+    ));
+    assert!(BlkDev::try_from("malloc:///m0").unwrap().lookup().is_err());
+    let missing = BlkDev::try_from("file:///dev/devinfo-missing")
+        .unwrap()
+        .lookup();
+    assert!(matches!(&missing, Err(e) if e.is_transient()));
+}
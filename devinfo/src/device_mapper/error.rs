@@ -0,0 +1,32 @@
+use crate::DevInfoError;
+use snafu::Snafu;
+use std::path::PathBuf;
+
+pub type Result<T, E = DmError> = std::result::Result<T, E>;
+
+/// Errors of the device-mapper inspection.
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)), context(suffix(false)))]
+pub enum DmError {
+    #[snafu(display("Failed to look up {}: {}", device.display(), source))]
+    Device {
+        device: PathBuf,
+        source: DevInfoError,
+    },
+    #[snafu(display("{} is not a device-mapper device", device.display()))]
+    NotDeviceMapper { device: PathBuf },
+    #[snafu(display("Failed to open {}: {}", path.display(), source))]
+    Open {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("Failed to get the table status of {}: {}", name, source))]
+    TableStatus { name: String, source: nix::Error },
+    #[snafu(display("Invalid table status of {}", name))]
+    InvalidStatus { name: String },
+    #[snafu(display("Failed to probe {}: {}", device.display(), source))]
+    Probe {
+        device: PathBuf,
+        source: DevInfoError,
+    },
+}
@@ -0,0 +1,436 @@
+//! Inspect device-mapper devices, such as LVM logical volumes and dm-crypt
+//! volumes, and find out why a disk is used by one. The targets of a device
+//! are read with the DM_TABLE_STATUS ioctl, nothing is ever changed.
+
+use crate::{
+    blkid::probe::{Probe as BlkidProbe, SuperblocksFlags},
+    topology::{BlockNode, DeviceKind, Topology},
+};
+use error::{Device, InvalidStatus, NotDeviceMapper, Open, Probe, TableStatus};
+use snafu::ResultExt;
+use std::{
+    convert::TryInto,
+    fmt::{Display, Formatter},
+    fs::{self, File},
+    mem::size_of,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+pub use error::{DmError, Result};
+
+/// Errors of the device-mapper inspection.
+pub mod error;
+
+const SYS_CLASS_BLOCK: &str = "/sys/class/block";
+const DEV_MAPPER: &str = "/dev/mapper";
+const DM_CONTROL: &str = "/dev/mapper/control";
+const DM_NAME_LEN: usize = 128;
+const DM_UUID_LEN: usize = 129;
+const DM_BUFFER_FULL_FLAG: u32 = 1 << 8;
+/// The size of the buffer the table status is first read into, it is grown
+/// up to DM_MAX_BUFFER when the kernel reports it is too small.
+const DM_BUFFER: usize = 16 * 1024;
+const DM_MAX_BUFFER: usize = 1024 * 1024;
+/// The size of struct dm_target_spec, which is followed by the status.
+const DM_TARGET_SPEC_LEN: usize = 40;
+
+/// struct dm_ioctl from linux/dm-ioctl.h
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DmIoctl {
+    version: [u32; 3],
+    data_size: u32,
+    data_start: u32,
+    target_count: u32,
+    open_count: i32,
+    flags: u32,
+    event_nr: u32,
+    padding: u32,
+    dev: u64,
+    name: [u8; DM_NAME_LEN],
+    uuid: [u8; DM_UUID_LEN],
+    data: [u8; 7],
+}
+
+nix::ioctl_readwrite!(dm_table_status, 0xfd, 12, DmIoctl);
+
+fn read_attr(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// A device-mapper device as found in sysfs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DmDevice {
+    /// kernel name, i.e dm-0
+    pub name: String,
+    /// the device-mapper name, i.e vg0-lv0
+    pub dm_name: String,
+    /// i.e LVM-<vg uuid><lv uuid> or CRYPT-LUKS2-<uuid>-<name>
+    pub uuid: Option<String>,
+    pub suspended: bool,
+    /// Lvm, Crypt, Multipath or DeviceMapper
+    pub kind: DeviceKind,
+    pub major: u32,
+    pub minor: u32,
+}
+
+/// A target of the table of a device-mapper device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DmTarget {
+    /// in 512 byte sectors
+    pub start: u64,
+    /// in 512 byte sectors
+    pub length: u64,
+    /// i.e linear, striped, crypt, thin-pool or multipath
+    pub target_type: String,
+    /// the status as reported by the target, which never contains keys
+    pub status: String,
+}
+
+impl DmDevice {
+    /// Look up the device by its kernel name (dm-0), device node or any
+    /// symlink to it (/dev/mapper/vg0-lv0).
+    pub fn new<P: AsRef<Path>>(device: P) -> Result<Self> {
+        let device = device.as_ref();
+        let node = BlockNode::new(device).context(Device { device })?;
+        Self::from_node(node).ok_or_else(|| NotDeviceMapper { device }.build())
+    }
+
+    /// Look up the device by its device-mapper name.
+    pub fn from_dm_name(dm_name: &str) -> Option<Self> {
+        list().into_iter().find(|device| device.dm_name == dm_name)
+    }
+
+    fn from_node(node: BlockNode) -> Option<Self> {
+        let dm = Path::new(SYS_CLASS_BLOCK).join(&node.name).join("dm");
+        Some(Self {
+            dm_name: node.dm_name?,
+            uuid: read_attr(&dm.join("uuid")),
+            suspended: read_attr(&dm.join("suspended")).as_deref() == Some("1"),
+            name: node.name,
+            kind: node.kind,
+            major: node.major,
+            minor: node.minor,
+        })
+    }
+
+    /// The path of the device in /dev/mapper.
+    pub fn mapper_path(&self) -> PathBuf {
+        Path::new(DEV_MAPPER).join(&self.dm_name)
+    }
+
+    /// The volume group and logical volume name of an LVM volume, which are
+    /// joined by a dash in the device-mapper name with any dash in either
+    /// name doubled.
+    pub fn lv_name(&self) -> Option<(String, String)> {
+        if self.kind != DeviceKind::Lvm {
+            return None;
+        }
+        let bytes = self.dm_name.as_bytes();
+        let mut index = 0;
+        while index < bytes.len() {
+            match (bytes[index], bytes.get(index + 1)) {
+                (b'-', Some(b'-')) => index += 2,
+                (b'-', _) => {
+                    let (vg, lv) = self.dm_name.split_at(index);
+                    return Some((vg.replace("--", "-"), lv[1 ..].replace("--", "-")));
+                }
+                _ => index += 1,
+            }
+        }
+        None
+    }
+
+    /// The targets of the active table, with their status.
+    pub fn targets(&self) -> Result<Vec<DmTarget>> {
+        let name = || TableStatus {
+            name: self.dm_name.clone(),
+        };
+        let control = File::open(DM_CONTROL).context(Open { path: DM_CONTROL })?;
+        if self.dm_name.len() >= DM_NAME_LEN {
+            return Err(nix::errno::Errno::ENAMETOOLONG).with_context(|_| name());
+        }
+
+        let mut size = DM_BUFFER;
+        loop {
+            // a buffer of u64 to align the header
+            let mut buffer = vec![0u64; size / 8];
+            // SAFETY: the buffer is zeroed, aligned and larger than the header
+            let header = unsafe { &mut *(buffer.as_mut_ptr() as *mut DmIoctl) };
+            header.version = [4, 0, 0];
+            header.data_size = size as u32;
+            header.data_start = size_of::<DmIoctl>() as u32;
+            header.name[.. self.dm_name.len()].copy_from_slice(self.dm_name.as_bytes());
+            // SAFETY: the kernel writes no more than data_size bytes
+            unsafe { dm_table_status(control.as_raw_fd(), header) }.with_context(|_| name())?;
+
+            let (flags, start, end) = (header.flags, header.data_start, header.data_size);
+            let count = header.target_count;
+            if flags & DM_BUFFER_FULL_FLAG != 0 && size < DM_MAX_BUFFER {
+                size *= 4;
+                continue;
+            }
+            // SAFETY: the buffer is viewed as the bytes it consists of
+            let bytes = unsafe { std::slice::from_raw_parts(buffer.as_ptr() as *const u8, size) };
+            let data = match flags & DM_BUFFER_FULL_FLAG {
+                0 => bytes.get(start as usize .. end as usize),
+                _ => None,
+            };
+            return data
+                .and_then(|data| parse_targets(data, count))
+                .ok_or_else(|| {
+                    InvalidStatus {
+                        name: self.dm_name.clone(),
+                    }
+                    .build()
+                });
+        }
+    }
+}
+
+/// Parse the struct dm_target_spec, each followed by its status, which the
+/// kernel places in the data of the ioctl. The offset of the next target is
+/// relative to the start of the data.
+fn parse_targets(data: &[u8], count: u32) -> Option<Vec<DmTarget>> {
+    let c_str = |bytes: &[u8]| {
+        let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[.. end]).to_string()
+    };
+    let mut targets = Vec::new();
+    let mut offset = 0;
+    for _ in 0 .. count {
+        let spec = data.get(offset .. offset + DM_TARGET_SPEC_LEN)?;
+        let u64_at = |at: usize| Some(u64::from_ne_bytes(spec[at .. at + 8].try_into().ok()?));
+        targets.push(DmTarget {
+            start: u64_at(0)?,
+            length: u64_at(8)?,
+            target_type: c_str(&spec[24 ..]),
+            status: c_str(data.get(offset + DM_TARGET_SPEC_LEN ..)?),
+        });
+        offset = u32::from_ne_bytes(spec[20 .. 24].try_into().ok()?) as usize;
+    }
+    Some(targets)
+}
+
+/// All device-mapper devices.
+pub fn list() -> Vec<DmDevice> {
+    let mut devices = fs::read_dir(SYS_CLASS_BLOCK)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .filter(|name| name.starts_with("dm-"))
+                .filter_map(|name| DmDevice::from_node(BlockNode::new(name).ok()?))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    devices.sort_by_key(|device| device.minor);
+    devices
+}
+
+/// The LVM signature of a physical volume.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhysicalVolume {
+    pub device: PathBuf,
+    /// the PV UUID, i.e Z1Dbpo-uPhE-PPxQ-LHDs-qn1t-Xvq8-VMXrLb
+    pub uuid: Option<String>,
+}
+
+/// Detect the LVM physical volume signature on the device. The volume group
+/// is not necessarily active, or even complete.
+pub fn physical_volume<P: AsRef<Path>>(device: P) -> Result<Option<PhysicalVolume>> {
+    let device = device.as_ref();
+    let context = || Probe { device };
+    let mut probe = BlkidProbe::new_from_filename(device).with_context(|_| context())?;
+    probe
+        .set_superblocks_flags(SuperblocksFlags::TYPE | SuperblocksFlags::UUID)
+        .with_context(|_| context())?;
+
+    if probe.do_safe_probe().with_context(|_| context())? != 0 {
+        return Ok(None);
+    }
+    Ok(match probe.lookup_value("TYPE").ok().as_deref() {
+        Some("LVM2_member") => Some(PhysicalVolume {
+            device: device.to_path_buf(),
+            uuid: probe.lookup_value("UUID").ok(),
+        }),
+        _ => None,
+    })
+}
+
+/// Why a device can not be used as is, because of device-mapper.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DmUsage {
+    /// The device is a device-mapper device itself.
+    DeviceMapper(DmDevice),
+    /// The device-mapper device is built on top of the device, or one of
+    /// its partitions.
+    HeldBy(DmDevice),
+    /// The device, or one of its partitions, is an LVM physical volume.
+    PhysicalVolume(PhysicalVolume),
+}
+
+fn kind_name(kind: DeviceKind) -> &'static str {
+    match kind {
+        DeviceKind::Lvm => "LVM logical volume",
+        DeviceKind::Crypt => "dm-crypt volume",
+        DeviceKind::Multipath => "multipath device",
+        _ => "device-mapper device",
+    }
+}
+
+/// The reason, to be prefixed with the device, i.e "/dev/sdb is used by
+/// the dm-crypt volume /dev/mapper/luks-0".
+impl Display for DmUsage {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DeviceMapper(dm) => {
+                write!(
+                    fmt,
+                    "is the {} {}",
+                    kind_name(dm.kind),
+                    dm.mapper_path().display()
+                )
+            }
+            Self::HeldBy(dm) => write!(
+                fmt,
+                "is used by the {} {}",
+                kind_name(dm.kind),
+                dm.mapper_path().display()
+            ),
+            Self::PhysicalVolume(pv) => write!(
+                fmt,
+                "has an LVM physical volume {} on {}",
+                pv.uuid.as_deref().unwrap_or_default(),
+                pv.device.display()
+            ),
+        }
+    }
+}
+
+/// All the ways in which device-mapper uses the device, see `Topology::new`
+/// for the devices which are accepted. Empty when the device is not used.
+pub fn usage<P: AsRef<Path>>(device: P) -> Result<Vec<DmUsage>> {
+    let device = device.as_ref();
+    let topology = Topology::new(device).context(Device { device })?;
+    let mut usage = Vec::new();
+
+    usage.extend(DmDevice::from_node(topology.device().clone()).map(DmUsage::DeviceMapper));
+    let users = topology.users();
+    usage.extend(
+        users
+            .iter()
+            .filter_map(|node| DmDevice::from_node((*node).clone()))
+            .map(DmUsage::HeldBy),
+    );
+
+    let partitions = users
+        .into_iter()
+        .filter(|node| node.kind == DeviceKind::Partition);
+    for node in std::iter::once(topology.device()).chain(partitions) {
+        if let Some(devnode) = node.devnode() {
+            usage.extend(physical_volume(devnode)?.map(DmUsage::PhysicalVolume));
+        }
+    }
+    Ok(usage)
+}
+
+#[test]
+fn device_mapper_inspection() {
+    use crate::loop_device::{attach_for_test, LoopOptions};
+
+    let mut lv = DmDevice {
+        name: "dm-3".to_string(),
+        dm_name: "vg--data-lv--0".to_string(),
+        uuid: None,
+        suspended: false,
+        kind: DeviceKind::Lvm,
+        major: 253,
+        minor: 3,
+    };
+    assert_eq!(
+        lv.lv_name(),
+        Some(("vg-data".to_string(), "lv-0".to_string()))
+    );
+    assert_eq!(lv.mapper_path(), Path::new("/dev/mapper/vg--data-lv--0"));
+    assert_eq!(
+        DmUsage::HeldBy(lv.clone()).to_string(),
+        "is used by the LVM logical volume /dev/mapper/vg--data-lv--0"
+    );
+    lv.kind = DeviceKind::Crypt;
+    assert_eq!(lv.lv_name(), None);
+
+    // two targets as the kernel lays them out, 8 byte aligned
+    let mut data = Vec::new();
+    for (start, length, target_type, status, next) in [
+        (0u64, 2048u64, "linear", "", 48u32),
+        (2048, 4096, "crypt", "", 88),
+    ] {
+        data.extend_from_slice(&start.to_ne_bytes());
+        data.extend_from_slice(&length.to_ne_bytes());
+        data.extend_from_slice(&0i32.to_ne_bytes());
+        data.extend_from_slice(&next.to_ne_bytes());
+        let mut name = [0u8; 16];
+        name[.. target_type.len()].copy_from_slice(target_type.as_bytes());
+        data.extend_from_slice(&name);
+        data.extend_from_slice(status.as_bytes());
+        data.resize(next as usize, 0);
+    }
+    let targets = parse_targets(&data, 2).unwrap();
+    assert_eq!(targets[1].start, 2048);
+    assert_eq!(targets[1].length, 4096);
+    let types: Vec<_> = targets.iter().map(|t| t.target_type.as_str()).collect();
+    assert_eq!(types, ["linear", "crypt"]);
+    assert!(parse_targets(&data[.. 60], 2).is_none());
+
+    assert!(matches!(
+        DmDevice::new("/dev/null"),
+        Err(DmError::Device { .. })
+    ));
+
+    // an LVM label in the second sector, as written by pvcreate, on an
+    // image which is too large to be taken for a floppy by blkid
+    let image = std::env::temp_dir().join(format!("devinfo_lvm_{}.img", std::process::id()));
+    let mut label = vec![0u8; 512];
+    label[.. 8].copy_from_slice(b"LABELONE");
+    label[8 .. 16].copy_from_slice(&1u64.to_le_bytes());
+    label[20 .. 24].copy_from_slice(&32u32.to_le_bytes());
+    label[24 .. 32].copy_from_slice(b"LVM2 001");
+    label[32 .. 64].copy_from_slice(b"Z1DbpouPhEPPxQLHDsqn1tXvq8VMXrLb");
+    let crc = label[20 ..].iter().fold(0xf597a6cfu32, |crc, byte| {
+        const TABLE: [u32; 16] = [
+            0x00000000, 0x1db71064, 0x3b6e20c8, 0x26d930ac, 0x76dc4190, 0x6b6b51f4, 0x4db26158,
+            0x5005713c, 0xedb88320, 0xf00f9344, 0xd6d6a3e8, 0xcb61b38c, 0x9b64c2b0, 0x86d3d2d4,
+            0xa00ae278, 0xbdbdf21c,
+        ];
+        let crc = crc ^ *byte as u32;
+        let crc = (crc >> 4) ^ TABLE[(crc & 0xf) as usize];
+        (crc >> 4) ^ TABLE[(crc & 0xf) as usize]
+    });
+    label[16 .. 20].copy_from_slice(&crc.to_le_bytes());
+    let mut content = vec![0u8; 4 * 1024 * 1024];
+    content[512 .. 1024].copy_from_slice(&label);
+    fs::write(&image, &content).unwrap();
+
+    let pv = physical_volume(&image).unwrap().unwrap();
+    assert_eq!(
+        pv.uuid.as_deref(),
+        Some("Z1Dbpo-uPhE-PPxQ-LHDs-qn1t-Xvq8-VMXrLb")
+    );
+
+    if let Some(loop_device) = attach_for_test(&image, &LoopOptions::default()) {
+        let device = loop_device.path();
+        let usage = usage(device).unwrap();
+        assert!(matches!(&usage[..], [DmUsage::PhysicalVolume(pv)] if pv.device == device));
+        assert!(usage[0]
+            .to_string()
+            .starts_with("has an LVM physical volume Z1Dbpo"));
+    }
+
+    fs::write(&image, vec![0u8; 4 * 1024 * 1024]).unwrap();
+    assert_eq!(physical_volume(&image).unwrap(), None);
+    let _ = fs::remove_file(image);
+}
@@ -0,0 +1,33 @@
+use crate::DevInfoError;
+use snafu::Snafu;
+use std::path::PathBuf;
+
+pub type Result<T, E = FilesystemError> = std::result::Result<T, E>;
+
+/// Errors of the filesystem tools.
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)), context(suffix(false)))]
+pub enum FilesystemError {
+    #[snafu(display("Failed to probe {}: {}", device.display(), source))]
+    Probe {
+        device: PathBuf,
+        source: DevInfoError,
+    },
+    #[snafu(display("Failed to execute {}: {}", command, source))]
+    Spawn {
+        command: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("{} exited with {:?}: {}", command, status, stderr))]
+    CommandFailed {
+        command: String,
+        status: Option<i32>,
+        stderr: String,
+    },
+    #[snafu(display("{} has errors which were not corrected: {}", device.display(), stderr))]
+    Uncorrected { device: PathBuf, stderr: String },
+    #[snafu(display("{} does not support {}", fstype, operation))]
+    Unsupported { fstype: String, operation: String },
+    #[snafu(display("Invalid option for {}: {}", fstype, value))]
+    InvalidOption { fstype: String, value: String },
+}
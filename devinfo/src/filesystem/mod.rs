@@ -0,0 +1,359 @@
+//! Detect, create, check and grow filesystems. Detection is done with
+//! libblkid, everything else with the filesystem specific tools which must
+//! be installed on the host.
+
+use crate::blkid::probe::{Probe, SuperblocksFlags};
+use error::{CommandFailed, InvalidOption, Spawn, Uncorrected, Unsupported};
+use snafu::ResultExt;
+use std::{
+    fmt::{self, Display, Formatter},
+    path::{Path, PathBuf},
+    process::{Command, Output},
+    str::FromStr,
+};
+use uuid::Uuid;
+
+pub use error::{FilesystemError, Result};
+
+/// Errors of the filesystem tools.
+pub mod error;
+
+/// The filesystems which can be created and managed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FsType {
+    Ext4,
+    Xfs,
+    Btrfs,
+}
+
+impl FsType {
+    /// Maximum length of the label in bytes.
+    fn max_label_len(&self) -> usize {
+        match self {
+            Self::Ext4 => 16,
+            Self::Xfs => 12,
+            Self::Btrfs => 255,
+        }
+    }
+}
+
+impl Display for FsType {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Ext4 => write!(f, "ext4"),
+            Self::Xfs => write!(f, "xfs"),
+            Self::Btrfs => write!(f, "btrfs"),
+        }
+    }
+}
+
+impl FromStr for FsType {
+    type Err = FilesystemError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ext4" => Ok(Self::Ext4),
+            "xfs" => Ok(Self::Xfs),
+            "btrfs" => Ok(Self::Btrfs),
+            fstype => Unsupported {
+                fstype,
+                operation: "formatting",
+            }
+            .fail(),
+        }
+    }
+}
+
+/// A signature found on a device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsInfo {
+    /// i.e ext4, xfs or LVM2_member
+    pub fstype: String,
+    /// filesystem, raid, crypto or other
+    pub usage: Option<String>,
+    pub label: Option<String>,
+    pub uuid: Option<String>,
+}
+
+impl FsInfo {
+    /// Whether this is one of the filesystems we can manage.
+    pub fn fs_type(&self) -> Option<FsType> {
+        self.fstype.parse().ok()
+    }
+}
+
+/// Detect the filesystem, or any other signature, on the device. None is
+/// returned when the device is empty.
+pub fn detect<P: AsRef<Path>>(device: P) -> Result<Option<FsInfo>> {
+    let device = device.as_ref();
+    let context = || error::Probe {
+        device: device.to_path_buf(),
+    };
+    let mut probe = Probe::new_from_filename(device).with_context(|_| context())?;
+    probe
+        .set_superblocks_flags(
+            SuperblocksFlags::TYPE
+                | SuperblocksFlags::USAGE
+                | SuperblocksFlags::LABEL
+                | SuperblocksFlags::UUID,
+        )
+        .with_context(|_| context())?;
+
+    if probe.do_safe_probe().with_context(|_| context())? != 0 {
+        return Ok(None);
+    }
+    Ok(probe.lookup_value("TYPE").ok().map(|fstype| FsInfo {
+        fstype,
+        usage: probe.lookup_value("USAGE").ok(),
+        label: probe.lookup_value("LABEL").ok(),
+        uuid: probe.lookup_value("UUID").ok(),
+    }))
+}
+
+/// Options for `format`.
+#[derive(Debug, Clone, Default)]
+pub struct FormatOptions {
+    pub label: Option<String>,
+    pub uuid: Option<Uuid>,
+    /// percentage of blocks reserved for the super user, ext4 only
+    pub reserved_blocks_percent: Option<u8>,
+    /// discard the blocks of the device while formatting, this is off by
+    /// default as it is slow on large thin provisioned volumes
+    pub discard: bool,
+}
+
+/// Run the command and return its output, failing when it exits with a
+/// status which is not accepted.
+fn run(command: &mut Command, accepted: &[i32]) -> Result<Output> {
+    let name = command.get_program().to_string_lossy().to_string();
+    let output = command.output().context(Spawn {
+        command: name.clone(),
+    })?;
+    match output.status.code() {
+        Some(code) if accepted.contains(&code) => Ok(output),
+        status => CommandFailed {
+            command: name,
+            status,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        }
+        .fail(),
+    }
+}
+
+/// Create a new filesystem on the device, any existing filesystem is
+/// overwritten.
+pub fn format<P: AsRef<Path>>(device: P, fstype: FsType, options: &FormatOptions) -> Result<()> {
+    let invalid = |value: String| {
+        InvalidOption {
+            fstype: fstype.to_string(),
+            value,
+        }
+        .fail()
+    };
+    if let Some(label) = &options.label {
+        if label.len() > fstype.max_label_len() {
+            return invalid(format!(
+                "label {} is longer than {} bytes",
+                label,
+                fstype.max_label_len()
+            ));
+        }
+    }
+
+    let mut command = match fstype {
+        FsType::Ext4 => {
+            let mut command = Command::new("mkfs.ext4");
+            command.args(["-q", "-F"]);
+            if let Some(percent) = options.reserved_blocks_percent {
+                if percent > 50 {
+                    return invalid(format!("{}% reserved blocks", percent));
+                }
+                command.arg("-m").arg(percent.to_string());
+            }
+            if let Some(uuid) = options.uuid {
+                command.arg("-U").arg(uuid.to_string());
+            }
+            if !options.discard {
+                command.args(["-E", "nodiscard"]);
+            }
+            command
+        }
+        FsType::Xfs | FsType::Btrfs => {
+            if options.reserved_blocks_percent.is_some() {
+                return invalid("reserved blocks".to_string());
+            }
+            let mut command = Command::new(format!("mkfs.{}", fstype));
+            command.arg("-f");
+            match (fstype, options.uuid) {
+                (FsType::Xfs, Some(uuid)) => {
+                    command.arg("-m").arg(format!("uuid={}", uuid));
+                }
+                (_, Some(uuid)) => {
+                    command.arg("-U").arg(uuid.to_string());
+                }
+                (_, None) => {}
+            }
+            if !options.discard {
+                command.arg("-K");
+            }
+            command
+        }
+    };
+    if let Some(label) = &options.label {
+        command.arg("-L").arg(label);
+    }
+
+    run(command.arg(device.as_ref()), &[0]).map(|_| ())
+}
+
+/// Result of a successful `check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsckOutcome {
+    /// no errors were found
+    Clean,
+    /// errors were found and corrected
+    Repaired,
+}
+
+/// Check the unmounted filesystem for errors and repair them if `repair` is
+/// set. Errors which remain are reported as `FilesystemError::Uncorrected`.
+pub fn check<P: AsRef<Path>>(device: P, fstype: FsType, repair: bool) -> Result<FsckOutcome> {
+    let device = device.as_ref();
+    let (mut command, repaired, errors): (_, &[i32], &[i32]) = match fstype {
+        // 1: errors corrected, 2: errors corrected and the system should be
+        // rebooted, 4: errors left uncorrected
+        FsType::Ext4 => {
+            let mut command = Command::new("e2fsck");
+            command.args(["-f", if repair { "-p" } else { "-n" }]);
+            (command, &[1, 2], &[4])
+        }
+        // 1: corruption found in no-modify mode, 2: the log must be
+        // replayed by mounting the filesystem
+        FsType::Xfs => {
+            let mut command = Command::new("xfs_repair");
+            if !repair {
+                command.arg("-n");
+            }
+            (command, &[], &[1, 2])
+        }
+        FsType::Btrfs => {
+            let mut command = Command::new("btrfs");
+            command.arg("check");
+            if repair {
+                command.arg("--repair");
+            }
+            (command, &[], &[1])
+        }
+    };
+
+    let accepted = [&[0], repaired, errors].concat();
+    let output = run(command.arg(device), &accepted)?;
+    match output.status.code() {
+        Some(0) => Ok(FsckOutcome::Clean),
+        Some(code) if repaired.contains(&code) => Ok(FsckOutcome::Repaired),
+        _ => Uncorrected {
+            device: device.to_path_buf(),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        }
+        .fail(),
+    }
+}
+
+/// Grow the filesystem to the size of the device. Xfs and btrfs can only be
+/// grown while mounted, so the mountpoint is required for those.
+pub fn grow<P: AsRef<Path>>(device: P, fstype: FsType, mountpoint: Option<&Path>) -> Result<()> {
+    let mountpoint = || -> Result<PathBuf> {
+        mountpoint.map(Path::to_path_buf).ok_or_else(|| {
+            Unsupported {
+                fstype: fstype.to_string(),
+                operation: "growing while not mounted",
+            }
+            .build()
+        })
+    };
+
+    let mut command = match fstype {
+        FsType::Ext4 => {
+            let mut command = Command::new("resize2fs");
+            command.arg(device.as_ref());
+            command
+        }
+        FsType::Xfs => {
+            let mut command = Command::new("xfs_growfs");
+            command.arg(mountpoint()?);
+            command
+        }
+        FsType::Btrfs => {
+            let mut command = Command::new("btrfs");
+            command
+                .args(["filesystem", "resize", "max"])
+                .arg(mountpoint()?);
+            command
+        }
+    };
+
+    run(&mut command, &[0]).map(|_| ())
+}
+
+#[test]
+fn filesystem_lifecycle() {
+    let path = std::env::temp_dir().join("devinfo_filesystem.img");
+    std::fs::File::create(&path)
+        .and_then(|f| f.set_len(32 * 1024 * 1024))
+        .unwrap();
+    assert_eq!(detect(&path).unwrap(), None);
+
+    let uuid = Uuid::new_v4();
+    let options = FormatOptions {
+        label: Some("devinfo".to_string()),
+        uuid: Some(uuid),
+        reserved_blocks_percent: Some(0),
+        discard: false,
+    };
+    match format(&path, FsType::Ext4, &options) {
+        Err(FilesystemError::Spawn { .. }) => {
+            println!("mkfs.ext4 not available, skipping");
+            return;
+        }
+        result => result.unwrap(),
+    }
+
+    let info = detect(&path).unwrap().unwrap();
+    assert_eq!(info.fs_type(), Some(FsType::Ext4));
+    assert_eq!(info.usage.as_deref(), Some("filesystem"));
+    assert_eq!(info.label.as_deref(), Some("devinfo"));
+    assert_eq!(info.uuid, Some(uuid.to_string()));
+
+    assert!(matches!(
+        format(
+            &path,
+            FsType::Xfs,
+            &FormatOptions {
+                reserved_blocks_percent: Some(5),
+                ..Default::default()
+            }
+        ),
+        Err(FilesystemError::InvalidOption { .. })
+    ));
+    assert!(matches!(
+        grow(&path, FsType::Xfs, None),
+        Err(FilesystemError::Unsupported { .. })
+    ));
+
+    assert_eq!(
+        check(&path, FsType::Ext4, false).unwrap(),
+        FsckOutcome::Clean
+    );
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .and_then(|f| f.set_len(64 * 1024 * 1024))
+        .unwrap();
+    grow(&path, FsType::Ext4, None).unwrap();
+    assert_eq!(
+        check(&path, FsType::Ext4, true).unwrap(),
+        FsckOutcome::Clean
+    );
+
+    let _ = std::fs::remove_file(path);
+}
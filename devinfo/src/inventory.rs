@@ -0,0 +1,311 @@
+//! Inventory of the block devices of the host. The information which is
+//! gathered mirrors the `BlockDevice` message of the io-engine host API, so
+//! that the io-engine and the node tools report devices in the same way.
+//!
+//! Most of the information is taken from the udev database, when udev has
+//! not (yet) probed a device, e.g. when running in a container without the
+//! udev database mounted, the filesystem type is probed with libblkid.
+
+use crate::{blkid::probe::Probe, mountinfo::SafeMountIter, DevInfoError};
+use std::{collections::HashMap, ffi::OsStr, path::Path};
+use udev::{Device, Enumerator};
+
+/// Major numbers of the device types which may be reported as available.
+const USABLE_MAJORS: [u32; 4] = [
+    7,   // loop devices
+    8,   // SCSI disk devices
+    43,  // network block devices
+    259, // block extended major, i.e NVMe
+];
+
+/// Partition information of a block device which represents a partition.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PartitionInfo {
+    /// devname of the disk to which this partition belongs
+    pub parent: String,
+    /// partition number
+    pub number: u32,
+    /// partition name
+    pub name: String,
+    /// partition scheme: gpt, dos, ...
+    pub scheme: String,
+    /// partition type identifier
+    pub typeid: String,
+    /// UUID identifying the partition
+    pub uuid: String,
+}
+
+/// Filesystem information of a block device which contains a filesystem.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FilesystemInfo {
+    /// filesystem type: ext4, xfs, ...
+    pub fstype: String,
+    /// volume label
+    pub label: String,
+    /// UUID identifying the filesystem
+    pub uuid: String,
+    /// paths where the filesystem is currently mounted
+    pub mountpoints: Vec<String>,
+}
+
+/// A block device as reported by `list_block_devices`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BlockDeviceInfo {
+    /// entry in /dev associated with the device
+    pub devname: String,
+    /// currently "disk" or "partition"
+    pub devtype: String,
+    pub devmajor: u32,
+    pub devminor: u32,
+    /// device model, useful to identify io-engine devices
+    pub model: String,
+    /// official device path
+    pub devpath: String,
+    /// udev generated symlinks by which the device may be identified
+    pub devlinks: Vec<String>,
+    /// size of the device in 512 byte blocks
+    pub size: u64,
+    pub partition: Option<PartitionInfo>,
+    pub filesystem: Option<FilesystemInfo>,
+    /// the device is not in use and may be used by the io-engine
+    pub available: bool,
+    /// the bus through which the device is connected to the system
+    pub connection_type: String,
+    pub is_rotational: Option<bool>,
+}
+
+fn udev_error(source: std::io::Error) -> DevInfoError {
+    DevInfoError::Udev { source }
+}
+
+fn to_string(value: Option<&OsStr>) -> String {
+    value
+        .map(|v| v.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+fn to_u32(value: Option<&OsStr>) -> u32 {
+    to_string(value).trim().parse().unwrap_or_default()
+}
+
+/// Mountpoints of every mounted device, keyed by the mount source.
+fn get_mounts() -> Result<HashMap<String, Vec<String>>, DevInfoError> {
+    let mut table: HashMap<String, Vec<String>> = HashMap::new();
+    let mounts = SafeMountIter::get().map_err(|e| DevInfoError::Io { source: e.into() })?;
+    for mount in mounts.flatten() {
+        table
+            .entry(mount.source.display().to_string())
+            .or_default()
+            .push(mount.dest.display().to_string());
+    }
+    Ok(table)
+}
+
+/// Probe the filesystem type with libblkid, which we only do when udev has
+/// no information about the device.
+fn probe_fstype(devname: &str) -> Option<String> {
+    let mut probe = Probe::new_from_filename(devname).ok()?;
+    if probe.do_safe_probe().ok()? != 0 {
+        return None;
+    }
+    probe.lookup_value("TYPE").ok()
+}
+
+/// A device which is in use by another device, i.e dm, md or bcache, has
+/// holders.
+fn has_holders(device: &Device) -> bool {
+    std::fs::read_dir(device.syspath().join("holders"))
+        .map(|mut d| d.next().is_some())
+        .unwrap_or(false)
+}
+
+fn is_rotational(device: &Device) -> Option<bool> {
+    let rotational = |d: &Device| Some(d.attribute_value("queue/rotational")?.to_str()? == "1");
+    // partitions have no queue of their own
+    rotational(device).or_else(|| rotational(&device.parent()?))
+}
+
+fn connection_type(device: &Device) -> String {
+    if let Some(bus) = device.property_value("ID_BUS") {
+        return to_string(Some(bus));
+    }
+    let syspath = device.syspath().display().to_string();
+    if syspath.contains("/nvme") {
+        "nvme".to_string()
+    } else if syspath.contains("/virtio") {
+        "virtio".to_string()
+    } else {
+        String::new()
+    }
+}
+
+fn new_partition(parent: Option<&str>, device: &Device) -> Option<PartitionInfo> {
+    if device.devtype() != Some(OsStr::new("partition")) {
+        return None;
+    }
+    Some(PartitionInfo {
+        parent: parent.unwrap_or_default().to_string(),
+        number: to_u32(device.property_value("PARTN")),
+        name: to_string(device.property_value("PARTNAME")),
+        scheme: to_string(device.property_value("ID_PART_ENTRY_SCHEME")),
+        typeid: to_string(device.property_value("ID_PART_ENTRY_TYPE")),
+        uuid: to_string(device.property_value("ID_PART_ENTRY_UUID")),
+    })
+}
+
+fn new_filesystem(
+    devname: &str,
+    device: &Device,
+    mountpoints: Vec<String>,
+) -> Option<FilesystemInfo> {
+    let fstype = match device.property_value("ID_FS_TYPE") {
+        Some(fstype) => to_string(Some(fstype)),
+        None => probe_fstype(devname).unwrap_or_default(),
+    };
+    if fstype.is_empty() && mountpoints.is_empty() {
+        return None;
+    }
+    Some(FilesystemInfo {
+        fstype,
+        label: to_string(device.property_value("ID_FS_LABEL")),
+        uuid: to_string(device.property_value("ID_FS_UUID")),
+        mountpoints,
+    })
+}
+
+/// Create a new BlockDeviceInfo from the udev device. A device is only
+/// available when it is a disk without partitions, partition table,
+/// filesystem or holders, which is not mounted and is of a usable type.
+fn new_device(
+    parent: Option<&str>,
+    include: bool,
+    device: &Device,
+    mounts: &HashMap<String, Vec<String>>,
+) -> Option<BlockDeviceInfo> {
+    let devname = device.devnode()?.display().to_string();
+    let devlinks = to_string(device.property_value("DEVLINKS"))
+        .split_whitespace()
+        .map(String::from)
+        .collect::<Vec<_>>();
+
+    // the mount source may be any of the links, i.e /dev/mapper/<name>
+    let mountpoints = std::iter::once(&devname)
+        .chain(devlinks.iter())
+        .filter_map(|name| mounts.get(name))
+        .flatten()
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let partition = new_partition(parent, device);
+    let filesystem = new_filesystem(&devname, device, mountpoints);
+    let devmajor = to_u32(device.property_value("MAJOR"));
+    let size = to_string(device.attribute_value("size"))
+        .trim()
+        .parse()
+        .unwrap_or_default();
+
+    let available = include
+        && size > 0
+        && parent.is_none()
+        && partition.is_none()
+        && filesystem.is_none()
+        && device.property_value("ID_PART_TABLE_TYPE").is_none()
+        && !has_holders(device)
+        && USABLE_MAJORS.contains(&devmajor);
+
+    Some(BlockDeviceInfo {
+        devtype: to_string(device.devtype()),
+        devmajor,
+        devminor: to_u32(device.property_value("MINOR")),
+        model: to_string(device.property_value("ID_MODEL")),
+        devpath: to_string(device.property_value("DEVPATH")),
+        devlinks,
+        size,
+        partition,
+        filesystem,
+        available,
+        connection_type: connection_type(device),
+        is_rotational: is_rotational(device),
+        devname,
+    })
+}
+
+/// Partitions of the given disk.
+fn get_partitions(
+    parent: &str,
+    disk: &Device,
+    mounts: &HashMap<String, Vec<String>>,
+) -> Result<Vec<BlockDeviceInfo>, DevInfoError> {
+    let mut enumerator = Enumerator::new().map_err(udev_error)?;
+    enumerator.match_parent(disk).map_err(udev_error)?;
+    enumerator
+        .match_property("DEVTYPE", "partition")
+        .map_err(udev_error)?;
+
+    Ok(enumerator
+        .scan_devices()
+        .map_err(udev_error)?
+        .filter_map(|entry| new_device(Some(parent), true, &entry, mounts))
+        .collect())
+}
+
+/// List the block devices of the host, each disk is followed by its
+/// partitions. Only the devices which are available for use are returned
+/// unless `all` is set.
+pub fn list_block_devices(all: bool) -> Result<Vec<BlockDeviceInfo>, DevInfoError> {
+    let mounts = get_mounts()?;
+    let mut list = Vec::new();
+
+    let mut enumerator = Enumerator::new().map_err(udev_error)?;
+    enumerator.match_subsystem("block").map_err(udev_error)?;
+    enumerator
+        .match_property("DEVTYPE", "disk")
+        .map_err(udev_error)?;
+
+    for entry in enumerator.scan_devices().map_err(udev_error)? {
+        let devname = match entry.devnode() {
+            Some(devname) => devname.display().to_string(),
+            None => continue,
+        };
+        let partitions = get_partitions(&devname, &entry, &mounts)?;
+        if let Some(device) = new_device(None, partitions.is_empty(), &entry, &mounts) {
+            list.push(device);
+        }
+        list.extend(partitions);
+    }
+
+    list.retain(|d| all || d.available);
+    Ok(list)
+}
+
+/// Lookup a single device by its devname or any of its links.
+pub fn block_device_info<P: AsRef<Path>>(path: P) -> Result<BlockDeviceInfo, DevInfoError> {
+    let path = path.as_ref();
+    let canonical = path.canonicalize().map_err(|_| DevInfoError::NotFound {
+        path: path.display().to_string(),
+    })?;
+    list_block_devices(true)?
+        .into_iter()
+        .find(|d| Path::new(&d.devname) == canonical)
+        .ok_or_else(|| DevInfoError::NotFound {
+            path: path.display().to_string(),
+        })
+}
+
+#[test]
+fn inventory() {
+    let all = list_block_devices(true).unwrap();
+    let available = list_block_devices(false).unwrap();
+    assert!(available.len() <= all.len());
+
+    for dev in &available {
+        assert!(dev.partition.is_none());
+        assert!(dev.filesystem.is_none());
+        assert!(dev.size > 0);
+        assert!(all.contains(dev));
+    }
+    for dev in all.iter().filter(|d| d.partition.is_some()) {
+        assert!(!dev.available);
+        assert_eq!(dev.devtype, "partition");
+    }
+}
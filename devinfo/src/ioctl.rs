@@ -0,0 +1,348 @@
+//! A handle to a block device for the block layer ioctls: its size and block
+//! sizes, the read-only flag, and discarding or zeroing ranges of it. The
+//! queue limits are read from sysfs.
+
+use crate::DevInfoError;
+use nix::sys::stat::{major, minor};
+use std::{
+    fs::{File, OpenOptions},
+    os::{
+        raw::{c_int, c_uint},
+        unix::{
+            fs::{FileTypeExt, MetadataExt},
+            io::AsRawFd,
+        },
+    },
+    path::{Path, PathBuf},
+};
+
+nix::ioctl_write_ptr_bad!(blkroset, nix::request_code_none!(0x12, 93), c_int);
+nix::ioctl_read_bad!(blkroget, nix::request_code_none!(0x12, 94), c_int);
+nix::ioctl_none!(blkrrpart, 0x12, 95);
+nix::ioctl_none!(blkflsbuf, 0x12, 97);
+nix::ioctl_read_bad!(blksszget, nix::request_code_none!(0x12, 104), c_int);
+nix::ioctl_read!(blkgetsize64, 0x12, 114, u64);
+nix::ioctl_write_ptr_bad!(blkdiscard, nix::request_code_none!(0x12, 119), [u64; 2]);
+nix::ioctl_read_bad!(blkiomin, nix::request_code_none!(0x12, 120), c_uint);
+nix::ioctl_read_bad!(blkioopt, nix::request_code_none!(0x12, 121), c_uint);
+nix::ioctl_read_bad!(blkpbszget, nix::request_code_none!(0x12, 123), c_uint);
+nix::ioctl_write_ptr_bad!(blksecdiscard, nix::request_code_none!(0x12, 125), [u64; 2]);
+nix::ioctl_write_ptr_bad!(blkzeroout, nix::request_code_none!(0x12, 127), [u64; 2]);
+
+fn io_error(error: nix::Error) -> DevInfoError {
+    DevInfoError::Io {
+        source: error.into(),
+    }
+}
+
+/// The limits of the request queue of a block device, from
+/// /sys/block/<dev>/queue. Sizes are in bytes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueueLimits {
+    pub logical_block_size: u64,
+    pub physical_block_size: u64,
+    pub minimum_io_size: u64,
+    /// 0 when the device does not report one
+    pub optimal_io_size: u64,
+    pub discard_granularity: u64,
+    /// 0 when the device does not support discard
+    pub discard_max_bytes: u64,
+    /// 0 when the device does not support write zeroes
+    pub write_zeroes_max_bytes: u64,
+    pub max_sectors_kb: u64,
+    pub read_ahead_kb: u64,
+    pub rotational: bool,
+    /// the active I/O scheduler, i.e none or mq-deadline
+    pub scheduler: Option<String>,
+}
+
+impl QueueLimits {
+    fn read(queue: &Path) -> Result<Self, DevInfoError> {
+        let read = |name: &str| {
+            std::fs::read_to_string(queue.join(name))
+                .map(|value| value.trim().to_string())
+                .map_err(|source| DevInfoError::Io { source })
+        };
+        // attributes which are missing on older kernels are left at 0
+        let number = |name: &str| read(name).ok().and_then(|v| v.parse::<u64>().ok());
+        let scheduler = read("scheduler").ok().and_then(|value| {
+            let (_, active) = value.split_once('[')?;
+            Some(active.split_once(']')?.0.to_string())
+        });
+
+        Ok(Self {
+            logical_block_size: read("logical_block_size")?.parse().map_err(|_| {
+                DevInfoError::ParseError {
+                    value: queue.join("logical_block_size").display().to_string(),
+                }
+            })?,
+            physical_block_size: number("physical_block_size").unwrap_or_default(),
+            minimum_io_size: number("minimum_io_size").unwrap_or_default(),
+            optimal_io_size: number("optimal_io_size").unwrap_or_default(),
+            discard_granularity: number("discard_granularity").unwrap_or_default(),
+            discard_max_bytes: number("discard_max_bytes").unwrap_or_default(),
+            write_zeroes_max_bytes: number("write_zeroes_max_bytes").unwrap_or_default(),
+            max_sectors_kb: number("max_sectors_kb").unwrap_or_default(),
+            read_ahead_kb: number("read_ahead_kb").unwrap_or_default(),
+            rotational: number("rotational") == Some(1),
+            scheduler,
+        })
+    }
+}
+
+/// An open block device.
+#[derive(Debug)]
+pub struct BlockDevice {
+    file: File,
+    path: PathBuf,
+}
+
+impl BlockDevice {
+    /// Open the block device read-only, which is enough for all queries.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, DevInfoError> {
+        Self::open_with(path.as_ref(), false)
+    }
+
+    /// Open the block device for writing, as needed to discard or zero it.
+    pub fn open_writable<P: AsRef<Path>>(path: P) -> Result<Self, DevInfoError> {
+        Self::open_with(path.as_ref(), true)
+    }
+
+    fn open_with(path: &Path, write: bool) -> Result<Self, DevInfoError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(write)
+            .open(path)
+            .map_err(|source| match source.kind() {
+                std::io::ErrorKind::NotFound => DevInfoError::NotFound {
+                    path: path.display().to_string(),
+                },
+                _ => DevInfoError::Io { source },
+            })?;
+        Self::from_file(file, path)
+    }
+
+    /// Use an already open file, which must be a block device.
+    pub(crate) fn from_file(file: File, path: &Path) -> Result<Self, DevInfoError> {
+        let metadata = file
+            .metadata()
+            .map_err(|source| DevInfoError::Io { source })?;
+        if !metadata.file_type().is_block_device() {
+            return Err(DevInfoError::NotSupported {
+                value: format!("{} is not a block device", path.display()),
+            });
+        }
+        Ok(Self {
+            file,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Size in bytes (BLKGETSIZE64).
+    pub fn size(&self) -> Result<u64, DevInfoError> {
+        let mut size = 0;
+        unsafe { blkgetsize64(self.file.as_raw_fd(), &mut size) }.map_err(io_error)?;
+        Ok(size)
+    }
+
+    /// The logical block size, the smallest unit the device can address
+    /// (BLKSSZGET).
+    pub fn logical_block_size(&self) -> Result<u32, DevInfoError> {
+        let mut size: c_int = 0;
+        unsafe { blksszget(self.file.as_raw_fd(), &mut size) }.map_err(io_error)?;
+        Ok(size as u32)
+    }
+
+    /// The physical block size, the smallest unit the device can write
+    /// without a read-modify-write (BLKPBSZGET).
+    pub fn physical_block_size(&self) -> Result<u32, DevInfoError> {
+        let mut size: c_uint = 0;
+        unsafe { blkpbszget(self.file.as_raw_fd(), &mut size) }.map_err(io_error)?;
+        Ok(size)
+    }
+
+    /// The minimum I/O size, the preferred granularity (BLKIOMIN).
+    pub fn io_min(&self) -> Result<u32, DevInfoError> {
+        let mut size: c_uint = 0;
+        unsafe { blkiomin(self.file.as_raw_fd(), &mut size) }.map_err(io_error)?;
+        Ok(size)
+    }
+
+    /// The optimal I/O size, i.e the stripe width, 0 if not reported
+    /// (BLKIOOPT).
+    pub fn io_opt(&self) -> Result<u32, DevInfoError> {
+        let mut size: c_uint = 0;
+        unsafe { blkioopt(self.file.as_raw_fd(), &mut size) }.map_err(io_error)?;
+        Ok(size)
+    }
+
+    /// Whether the device is read-only (BLKROGET).
+    pub fn is_read_only(&self) -> Result<bool, DevInfoError> {
+        let mut read_only: c_int = 0;
+        unsafe { blkroget(self.file.as_raw_fd(), &mut read_only) }.map_err(io_error)?;
+        Ok(read_only != 0)
+    }
+
+    /// Set or clear the read-only flag of the device (BLKROSET).
+    pub fn set_read_only(&self, read_only: bool) -> Result<(), DevInfoError> {
+        let read_only = read_only as c_int;
+        unsafe { blkroset(self.file.as_raw_fd(), &read_only) }.map_err(io_error)?;
+        Ok(())
+    }
+
+    /// Flush and invalidate the buffer cache of the device (BLKFLSBUF).
+    pub fn flush_buffers(&self) -> Result<(), DevInfoError> {
+        unsafe { blkflsbuf(self.file.as_raw_fd()) }.map_err(io_error)?;
+        Ok(())
+    }
+
+    /// Make the kernel re-read the partition table (BLKRRPART), this fails
+    /// with EBUSY when any of the partitions is in use.
+    pub fn reread_partitions(&self) -> Result<(), DevInfoError> {
+        unsafe { blkrrpart(self.file.as_raw_fd()) }.map_err(io_error)?;
+        Ok(())
+    }
+
+    /// Check that the range is within the device and aligned to the logical
+    /// block size, as the kernel requires for discard and zero-out.
+    fn range(&self, offset: u64, length: u64) -> Result<[u64; 2], DevInfoError> {
+        let invalid = |reason: String| {
+            Err(DevInfoError::InvalidRange {
+                path: self.path.display().to_string(),
+                offset,
+                length,
+                reason,
+            })
+        };
+        let block_size = self.logical_block_size()? as u64;
+        let size = self.size()?;
+        if length == 0 {
+            return invalid("the length is 0".to_string());
+        }
+        // the logical block size is a power of two
+        if (offset | length) & (block_size - 1) != 0 {
+            return invalid(format!("not aligned to {block_size} bytes"));
+        }
+        match offset.checked_add(length) {
+            Some(end) if end <= size => Ok([offset, length]),
+            _ => invalid(format!("beyond the end of the device at {size}")),
+        }
+    }
+
+    /// Discard the range, after which its contents are undefined
+    /// (BLKDISCARD).
+    pub fn discard(&self, offset: u64, length: u64) -> Result<(), DevInfoError> {
+        let range = self.range(offset, length)?;
+        unsafe { blkdiscard(self.file.as_raw_fd(), &range) }.map_err(io_error)?;
+        Ok(())
+    }
+
+    /// Discard the range and erase any copies of the data the device may
+    /// hold, i.e on flash (BLKSECDISCARD). Few devices support this.
+    pub fn secure_discard(&self, offset: u64, length: u64) -> Result<(), DevInfoError> {
+        let range = self.range(offset, length)?;
+        unsafe { blksecdiscard(self.file.as_raw_fd(), &range) }.map_err(io_error)?;
+        Ok(())
+    }
+
+    /// Zero the range, with write zeroes or unmap where the device supports
+    /// it and by writing zeroes otherwise (BLKZEROOUT).
+    pub fn zero_out(&self, offset: u64, length: u64) -> Result<(), DevInfoError> {
+        let range = self.range(offset, length)?;
+        unsafe { blkzeroout(self.file.as_raw_fd(), &range) }.map_err(io_error)?;
+        Ok(())
+    }
+
+    /// The queue limits of the device, those of the disk for a partition.
+    pub fn queue_limits(&self) -> Result<QueueLimits, DevInfoError> {
+        let rdev = self
+            .file
+            .metadata()
+            .map_err(|source| DevInfoError::Io { source })?
+            .rdev();
+        let sys = PathBuf::from(format!("/sys/dev/block/{}:{}", major(rdev), minor(rdev)));
+        let sys = sys
+            .canonicalize()
+            .map_err(|source| DevInfoError::Io { source })?;
+        let queue = sys.join("queue");
+        if queue.exists() {
+            return QueueLimits::read(&queue);
+        }
+        match sys.parent() {
+            Some(disk) => QueueLimits::read(&disk.join("queue")),
+            None => Err(DevInfoError::NotFound {
+                path: queue.display().to_string(),
+            }),
+        }
+    }
+}
+
+#[test]
+fn block_device_ioctls() {
+    use crate::loop_device::{attach_for_test, LoopOptions};
+    use std::os::unix::fs::FileExt;
+
+    let image = std::env::temp_dir().join("devinfo_block_device.img");
+    File::create(&image)
+        .and_then(|f| f.set_len(8 * 1024 * 1024))
+        .unwrap();
+    assert!(matches!(
+        BlockDevice::open(&image),
+        Err(DevInfoError::NotSupported { .. })
+    ));
+
+    let loop_device = match attach_for_test(&image, &LoopOptions::default()) {
+        Some(device) => device,
+        None => return,
+    };
+    let device = BlockDevice::open_writable(loop_device.path()).unwrap();
+    assert_eq!(device.size().unwrap(), 8 * 1024 * 1024);
+    assert_eq!(device.logical_block_size().unwrap(), 512);
+    assert!(device.physical_block_size().unwrap() >= 512);
+    assert!(device.io_min().unwrap() >= 512);
+    device.io_opt().unwrap();
+
+    let limits = device.queue_limits().unwrap();
+    assert_eq!(limits.logical_block_size, 512);
+    assert!(!limits.rotational || limits.scheduler.is_some());
+
+    let file = File::options().write(true).open(&image).unwrap();
+    file.write_all_at(&[0xaa; 8192], 4096).unwrap();
+    file.sync_all().unwrap();
+    device.flush_buffers().unwrap();
+    device.zero_out(4096, 4096).unwrap();
+    device.flush_buffers().unwrap();
+    let mut data = [0u8; 8192];
+    File::open(loop_device.path())
+        .and_then(|f| f.read_exact_at(&mut data, 4096))
+        .unwrap();
+    assert!(data[.. 4096].iter().all(|b| *b == 0));
+    assert!(data[4096 ..].iter().all(|b| *b == 0xaa));
+    if limits.discard_max_bytes > 0 {
+        device.discard(0, 1024 * 1024).unwrap();
+    }
+
+    for (offset, length) in [(0, 0), (100, 512), (0, 100), (8 * 1024 * 1024, 512)] {
+        assert!(matches!(
+            device.zero_out(offset, length),
+            Err(DevInfoError::InvalidRange { .. })
+        ));
+    }
+    assert!(matches!(
+        device.discard(u64::MAX - 511, 512),
+        Err(DevInfoError::InvalidRange { .. })
+    ));
+
+    assert!(!device.is_read_only().unwrap());
+    device.set_read_only(true).unwrap();
+    assert!(device.is_read_only().unwrap());
+    device.set_read_only(false).unwrap();
+
+    drop(device);
+    drop(loop_device);
+    let _ = std::fs::remove_file(image);
+}
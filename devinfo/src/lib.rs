@@ -1,31 +1,95 @@
 //!
 //! Simple crate for doing device look ups.
-pub use block_device::BlkDev;
+pub use block_device::{BlkDev, BlkDevParams};
 mod block_device;
 use snafu::Snafu;
+#[cfg(target_os = "linux")]
+pub mod device_mapper;
+#[cfg(target_os = "linux")]
+pub mod filesystem;
+#[cfg(target_os = "linux")]
+pub mod inventory;
+#[cfg(target_os = "linux")]
+pub mod ioctl;
+#[cfg(target_os = "linux")]
+pub mod loop_device;
+#[cfg(target_os = "linux")]
+pub mod monitor;
+#[cfg(target_os = "linux")]
+pub mod mount;
 pub mod mountinfo;
+#[cfg(target_os = "linux")]
+pub mod nvme;
 pub mod partition;
+#[cfg(target_os = "linux")]
+pub mod parttable;
+pub mod scsi;
+#[cfg(target_os = "linux")]
+pub mod topology;
 
 #[allow(non_camel_case_types)]
 #[cfg(target_os = "linux")]
 pub mod blkid;
 
 #[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)), context(suffix(false)))]
 pub enum DevInfoError {
+    /// The device is not (yet) present, the lookup may be retried.
     #[snafu(display("Device {} not found", path))]
     NotFound { path: String },
+    #[snafu(display("Invalid URI {}: {}", uri, source))]
+    InvalidUri {
+        uri: String,
+        source: url::ParseError,
+    },
+    #[snafu(display("The URI {} does not contain a valid {}", uri, expected))]
+    InvalidPath { uri: String, expected: String },
+    #[snafu(display("Invalid UUID in {}: {}", uri, source))]
+    InvalidUuid { uri: String, source: uuid::Error },
+    #[snafu(display("Invalid value {} for {} in {}: {}", value, key, uri, source))]
+    InvalidParameter {
+        uri: String,
+        key: String,
+        value: String,
+        source: std::num::ParseIntError,
+    },
+    #[snafu(display("Name qualifier invalid in {}: expected <nqn>:<uuid>", uri))]
+    NqnInvalid { uri: String },
+    #[snafu(display("Unsupported scheme {} in {}", scheme, uri))]
+    UnsupportedScheme { uri: String, scheme: String },
     #[snafu(display("Failed to parse value {}", value))]
     ParseError { value: String },
-    #[snafu(display("Name qualifier invalid {} ", value))]
-    NqnInvalid { value: String },
     #[snafu(display("Device not supported: {} ", value))]
     NotSupported { value: String },
-    #[snafu(display("udev internal error {}", value))]
-    Udev { value: String },
+    #[snafu(display("udev internal error: {}", source))]
+    Udev { source: std::io::Error },
+    #[snafu(display("The udev monitor thread exited unexpectedly"))]
+    MonitorStopped,
+    #[snafu(display("Partition table error: {}", value))]
+    PartitionTable { value: String },
+    #[snafu(display("Invalid range {}+{} of {}: {}", offset, length, path, reason))]
+    InvalidRange {
+        path: String,
+        offset: u64,
+        length: u64,
+        reason: String,
+    },
+    #[snafu(display("{} matches more than one device: {}", value, devices.join(", ")))]
+    Ambiguous { value: String, devices: Vec<String> },
+    #[snafu(display("Timed out waiting for {}", value))]
+    Timeout { value: String },
     #[snafu(display("I/O error: {}", source))]
     Io { source: std::io::Error },
-    #[snafu(display("non-UTF8 string"))]
-    InvalidStr,
+    #[snafu(display("non-UTF8 string {}", value))]
+    InvalidStr { value: String },
+}
+
+impl DevInfoError {
+    /// Whether the device may still show up, i.e it is being connected, as
+    /// opposed to errors which persist on retrying such as an invalid URI.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::NotFound { .. } | Self::Timeout { .. })
+    }
 }
 
 #[test]
@@ -0,0 +1,34 @@
+use snafu::Snafu;
+use std::path::PathBuf;
+
+pub type Result<T, E = LoopError> = std::result::Result<T, E>;
+
+/// Errors of the loop device management.
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)), context(suffix(false)))]
+pub enum LoopError {
+    #[snafu(display("Failed to open {}: {}", path.display(), source))]
+    Open {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("loop-control request failed: {}", source))]
+    Control { source: nix::Error },
+    #[snafu(display("Failed to attach {} to {}: {}", file.display(), device.display(), source))]
+    Attach {
+        file: PathBuf,
+        device: PathBuf,
+        source: nix::Error,
+    },
+    #[snafu(display("Failed to detach {}: {}", device.display(), source))]
+    Detach { device: PathBuf, source: nix::Error },
+    #[snafu(display("{} is no longer attached", device.display()))]
+    NotAttached { device: PathBuf },
+    #[snafu(display("Failed to read {}: {}", path.display(), source))]
+    Sysfs {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("Invalid loop device option: {}", value))]
+    InvalidOption { value: String },
+}
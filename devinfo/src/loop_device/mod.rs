@@ -0,0 +1,433 @@
+//! Attach files to loop devices, and find the loop devices of a file. A
+//! device is allocated through `/dev/loop-control` and configured in one go
+//! with LOOP_CONFIGURE, falling back to the older ioctls on kernels before
+//! 5.8.
+
+use error::{Attach, Control, Detach, InvalidOption, NotAttached, Open, Sysfs};
+use nix::errno::Errno;
+use snafu::ResultExt;
+use std::{
+    fs::{File, OpenOptions},
+    os::unix::{ffi::OsStrExt, io::AsRawFd},
+    path::{Path, PathBuf},
+};
+
+pub use error::{LoopError, Result};
+
+/// Errors of the loop device management.
+pub mod error;
+
+const LO_FLAGS_READ_ONLY: u32 = 1;
+const LO_FLAGS_AUTOCLEAR: u32 = 4;
+const LO_FLAGS_PARTSCAN: u32 = 8;
+const LO_FLAGS_DIRECT_IO: u32 = 16;
+const LO_NAME_SIZE: usize = 64;
+/// Attempts to allocate a free device, another process may grab the device
+/// we were handed in between allocating and configuring it.
+const ATTACH_RETRIES: usize = 10;
+
+/// struct loop_info64 from linux/loop.h
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct LoopInfo64 {
+    lo_device: u64,
+    lo_inode: u64,
+    lo_rdevice: u64,
+    lo_offset: u64,
+    lo_sizelimit: u64,
+    lo_number: u32,
+    lo_encrypt_type: u32,
+    lo_encrypt_key_size: u32,
+    lo_flags: u32,
+    lo_file_name: [u8; LO_NAME_SIZE],
+    lo_crypt_name: [u8; LO_NAME_SIZE],
+    lo_encrypt_key: [u8; 32],
+    lo_init: [u64; 2],
+}
+
+/// struct loop_config from linux/loop.h
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct LoopConfig {
+    fd: u32,
+    block_size: u32,
+    info: LoopInfo64,
+    reserved: [u64; 8],
+}
+
+nix::ioctl_write_int_bad!(loop_set_fd, nix::request_code_none!(0x4c, 0x00));
+nix::ioctl_none_bad!(loop_clr_fd, nix::request_code_none!(0x4c, 0x01));
+nix::ioctl_write_ptr_bad!(
+    loop_set_status64,
+    nix::request_code_none!(0x4c, 0x04),
+    LoopInfo64
+);
+nix::ioctl_write_int_bad!(loop_set_direct_io, nix::request_code_none!(0x4c, 0x08));
+nix::ioctl_write_int_bad!(loop_set_block_size, nix::request_code_none!(0x4c, 0x09));
+nix::ioctl_write_ptr_bad!(
+    loop_configure,
+    nix::request_code_none!(0x4c, 0x0a),
+    LoopConfig
+);
+nix::ioctl_none_bad!(loop_ctl_get_free, nix::request_code_none!(0x4c, 0x82));
+
+/// Options for `LoopDevice::attach`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoopOptions {
+    /// offset into the file in bytes, a multiple of 512
+    pub offset: u64,
+    /// maximum size of the device in bytes, a multiple of 512, by default the
+    /// device extends to the end of the file
+    pub size_limit: Option<u64>,
+    pub read_only: bool,
+    /// bypass the page cache of the file
+    pub direct_io: bool,
+    /// let the kernel detach the device when it is last closed
+    pub autoclear: bool,
+    /// scan the device for partitions
+    pub partscan: bool,
+    /// logical block size, by default 512
+    pub block_size: Option<u32>,
+}
+
+impl LoopOptions {
+    fn validate(&self) -> Result<()> {
+        let invalid = |value: String| InvalidOption { value }.fail();
+        if self.offset & 511 != 0 {
+            return invalid(format!("offset {} is not a multiple of 512", self.offset));
+        }
+        if let Some(size_limit) = self.size_limit {
+            if size_limit == 0 || size_limit & 511 != 0 {
+                return invalid(format!("size limit {size_limit} is not a multiple of 512"));
+            }
+        }
+        if let Some(block_size) = self.block_size {
+            if !(512 ..= 4096).contains(&block_size) || !block_size.is_power_of_two() {
+                return invalid(format!("block size {block_size}"));
+            }
+        }
+        Ok(())
+    }
+
+    fn flags(&self) -> u32 {
+        [
+            (self.read_only, LO_FLAGS_READ_ONLY),
+            (self.autoclear, LO_FLAGS_AUTOCLEAR),
+            (self.partscan, LO_FLAGS_PARTSCAN),
+            (self.direct_io, LO_FLAGS_DIRECT_IO),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |flags, (_, flag)| flags | flag)
+    }
+
+    fn info(&self, file: &Path) -> LoopInfo64 {
+        let mut info = LoopInfo64 {
+            lo_device: 0,
+            lo_inode: 0,
+            lo_rdevice: 0,
+            lo_offset: self.offset,
+            lo_sizelimit: self.size_limit.unwrap_or_default(),
+            lo_number: 0,
+            lo_encrypt_type: 0,
+            lo_encrypt_key_size: 0,
+            lo_flags: self.flags(),
+            lo_file_name: [0; LO_NAME_SIZE],
+            lo_crypt_name: [0; LO_NAME_SIZE],
+            lo_encrypt_key: [0; 32],
+            lo_init: [0; 2],
+        };
+        // informational only and truncated, as with losetup
+        let name = file.as_os_str().as_bytes();
+        let len = name.len().min(LO_NAME_SIZE - 1);
+        info.lo_file_name[.. len].copy_from_slice(&name[.. len]);
+        info
+    }
+}
+
+/// The state of an attached loop device, as shown in sysfs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoopInfo {
+    pub number: u32,
+    pub device: PathBuf,
+    pub backing_file: PathBuf,
+    pub offset: u64,
+    /// 0 when the device extends to the end of the file
+    pub size_limit: u64,
+    pub read_only: bool,
+    pub direct_io: bool,
+    pub autoclear: bool,
+    pub partscan: bool,
+}
+
+impl LoopInfo {
+    /// The state of the loop device, None if it is not attached.
+    fn read(number: u32) -> Result<Option<Self>> {
+        let sys = PathBuf::from(format!("/sys/block/loop{number}"));
+        let read = |name: &str| {
+            let path = sys.join(name);
+            std::fs::read_to_string(&path)
+                .map(|value| value.trim().to_string())
+                .context(Sysfs { path })
+        };
+        // the loop directory only exists while a file is attached
+        if !sys.join("loop").exists() {
+            return Ok(None);
+        }
+        let number_of = |name: &str| -> Result<u64> { Ok(read(name)?.parse().unwrap_or_default()) };
+        Ok(Some(Self {
+            number,
+            device: PathBuf::from(format!("/dev/loop{number}")),
+            backing_file: PathBuf::from(read("loop/backing_file")?),
+            offset: number_of("loop/offset")?,
+            size_limit: number_of("loop/sizelimit")?,
+            read_only: read("ro")? == "1",
+            direct_io: read("loop/dio")? == "1",
+            autoclear: read("loop/autoclear")? == "1",
+            partscan: read("loop/partscan")? == "1",
+        }))
+    }
+}
+
+/// All attached loop devices.
+pub fn list() -> Result<Vec<LoopInfo>> {
+    let path = Path::new("/sys/block");
+    let mut numbers = std::fs::read_dir(path)
+        .context(Sysfs { path })?
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name();
+            name.to_str()?.strip_prefix("loop")?.parse::<u32>().ok()
+        })
+        .collect::<Vec<_>>();
+    numbers.sort_unstable();
+
+    let mut devices = Vec::new();
+    for number in numbers {
+        devices.extend(LoopInfo::read(number)?);
+    }
+    Ok(devices)
+}
+
+/// The loop devices the file is attached to, a file may be attached more
+/// than once.
+pub fn find_by_backing_file<P: AsRef<Path>>(file: P) -> Result<Vec<LoopInfo>> {
+    let file = file.as_ref();
+    let file = file.canonicalize().unwrap_or_else(|_| file.to_path_buf());
+    Ok(list()?
+        .into_iter()
+        .filter(|info| info.backing_file == file)
+        .collect())
+}
+
+/// An attached loop device, which is detached when dropped unless `persist`
+/// is called.
+#[derive(Debug)]
+pub struct LoopDevice {
+    number: u32,
+    device: PathBuf,
+    file: File,
+    detach_on_drop: bool,
+}
+
+impl LoopDevice {
+    /// Attach the file to a free loop device.
+    pub fn attach<P: AsRef<Path>>(file: P, options: &LoopOptions) -> Result<Self> {
+        options.validate()?;
+        let path = file.as_ref();
+        let path = path.canonicalize().context(Open { path })?;
+        let backing = OpenOptions::new()
+            .read(true)
+            .write(!options.read_only)
+            .open(&path)
+            .context(Open { path: &path })?;
+
+        let control = Path::new("/dev/loop-control");
+        let control = File::open(control).context(Open { path: control })?;
+        let mut retries = ATTACH_RETRIES;
+        loop {
+            let number = unsafe { loop_ctl_get_free(control.as_raw_fd()) }.context(Control)?;
+            let device = PathBuf::from(format!("/dev/loop{number}"));
+            let file = OpenOptions::new()
+                .read(true)
+                .write(!options.read_only)
+                .open(&device)
+                .context(Open { path: &device })?;
+
+            match Self::configure(&file, &backing, &path, options) {
+                Ok(()) => {
+                    return Ok(Self {
+                        number: number as u32,
+                        device,
+                        file,
+                        detach_on_drop: true,
+                    })
+                }
+                Err(Errno::EBUSY) if retries > 0 => retries -= 1,
+                Err(source) => return Err(source).context(Attach { file: path, device }),
+            }
+        }
+    }
+
+    fn configure(
+        device: &File,
+        backing: &File,
+        path: &Path,
+        options: &LoopOptions,
+    ) -> nix::Result<()> {
+        let config = LoopConfig {
+            fd: backing.as_raw_fd() as u32,
+            block_size: options.block_size.unwrap_or_default(),
+            info: options.info(path),
+            reserved: [0; 8],
+        };
+        let fd = device.as_raw_fd();
+        match unsafe { loop_configure(fd, &config) } {
+            Err(Errno::EINVAL | Errno::ENOTTY) => {}
+            result => return result.map(|_| ()),
+        }
+
+        // the kernel predates LOOP_CONFIGURE
+        unsafe { loop_set_fd(fd, backing.as_raw_fd()) }?;
+        let mut info = config.info;
+        info.lo_flags &= !LO_FLAGS_DIRECT_IO;
+        let result = unsafe { loop_set_status64(fd, &info) }
+            .and_then(|_| match options.block_size {
+                Some(size) => unsafe { loop_set_block_size(fd, size as _) },
+                None => Ok(0),
+            })
+            .and_then(|_| match options.direct_io {
+                true => unsafe { loop_set_direct_io(fd, 1) },
+                false => Ok(0),
+            });
+        if result.is_err() {
+            let _ = unsafe { loop_clr_fd(fd) };
+        }
+        result.map(|_| ())
+    }
+
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    /// The device node, i.e /dev/loop0.
+    pub fn path(&self) -> &Path {
+        &self.device
+    }
+
+    pub fn info(&self) -> Result<LoopInfo> {
+        LoopInfo::read(self.number)?.ok_or_else(|| {
+            NotAttached {
+                device: self.device.clone(),
+            }
+            .build()
+        })
+    }
+
+    /// Keep the device attached when this is dropped, and return its path.
+    pub fn persist(mut self) -> PathBuf {
+        self.detach_on_drop = false;
+        self.device.clone()
+    }
+
+    /// Detach the file from the device. The device is released once it is
+    /// no longer open, i.e when it is unmounted.
+    pub fn detach(mut self) -> Result<()> {
+        self.detach_on_drop = false;
+        Self::clear(&self.file, &self.device)
+    }
+
+    /// Detach the file from the loop device at the path.
+    pub fn detach_path<P: AsRef<Path>>(device: P) -> Result<()> {
+        let device = device.as_ref();
+        let file = File::open(device).context(Open { path: device })?;
+        Self::clear(&file, device)
+    }
+
+    fn clear(file: &File, device: &Path) -> Result<()> {
+        unsafe { loop_clr_fd(file.as_raw_fd()) }
+            .map(|_| ())
+            .context(Detach { device })
+    }
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        if self.detach_on_drop {
+            let _ = Self::clear(&self.file, &self.device);
+        }
+    }
+}
+
+/// Attach the image for a test, None when loop devices are not available,
+/// i.e in a container without /dev/loop-control.
+#[cfg(test)]
+pub(crate) fn attach_for_test(image: &Path, options: &LoopOptions) -> Option<LoopDevice> {
+    match LoopDevice::attach(image, options) {
+        Err(LoopError::Open { path, .. }) if path == Path::new("/dev/loop-control") => {
+            println!("no loop devices available, skipping");
+            None
+        }
+        result => Some(result.unwrap()),
+    }
+}
+
+#[test]
+fn loop_device_attach() {
+    let path = std::env::temp_dir().join("devinfo_loop_device.img");
+    File::create(&path)
+        .and_then(|f| f.set_len(16 * 1024 * 1024))
+        .unwrap();
+
+    assert!(matches!(
+        LoopDevice::attach(
+            &path,
+            &LoopOptions {
+                offset: 100,
+                ..Default::default()
+            }
+        ),
+        Err(LoopError::InvalidOption { .. })
+    ));
+
+    let options = LoopOptions {
+        offset: 1024 * 1024,
+        size_limit: Some(4 * 1024 * 1024),
+        read_only: true,
+        block_size: Some(4096),
+        ..Default::default()
+    };
+    let device = match attach_for_test(&path, &options) {
+        Some(device) => device,
+        None => return,
+    };
+
+    let info = device.info().unwrap();
+    assert_eq!(info.backing_file, path.canonicalize().unwrap());
+    assert_eq!(
+        (info.offset, info.size_limit),
+        (1024 * 1024, 4 * 1024 * 1024)
+    );
+    assert!(info.read_only && !info.autoclear);
+    let sys = format!(
+        "/sys/block/loop{}/queue/logical_block_size",
+        device.number()
+    );
+    assert_eq!(std::fs::read_to_string(sys).unwrap().trim(), "4096");
+    assert!(std::fs::OpenOptions::new()
+        .write(true)
+        .open(device.path())
+        .and_then(|mut f| std::io::Write::write_all(&mut f, &[0; 4096]))
+        .is_err());
+
+    let second = LoopDevice::attach(&path, &LoopOptions::default()).unwrap();
+    let numbers = |infos: Vec<LoopInfo>| infos.iter().map(|i| i.number).collect::<Vec<_>>();
+    let mut attached = vec![device.number(), second.number()];
+    attached.sort_unstable();
+    assert_eq!(numbers(find_by_backing_file(&path).unwrap()), attached);
+
+    let second = second.persist();
+    drop(device);
+    LoopDevice::detach_path(&second).unwrap();
+    assert!(find_by_backing_file(&path).unwrap().is_empty());
+    let _ = std::fs::remove_file(path);
+}
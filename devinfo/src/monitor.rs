@@ -0,0 +1,276 @@
+//! Asynchronous udev monitor for block device events.
+//!
+//! The udev monitor socket can not be moved between threads, so it is owned
+//! by a dedicated thread which forwards the events which match the filter to
+//! the (Send) `UdevMonitor` stream. The thread exits once the stream is
+//! dropped.
+
+use crate::{BlkDev, DevInfoError};
+use futures::Stream;
+use nix::poll::{poll, PollFd, PollFlags};
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::sync::mpsc;
+use udev::{EventType, MonitorBuilder};
+
+/// How long the monitor thread waits for events before checking whether the
+/// stream has been dropped, in milliseconds.
+const POLL_TIMEOUT_MS: i32 = 100;
+/// Number of events which are buffered before the monitor thread blocks.
+const EVENT_QUEUE_DEPTH: usize = 64;
+
+/// The action which caused the event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdevAction {
+    Add,
+    Remove,
+    Change,
+    /// bind, unbind, move, etc.
+    Other,
+}
+
+impl From<EventType> for UdevAction {
+    fn from(event: EventType) -> Self {
+        match event {
+            EventType::Add => Self::Add,
+            EventType::Remove => Self::Remove,
+            EventType::Change => Self::Change,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// A device event as received from udev.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdevEvent {
+    pub action: UdevAction,
+    pub syspath: String,
+    pub subsystem: Option<String>,
+    /// entry in /dev associated with the device, if any
+    pub devname: Option<String>,
+    /// "disk" or "partition" for block devices
+    pub devtype: Option<String>,
+    pub wwn: Option<String>,
+    pub serial: Option<String>,
+    /// udev generated symlinks by which the device may be identified
+    pub devlinks: Vec<String>,
+    /// all properties of the event
+    pub properties: HashMap<String, String>,
+}
+
+impl UdevEvent {
+    fn new(event: &udev::Event) -> Self {
+        let properties = event
+            .properties()
+            .map(|p| {
+                (
+                    p.name().to_string_lossy().to_string(),
+                    p.value().to_string_lossy().to_string(),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        let property = |name: &str| properties.get(name).cloned();
+
+        Self {
+            action: event.event_type().into(),
+            syspath: event.syspath().display().to_string(),
+            subsystem: event.subsystem().map(|s| s.to_string_lossy().to_string()),
+            devname: property("DEVNAME"),
+            devtype: property("DEVTYPE"),
+            wwn: property("ID_WWN"),
+            serial: property("ID_SERIAL"),
+            devlinks: property("DEVLINKS")
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(String::from)
+                .collect(),
+            properties,
+        }
+    }
+}
+
+/// Filter which selects the events a monitor yields. Subsystem and devtype
+/// matching is done by udev, property matching by the monitor itself.
+#[derive(Debug, Clone, Default)]
+pub struct UdevFilter {
+    subsystem: Option<String>,
+    devtype: Option<String>,
+    properties: Vec<(String, String)>,
+}
+
+impl UdevFilter {
+    /// Filter which matches the events of all block devices.
+    pub fn block() -> Self {
+        Self::default().subsystem("block")
+    }
+
+    /// Only match devices of the given subsystem.
+    pub fn subsystem(mut self, subsystem: &str) -> Self {
+        self.subsystem = Some(subsystem.to_string());
+        self
+    }
+
+    /// Only match devices of the given devtype, requires a subsystem.
+    pub fn devtype(mut self, devtype: &str) -> Self {
+        self.devtype = Some(devtype.to_string());
+        self
+    }
+
+    /// Only match devices which have the given property value, may be given
+    /// multiple times in which case all properties must match.
+    pub fn property(mut self, name: &str, value: &str) -> Self {
+        self.properties.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    fn matches(&self, event: &UdevEvent) -> bool {
+        self.properties
+            .iter()
+            .all(|(name, value)| event.properties.get(name) == Some(value))
+    }
+
+    fn listen(&self) -> Result<udev::MonitorSocket, DevInfoError> {
+        let udev_error = |source| DevInfoError::Udev { source };
+        let mut builder = MonitorBuilder::new().map_err(udev_error)?;
+        builder = match (&self.subsystem, &self.devtype) {
+            (Some(subsystem), Some(devtype)) => builder
+                .match_subsystem_devtype(subsystem, devtype)
+                .map_err(udev_error)?,
+            (Some(subsystem), None) => builder.match_subsystem(subsystem).map_err(udev_error)?,
+            (None, _) => builder,
+        };
+        builder.listen().map_err(udev_error)
+    }
+}
+
+/// Stream of udev events which match the filter the monitor was created
+/// with.
+#[derive(Debug)]
+pub struct UdevMonitor {
+    events: mpsc::Receiver<Result<UdevEvent, DevInfoError>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl UdevMonitor {
+    /// Start monitoring, only the events which occur after this returns are
+    /// yielded.
+    pub fn new(filter: UdevFilter) -> Result<Self, DevInfoError> {
+        let (tx, events) = mpsc::channel(EVENT_QUEUE_DEPTH);
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+
+        std::thread::Builder::new()
+            .name("udev-monitor".to_string())
+            .spawn(move || {
+                let socket = match filter.listen() {
+                    Ok(socket) => {
+                        let _ = ready_tx.send(Ok(()));
+                        socket
+                    }
+                    Err(error) => {
+                        let _ = ready_tx.send(Err(error));
+                        return;
+                    }
+                };
+
+                while !stopped.load(Ordering::Relaxed) {
+                    let mut fds = [PollFd::new(&socket, PollFlags::POLLIN)];
+                    match poll(&mut fds, POLL_TIMEOUT_MS) {
+                        Ok(0) | Err(nix::errno::Errno::EINTR) => continue,
+                        Ok(_) => {}
+                        Err(errno) => {
+                            let _ = tx.blocking_send(Err(DevInfoError::Io {
+                                source: errno.into(),
+                            }));
+                            return;
+                        }
+                    }
+
+                    for event in socket.iter() {
+                        let event = UdevEvent::new(&event);
+                        if filter.matches(&event) && tx.blocking_send(Ok(event)).is_err() {
+                            return;
+                        }
+                    }
+                }
+            })
+            .map_err(|source| DevInfoError::Io { source })?;
+
+        ready_rx
+            .recv()
+            .map_err(|_| DevInfoError::MonitorStopped)??;
+
+        Ok(Self { events, stop })
+    }
+
+    /// Wait for the next event, None is returned when the monitor failed.
+    pub async fn next_event(&mut self) -> Option<Result<UdevEvent, DevInfoError>> {
+        self.events.recv().await
+    }
+}
+
+impl Stream for UdevMonitor {
+    type Item = Result<UdevEvent, DevInfoError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+impl Drop for UdevMonitor {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Wait for the device to show up and return its device path. The lookup is
+/// retried whenever a block device is added or changed, rather than polling.
+pub async fn wait_for(dev: &BlkDev, timeout: Duration) -> Result<String, DevInfoError> {
+    // start monitoring before the lookup, so that we can not miss the event
+    let mut monitor = UdevMonitor::new(UdevFilter::block())?;
+    if let Ok(path) = dev.lookup() {
+        return Ok(path);
+    }
+
+    let wait = async {
+        while let Some(event) = monitor.next_event().await {
+            if event?.action == UdevAction::Remove {
+                continue;
+            }
+            if let Ok(path) = dev.lookup() {
+                return Ok(path);
+            }
+        }
+        Err(DevInfoError::MonitorStopped)
+    };
+
+    tokio::time::timeout(timeout, wait)
+        .await
+        .map_err(|_| DevInfoError::Timeout {
+            value: format!("{:?}", dev),
+        })?
+}
+
+#[tokio::test]
+async fn monitor_wait_for() {
+    use std::convert::TryFrom;
+
+    let dev =
+        BlkDev::try_from("nvmf://host/nqn.2019-05.io.openebs:00000000-0000-0000-0000-000000000000")
+            .unwrap();
+    let result = wait_for(&dev, Duration::from_millis(200)).await;
+    assert!(matches!(result, Err(DevInfoError::Timeout { .. })));
+
+    // the monitor and its thread must be usable from spawned tasks
+    let monitor = UdevMonitor::new(UdevFilter::block().property("DEVTYPE", "disk")).unwrap();
+    tokio::spawn(async move { drop(monitor) }).await.unwrap();
+}
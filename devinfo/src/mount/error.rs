@@ -0,0 +1,35 @@
+use snafu::Snafu;
+use std::path::PathBuf;
+
+pub type Result<T, E = MountError> = std::result::Result<T, E>;
+
+/// Errors of the mount helpers.
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)), context(suffix(false)))]
+pub enum MountError {
+    #[snafu(display("Failed to mount {} on {}: {}", device.display(), dest.display(), source))]
+    Mount {
+        device: PathBuf,
+        dest: PathBuf,
+        source: nix::Error,
+    },
+    #[snafu(display("Failed to remount {}: {}", dest.display(), source))]
+    Remount { dest: PathBuf, source: nix::Error },
+    #[snafu(display("Failed to unmount {}: {}", dest.display(), source))]
+    Unmount { dest: PathBuf, source: nix::Error },
+    #[snafu(display("Failed to read the mount table: {}", source))]
+    MountTable { source: std::io::Error },
+    #[snafu(display("Failed to stat {}: {}", path.display(), source))]
+    Stat {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("{} is already mounted from {}", dest.display(), device.display()))]
+    AlreadyMounted { dest: PathBuf, device: PathBuf },
+    #[snafu(display("{} is mounted with {}, expected {}", dest.display(), found, expected))]
+    OptionsMismatch {
+        dest: PathBuf,
+        expected: String,
+        found: String,
+    },
+}
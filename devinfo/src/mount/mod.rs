@@ -0,0 +1,471 @@
+//! Mount and unmount filesystems, with idempotent helpers which consult the
+//! mount table before doing anything.
+
+use crate::mountinfo::{MountInfo, SafeMountIter};
+use error::{AlreadyMounted, Mount, MountTable, OptionsMismatch, Remount, Stat, Unmount};
+use nix::mount::{MntFlags, MsFlags};
+use snafu::ResultExt;
+use std::{
+    convert::Infallible,
+    fmt::{self, Display, Formatter},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+pub use error::{MountError, Result};
+
+/// Errors of the mount helpers.
+pub mod error;
+
+/// Options which map onto mount flags, the flag is set when the value is
+/// true and cleared otherwise.
+const FLAG_OPTIONS: [(&str, MsFlags, bool); 20] = [
+    ("nosuid", MsFlags::MS_NOSUID, true),
+    ("suid", MsFlags::MS_NOSUID, false),
+    ("nodev", MsFlags::MS_NODEV, true),
+    ("dev", MsFlags::MS_NODEV, false),
+    ("noexec", MsFlags::MS_NOEXEC, true),
+    ("exec", MsFlags::MS_NOEXEC, false),
+    ("sync", MsFlags::MS_SYNCHRONOUS, true),
+    ("async", MsFlags::MS_SYNCHRONOUS, false),
+    ("dirsync", MsFlags::MS_DIRSYNC, true),
+    ("mand", MsFlags::MS_MANDLOCK, true),
+    ("nomand", MsFlags::MS_MANDLOCK, false),
+    ("noatime", MsFlags::MS_NOATIME, true),
+    ("atime", MsFlags::MS_NOATIME, false),
+    ("nodiratime", MsFlags::MS_NODIRATIME, true),
+    ("diratime", MsFlags::MS_NODIRATIME, false),
+    ("relatime", MsFlags::MS_RELATIME, true),
+    ("norelatime", MsFlags::MS_RELATIME, false),
+    ("strictatime", MsFlags::MS_STRICTATIME, true),
+    ("lazytime", MsFlags::MS_LAZYTIME, true),
+    ("silent", MsFlags::MS_SILENT, true),
+];
+
+/// Mount propagation type, the r prefixed variants apply recursively.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Propagation {
+    Shared,
+    RShared,
+    Slave,
+    RSlave,
+    Private,
+    RPrivate,
+    Unbindable,
+    RUnbindable,
+}
+
+impl Propagation {
+    const ALL: [Self; 8] = [
+        Self::Shared,
+        Self::RShared,
+        Self::Slave,
+        Self::RSlave,
+        Self::Private,
+        Self::RPrivate,
+        Self::Unbindable,
+        Self::RUnbindable,
+    ];
+
+    fn flags(&self) -> MsFlags {
+        match self {
+            Self::Shared => MsFlags::MS_SHARED,
+            Self::RShared => MsFlags::MS_SHARED | MsFlags::MS_REC,
+            Self::Slave => MsFlags::MS_SLAVE,
+            Self::RSlave => MsFlags::MS_SLAVE | MsFlags::MS_REC,
+            Self::Private => MsFlags::MS_PRIVATE,
+            Self::RPrivate => MsFlags::MS_PRIVATE | MsFlags::MS_REC,
+            Self::Unbindable => MsFlags::MS_UNBINDABLE,
+            Self::RUnbindable => MsFlags::MS_UNBINDABLE | MsFlags::MS_REC,
+        }
+    }
+}
+
+impl Display for Propagation {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Shared => "shared",
+            Self::RShared => "rshared",
+            Self::Slave => "slave",
+            Self::RSlave => "rslave",
+            Self::Private => "private",
+            Self::RPrivate => "rprivate",
+            Self::Unbindable => "unbindable",
+            Self::RUnbindable => "runbindable",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Mount options as given to mount(8), i.e "ro,nosuid,bind,discard".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountOptions {
+    pub read_only: bool,
+    pub bind: bool,
+    /// bind mount the whole subtree (rbind)
+    pub recursive: bool,
+    pub propagation: Option<Propagation>,
+    /// flags other than the above, i.e MS_NOSUID
+    pub flags: MsFlags,
+    /// filesystem specific options, i.e discard
+    pub data: Vec<String>,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        Self {
+            read_only: false,
+            bind: false,
+            recursive: false,
+            propagation: None,
+            flags: MsFlags::empty(),
+            data: Vec::new(),
+        }
+    }
+}
+
+impl MountOptions {
+    /// Read-only options, otherwise the defaults.
+    pub fn read_only() -> Self {
+        Self {
+            read_only: true,
+            ..Default::default()
+        }
+    }
+
+    /// Options for a (recursive) bind mount.
+    pub fn bind(recursive: bool) -> Self {
+        Self {
+            bind: true,
+            recursive,
+            ..Default::default()
+        }
+    }
+
+    /// All flags, excluding the propagation type which can not be combined
+    /// with the other flags.
+    pub fn ms_flags(&self) -> MsFlags {
+        let mut flags = self.flags;
+        flags.set(MsFlags::MS_RDONLY, self.read_only);
+        flags.set(MsFlags::MS_BIND, self.bind);
+        flags.set(MsFlags::MS_REC, self.bind && self.recursive);
+        flags
+    }
+
+    fn data(&self) -> Option<String> {
+        Some(self.data.join(",")).filter(|d| !d.is_empty())
+    }
+}
+
+impl FromStr for MountOptions {
+    type Err = Infallible;
+
+    /// Unknown options are filesystem specific and kept as data.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut options = Self::default();
+        for option in s.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+            if let Some((_, flag, set)) = FLAG_OPTIONS.iter().find(|(name, ..)| *name == option) {
+                options.flags.set(*flag, *set);
+                continue;
+            }
+            if let Some(propagation) = Propagation::ALL.iter().find(|p| p.to_string() == option) {
+                options.propagation = Some(*propagation);
+                continue;
+            }
+            match option {
+                "ro" => options.read_only = true,
+                "rw" => options.read_only = false,
+                "bind" => options.bind = true,
+                "rbind" => {
+                    options.bind = true;
+                    options.recursive = true;
+                }
+                "defaults" => {}
+                data => options.data.push(data.to_string()),
+            }
+        }
+        Ok(options)
+    }
+}
+
+impl Display for MountOptions {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut options = vec![if self.read_only { "ro" } else { "rw" }.to_string()];
+        options.extend(
+            FLAG_OPTIONS
+                .iter()
+                .filter(|(_, flag, set)| *set && self.flags.contains(*flag))
+                .map(|(name, ..)| name.to_string()),
+        );
+        match (self.bind, self.recursive) {
+            (true, true) => options.push("rbind".to_string()),
+            (true, false) => options.push("bind".to_string()),
+            _ => {}
+        }
+        options.extend(self.propagation.map(|p| p.to_string()));
+        options.extend(self.data.iter().cloned());
+        write!(f, "{}", options.join(","))
+    }
+}
+
+/// Options for `unmount`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UnmountOptions {
+    /// detach the mount now, and clean up once it is no longer busy
+    pub lazy: bool,
+    /// force the unmount, even if this may lose data (NFS only)
+    pub force: bool,
+}
+
+fn set_propagation(dest: &Path, propagation: Propagation) -> Result<()> {
+    nix::mount::mount::<str, _, str, str>(None, dest, None, propagation.flags(), None)
+        .context(Remount { dest })
+}
+
+/// Mount the filesystem of the given type, bind mounts are delegated to
+/// `bind_mount`.
+pub fn mount<S: AsRef<Path>, D: AsRef<Path>>(
+    source: S,
+    dest: D,
+    fstype: &str,
+    options: &MountOptions,
+) -> Result<()> {
+    let (source, dest) = (source.as_ref(), dest.as_ref());
+    if options.bind {
+        return bind_mount(source, dest, options);
+    }
+
+    nix::mount::mount(
+        Some(source),
+        dest,
+        Some(fstype),
+        options.ms_flags(),
+        options.data().as_deref(),
+    )
+    .context(Mount {
+        device: source,
+        dest,
+    })?;
+    match options.propagation {
+        Some(propagation) => set_propagation(dest, propagation),
+        None => Ok(()),
+    }
+}
+
+/// Bind mount the source onto dest. The kernel ignores all other flags when
+/// creating a bind mount, so they are applied with a remount afterwards.
+pub fn bind_mount<S: AsRef<Path>, D: AsRef<Path>>(
+    source: S,
+    dest: D,
+    options: &MountOptions,
+) -> Result<()> {
+    let (source, dest) = (source.as_ref(), dest.as_ref());
+    let mut flags = MsFlags::MS_BIND;
+    flags.set(MsFlags::MS_REC, options.recursive);
+    nix::mount::mount::<_, _, str, str>(Some(source), dest, None, flags, None).context(Mount {
+        device: source,
+        dest,
+    })?;
+
+    if options.read_only || !options.flags.is_empty() {
+        let remount = MountOptions {
+            bind: true,
+            propagation: None,
+            ..options.clone()
+        };
+        remount_flags(dest, &remount)?;
+    }
+    match options.propagation {
+        Some(propagation) => set_propagation(dest, propagation),
+        None => Ok(()),
+    }
+}
+
+fn remount_flags(dest: &Path, options: &MountOptions) -> Result<()> {
+    nix::mount::mount::<str, _, str, _>(
+        None,
+        dest,
+        None,
+        options.ms_flags() | MsFlags::MS_REMOUNT,
+        options.data().as_deref(),
+    )
+    .context(Remount { dest })
+}
+
+/// Change the options of an existing mount, i.e switch it to read-only.
+/// Set `bind` to only change the per mount point flags of a bind mount.
+pub fn remount<D: AsRef<Path>>(dest: D, options: &MountOptions) -> Result<()> {
+    let dest = dest.as_ref();
+    remount_flags(dest, options)?;
+    match options.propagation {
+        Some(propagation) => set_propagation(dest, propagation),
+        None => Ok(()),
+    }
+}
+
+pub fn unmount<D: AsRef<Path>>(dest: D, options: UnmountOptions) -> Result<()> {
+    let dest = dest.as_ref();
+    let mut flags = MntFlags::empty();
+    flags.set(MntFlags::MNT_DETACH, options.lazy);
+    flags.set(MntFlags::MNT_FORCE, options.force);
+    nix::mount::umount2(dest, flags).context(Unmount { dest })
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// The mounts whose mountpoint is dest, the last one is the top most mount.
+fn mounts_at(dest: &Path) -> Result<Vec<MountInfo>> {
+    let dest = canonical(dest);
+    let mounts = SafeMountIter::get()
+        .map_err(std::io::Error::from)
+        .context(MountTable)?;
+    Ok(mounts.flatten().filter(|m| m.dest == dest).collect())
+}
+
+/// Mount the source on dest, unless it is already mounted there. Returns
+/// true when the source was mounted by this call. It is an error when dest
+/// is mounted from another source, or when the read-only flag differs.
+///
+/// A bind mount is recognised by the source and dest residing on the same
+/// device, as the mount table only shows the device of the filesystem.
+pub fn ensure_mounted<S: AsRef<Path>, D: AsRef<Path>>(
+    source: S,
+    dest: D,
+    fstype: &str,
+    options: &MountOptions,
+) -> Result<bool> {
+    let (source, dest) = (source.as_ref(), dest.as_ref());
+    let mount = match mounts_at(dest)?.pop() {
+        Some(mount) => mount,
+        None => {
+            mount(source, dest, fstype, options)?;
+            return Ok(true);
+        }
+    };
+
+    let same_source = if options.bind {
+        let dev = |path: &Path| {
+            std::fs::metadata(path)
+                .map(|m| m.dev())
+                .context(Stat { path })
+        };
+        dev(source)? == dev(dest)?
+    } else {
+        canonical(&mount.source) == canonical(source)
+    };
+    if !same_source {
+        return AlreadyMounted {
+            dest,
+            device: mount.source,
+        }
+        .fail();
+    }
+
+    let read_only = mount.options.iter().any(|o| o == "ro");
+    if read_only != options.read_only {
+        return OptionsMismatch {
+            dest,
+            expected: options.to_string(),
+            found: mount.options.join(","),
+        }
+        .fail();
+    }
+    Ok(false)
+}
+
+/// Unmount dest, unless it is not mounted. Returns true when dest was
+/// unmounted by this call.
+pub fn ensure_unmounted<D: AsRef<Path>>(dest: D) -> Result<bool> {
+    let dest = dest.as_ref();
+    if mounts_at(dest)?.is_empty() {
+        return Ok(false);
+    }
+    unmount(dest, UnmountOptions::default())?;
+    Ok(true)
+}
+
+#[test]
+fn mount_options() {
+    let options = "ro,nosuid,nodev,rbind,rslave,discard,data=ordered"
+        .parse::<MountOptions>()
+        .unwrap();
+    assert!(options.read_only && options.bind && options.recursive);
+    assert_eq!(options.propagation, Some(Propagation::RSlave));
+    assert_eq!(options.flags, MsFlags::MS_NOSUID | MsFlags::MS_NODEV);
+    assert_eq!(options.data, vec!["discard", "data=ordered"]);
+    assert_eq!(
+        options.to_string(),
+        "ro,nosuid,nodev,rbind,rslave,discard,data=ordered"
+    );
+    assert_eq!(
+        options.ms_flags(),
+        MsFlags::MS_RDONLY
+            | MsFlags::MS_NOSUID
+            | MsFlags::MS_NODEV
+            | MsFlags::MS_BIND
+            | MsFlags::MS_REC
+    );
+
+    // later options override earlier ones
+    let options = "defaults,noexec,exec,ro,rw"
+        .parse::<MountOptions>()
+        .unwrap();
+    assert_eq!(options, MountOptions::default());
+    assert_eq!(options.to_string(), "rw");
+}
+
+#[test]
+fn mount_tmpfs() {
+    let base = std::env::temp_dir().join("devinfo_mount_tmpfs");
+    let (dest, bind) = (base.join("mnt"), base.join("bind"));
+    std::fs::create_dir_all(&dest).unwrap();
+    std::fs::create_dir_all(&bind).unwrap();
+
+    match ensure_mounted("tmpfs", &dest, "tmpfs", &"size=1m".parse().unwrap()) {
+        Err(MountError::Mount {
+            source: nix::errno::Errno::EPERM,
+            ..
+        }) => {
+            println!("not allowed to mount, skipping");
+            return;
+        }
+        result => assert!(result.unwrap()),
+    }
+    assert!(!ensure_mounted("tmpfs", &dest, "tmpfs", &MountOptions::default()).unwrap());
+    assert!(matches!(
+        ensure_mounted("/dev/null", &dest, "tmpfs", &MountOptions::default()),
+        Err(MountError::AlreadyMounted { .. })
+    ));
+    assert!(matches!(
+        ensure_mounted("tmpfs", &dest, "tmpfs", &MountOptions::read_only()),
+        Err(MountError::OptionsMismatch { .. })
+    ));
+
+    let options = MountOptions {
+        read_only: true,
+        ..MountOptions::bind(false)
+    };
+    assert!(ensure_mounted(&dest, &bind, "none", &options).unwrap());
+    assert!(!ensure_mounted(&dest, &bind, "none", &options).unwrap());
+    assert!(std::fs::write(bind.join("file"), "data").is_err());
+    std::fs::write(dest.join("file"), "data").unwrap();
+
+    remount(&bind, &MountOptions::bind(false)).unwrap();
+    std::fs::write(bind.join("file"), "data").unwrap();
+
+    assert!(ensure_unmounted(&bind).unwrap());
+    assert!(!ensure_unmounted(&bind).unwrap());
+    unmount(
+        &dest,
+        UnmountOptions {
+            lazy: true,
+            force: false,
+        },
+    )
+    .unwrap();
+    assert!(!ensure_unmounted(&dest).unwrap());
+    let _ = std::fs::remove_dir_all(base);
+}
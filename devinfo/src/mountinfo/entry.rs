@@ -0,0 +1,575 @@
+use crate::mountinfo::{error::Result as MountInfoResult, MountInfo, SafeMountIter};
+use nix::sys::stat::{major, minor};
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    io::{self, BufRead, Error, ErrorKind},
+    iter::FromIterator,
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// An optional field of a mountinfo line, describing the propagation of
+/// the mount.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub enum OptionalField {
+    /// shared:X, the mount is shared in peer group X
+    Shared(u32),
+    /// master:X, the mount is a slave of peer group X
+    Master(u32),
+    /// propagate_from:X, the mount is a slave and receives propagation from
+    /// peer group X, the closest dominant peer group
+    PropagateFrom(u32),
+    /// the mount is unbindable
+    Unbindable,
+    /// a field unknown to this parser
+    Other(String),
+}
+
+impl Display for OptionalField {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Shared(id) => write!(f, "shared:{}", id),
+            Self::Master(id) => write!(f, "master:{}", id),
+            Self::PropagateFrom(id) => write!(f, "propagate_from:{}", id),
+            Self::Unbindable => write!(f, "unbindable"),
+            Self::Other(field) => write!(f, "{}", field),
+        }
+    }
+}
+
+impl FromStr for OptionalField {
+    type Err = io::Error;
+
+    fn from_str(field: &str) -> Result<Self, Self::Err> {
+        let peer_group = |value: &str| {
+            value.parse::<u32>().map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid peer group in {field}"),
+                )
+            })
+        };
+        Ok(match field.split_once(':') {
+            Some(("shared", id)) => Self::Shared(peer_group(id)?),
+            Some(("master", id)) => Self::Master(peer_group(id)?),
+            Some(("propagate_from", id)) => Self::PropagateFrom(peer_group(id)?),
+            _ if field == "unbindable" => Self::Unbindable,
+            _ => Self::Other(field.to_string()),
+        })
+    }
+}
+
+/// A line of `/proc/<pid>/mountinfo`, see proc(5).
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct MountInfoEntry {
+    /// unique id of the mount, may be reused after an unmount
+    pub mount_id: u32,
+    /// id of the parent mount, or of itself for the root of the tree
+    pub parent_id: u32,
+    /// major number of the device holding the filesystem
+    pub major: u32,
+    /// minor number of the device holding the filesystem
+    pub minor: u32,
+    /// the directory of the filesystem which forms the root of this mount,
+    /// anything other than / indicates a bind mount of a subdirectory
+    pub root: PathBuf,
+    pub mount_point: PathBuf,
+    /// per mount options
+    pub mount_options: Vec<String>,
+    pub optional_fields: Vec<OptionalField>,
+    pub fstype: String,
+    /// filesystem specific source, i.e /dev/sda1 or none
+    pub source: PathBuf,
+    /// per superblock options
+    pub super_options: Vec<String>,
+}
+
+/// Escape the characters the kernel escapes in mountinfo as octal, which is
+/// the reverse of `MountInfo::parse_value`.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ' ' | '\t' | '\n' | '\\' => escaped.push_str(&format!("\\{:03o}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl Display for MountInfoEntry {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}:{} {} {} {}",
+            self.mount_id,
+            self.parent_id,
+            self.major,
+            self.minor,
+            escape(&self.root.to_string_lossy()),
+            escape(&self.mount_point.to_string_lossy()),
+            self.mount_options.join(","),
+        )?;
+        for field in &self.optional_fields {
+            write!(f, " {}", field)?;
+        }
+        write!(
+            f,
+            " - {} {} {}",
+            escape(&self.fstype),
+            escape(&self.source.to_string_lossy()),
+            self.super_options.join(",")
+        )
+    }
+}
+
+impl FromStr for MountInfoEntry {
+    type Err = io::Error;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut parts = line.split_whitespace();
+
+        fn map_err(why: &'static str) -> io::Error {
+            Error::new(ErrorKind::InvalidData, why)
+        }
+        fn parse_path(value: &str) -> io::Result<PathBuf> {
+            let path = MountInfo::parse_value(value)?;
+            let path = path
+                .into_string()
+                .map_err(|_| map_err("non-utf8 paths are unsupported"))?;
+            Ok(PathBuf::from(path))
+        }
+        fn parse_options(value: &str) -> Vec<String> {
+            value.split(',').map(String::from).collect()
+        }
+
+        let mount_id = parts.next().ok_or_else(|| map_err("missing mount id"))?;
+        let parent_id = parts.next().ok_or_else(|| map_err("missing parent id"))?;
+        let device = parts.next().ok_or_else(|| map_err("missing major:minor"))?;
+        let root = parts.next().ok_or_else(|| map_err("missing root"))?;
+        let mount_point = parts.next().ok_or_else(|| map_err("missing mount point"))?;
+        let mount_options = parts
+            .next()
+            .ok_or_else(|| map_err("missing mount options"))?;
+
+        let mut optional_fields = Vec::new();
+        loop {
+            match parts.next() {
+                Some("-") => break,
+                Some(field) => optional_fields.push(field.parse()?),
+                None => return Err(map_err("missing optional fields separator")),
+            }
+        }
+
+        let fstype = parts.next().ok_or_else(|| map_err("missing type"))?;
+        let source = parts.next().ok_or_else(|| map_err("missing source"))?;
+        let super_options = parts
+            .next()
+            .ok_or_else(|| map_err("missing super options"))?;
+
+        let (major, minor) = device
+            .split_once(':')
+            .and_then(|(major, minor)| Some((major.parse().ok()?, minor.parse().ok()?)))
+            .ok_or_else(|| map_err("major:minor is not a pair of numbers"))?;
+
+        Ok(MountInfoEntry {
+            mount_id: mount_id
+                .parse()
+                .map_err(|_| map_err("mount id is not a number"))?,
+            parent_id: parent_id
+                .parse()
+                .map_err(|_| map_err("parent id is not a number"))?,
+            major,
+            minor,
+            root: parse_path(root)?,
+            mount_point: parse_path(mount_point)?,
+            mount_options: parse_options(mount_options),
+            optional_fields,
+            fstype: MountInfo::parse_value(fstype)?
+                .to_string_lossy()
+                .into_owned(),
+            source: parse_path(source)?,
+            super_options: parse_options(super_options),
+        })
+    }
+}
+
+impl MountInfoEntry {
+    /// The peer group this mount is shared with.
+    pub fn shared(&self) -> Option<u32> {
+        self.optional_fields.iter().find_map(|field| match field {
+            OptionalField::Shared(id) => Some(*id),
+            _ => None,
+        })
+    }
+
+    /// The peer group this mount receives propagation from, as a slave.
+    pub fn master(&self) -> Option<u32> {
+        self.optional_fields.iter().find_map(|field| match field {
+            OptionalField::Master(id) => Some(*id),
+            _ => None,
+        })
+    }
+
+    /// Whether mount and unmount events under this mount are private.
+    pub fn is_private(&self) -> bool {
+        self.shared().is_none() && self.master().is_none()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.mount_options.iter().any(|option| option == "ro")
+    }
+
+    /// The device node of the block device holding the filesystem, i.e
+    /// /dev/dm-0 when mounted through /dev/mapper. None for filesystems
+    /// without a block device, such as tmpfs.
+    pub fn device_path(&self) -> Option<PathBuf> {
+        let sys = format!("/sys/dev/block/{}:{}", self.major, self.minor);
+        let name = std::fs::read_link(sys).ok()?.file_name()?.to_os_string();
+        Some(Path::new("/dev").join(name))
+    }
+}
+
+/// The major and minor number of the block device. Symlinks, such as those
+/// in /dev/mapper and /dev/disk/by-*, are followed.
+fn device_number(device: &Path) -> io::Result<(u32, u32)> {
+    let metadata = std::fs::metadata(device)?;
+    if !metadata.file_type().is_block_device() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} is not a block device", device.display()),
+        ));
+    }
+    let rdev = metadata.rdev();
+    Ok((major(rdev) as u32, minor(rdev) as u32))
+}
+
+impl From<&MountInfoEntry> for MountInfo {
+    /// The entry as it would appear in `/proc/mounts`, with the mount and
+    /// super options merged.
+    fn from(entry: &MountInfoEntry) -> Self {
+        let mut options = entry.mount_options.clone();
+        for option in &entry.super_options {
+            if !options.contains(option) && option != "ro" && option != "rw" {
+                options.push(option.clone());
+            }
+        }
+        MountInfo {
+            source: entry.source.clone(),
+            dest: entry.mount_point.clone(),
+            fstype: entry.fstype.clone(),
+            options,
+        }
+    }
+}
+
+/// Iteratively parse a `/proc/<pid>/mountinfo` file.
+pub struct MountInfoIter<R> {
+    file: R,
+    buffer: String,
+}
+
+impl<R: BufRead> MountInfoIter<R> {
+    /// Read mountinfo entries from any in-memory buffer.
+    pub fn new_from_reader(readable: R) -> Self {
+        Self {
+            file: readable,
+            buffer: String::with_capacity(512),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for MountInfoIter<R> {
+    type Item = io::Result<MountInfoEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.buffer.clear();
+            match self.file.read_line(&mut self.buffer) {
+                Ok(0) => return None,
+                Ok(_) => {
+                    let line = self.buffer.trim();
+                    if !line.is_empty() {
+                        return Some(MountInfoEntry::from_str(line));
+                    }
+                }
+                Err(why) => return Some(Err(why)),
+            }
+        }
+    }
+}
+
+/// The mountinfo entries arranged as a tree, by parent id.
+#[derive(Debug, Clone, Default)]
+pub struct MountTree {
+    entries: Vec<MountInfoEntry>,
+    by_id: HashMap<u32, usize>,
+}
+
+impl FromIterator<MountInfoEntry> for MountTree {
+    fn from_iter<I: IntoIterator<Item = MountInfoEntry>>(iter: I) -> Self {
+        let entries: Vec<_> = iter.into_iter().collect();
+        let by_id = entries
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| (entry.mount_id, index))
+            .collect();
+        Self { entries, by_id }
+    }
+}
+
+impl MountTree {
+    /// Read the mount tree of the process with the given pid, or of the
+    /// current process.
+    pub fn read(pid: Option<u32>) -> MountInfoResult<Self> {
+        Ok(SafeMountIter::get_mountinfo(pid)?.collect::<io::Result<_>>()?)
+    }
+
+    /// All entries, in the order of the mountinfo file. A mount always
+    /// comes after the mount it is stacked on.
+    pub fn entries(&self) -> &[MountInfoEntry] {
+        &self.entries
+    }
+
+    pub fn get(&self, mount_id: u32) -> Option<&MountInfoEntry> {
+        self.by_id.get(&mount_id).map(|index| &self.entries[*index])
+    }
+
+    /// The parent of the entry, None for the roots of the tree.
+    pub fn parent(&self, entry: &MountInfoEntry) -> Option<&MountInfoEntry> {
+        if entry.parent_id == entry.mount_id {
+            return None;
+        }
+        self.get(entry.parent_id)
+    }
+
+    pub fn children<'a>(
+        &'a self,
+        entry: &'a MountInfoEntry,
+    ) -> impl Iterator<Item = &'a MountInfoEntry> + 'a {
+        self.entries
+            .iter()
+            .filter(move |child| child.parent_id == entry.mount_id && child != &entry)
+    }
+
+    /// The entries whose parent is not visible, i.e the root filesystem of
+    /// the mount namespace.
+    pub fn roots(&self) -> impl Iterator<Item = &MountInfoEntry> {
+        self.entries
+            .iter()
+            .filter(move |entry| self.parent(entry).is_none())
+    }
+
+    /// The visible mount at the mount point, i.e the last one stacked on it.
+    pub fn by_mount_point<P: AsRef<Path>>(&self, mount_point: P) -> Option<&MountInfoEntry> {
+        let mount_point = mount_point.as_ref();
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| entry.mount_point == mount_point)
+    }
+
+    /// All mounts of the filesystem on the device, including bind mounts of
+    /// it and its subdirectories.
+    pub fn by_device(&self, major: u32, minor: u32) -> impl Iterator<Item = &MountInfoEntry> {
+        self.entries
+            .iter()
+            .filter(move |entry| entry.major == major && entry.minor == minor)
+    }
+
+    /// All mounts of the block device, referenced by any of its names, see
+    /// `by_device`.
+    pub fn mounts_of_device<P: AsRef<Path>>(&self, device: P) -> io::Result<Vec<&MountInfoEntry>> {
+        let (major, minor) = device_number(device.as_ref())?;
+        Ok(self.by_device(major, minor).collect())
+    }
+
+    /// All mounts stacked on the mount point, the last one is visible.
+    pub fn device_of_mountpoint<P: AsRef<Path>>(&self, path: P) -> Vec<&MountInfoEntry> {
+        let path = path.as_ref();
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        self.entries
+            .iter()
+            .filter(|entry| entry.mount_point == path)
+            .collect()
+    }
+}
+
+/// All mounts of the block device in the current mount namespace, whether
+/// mounted through its device node, a /dev/mapper or /dev/disk/by-* link, or
+/// bind mounted (in part) elsewhere. Every match is returned.
+pub fn mounts_of_device<P: AsRef<Path>>(device: P) -> MountInfoResult<Vec<MountInfoEntry>> {
+    let tree = MountTree::read(None)?;
+    let mounts = tree.mounts_of_device(device)?;
+    Ok(mounts.into_iter().cloned().collect())
+}
+
+/// All mounts on the mount point in the current mount namespace, which
+/// describe the device (major:minor and source) of each one. When mounts are
+/// stacked the last one is visible.
+pub fn device_of_mountpoint<P: AsRef<Path>>(path: P) -> MountInfoResult<Vec<MountInfoEntry>> {
+    let tree = MountTree::read(None)?;
+    let mounts = tree.device_of_mountpoint(path);
+    Ok(mounts.into_iter().cloned().collect())
+}
+
+#[test]
+fn mountinfo_entry() {
+    let line = "36 35 98:0 /mnt1 /mnt/my\\040dir rw,noatime master:1 shared:7 - ext3 /dev/root rw,errors=continue";
+    let entry = line.parse::<MountInfoEntry>().unwrap();
+    assert_eq!(entry.mount_id, 36);
+    assert_eq!(entry.parent_id, 35);
+    assert_eq!((entry.major, entry.minor), (98, 0));
+    assert_eq!(entry.root, Path::new("/mnt1"));
+    assert_eq!(entry.mount_point, Path::new("/mnt/my dir"));
+    assert_eq!(entry.mount_options, vec!["rw", "noatime"]);
+    assert_eq!(
+        entry.optional_fields,
+        vec![OptionalField::Master(1), OptionalField::Shared(7)]
+    );
+    assert_eq!((entry.shared(), entry.master()), (Some(7), Some(1)));
+    assert_eq!(entry.fstype, "ext3");
+    assert_eq!(entry.source, Path::new("/dev/root"));
+    assert_eq!(entry.super_options, vec!["rw", "errors=continue"]);
+    assert_eq!(entry.to_string(), line);
+    assert_eq!(
+        MountInfo::from(&entry).options,
+        vec!["rw", "noatime", "errors=continue"]
+    );
+
+    // the escaped characters survive a round trip
+    let line = "37 35 98:1 /a\\134b /mnt/tab\\011new\\012line rw - fuse.my\\040fs my\\040src rw";
+    let entry = line.parse::<MountInfoEntry>().unwrap();
+    assert_eq!(entry.root, Path::new("/a\\b"));
+    assert_eq!(entry.mount_point, Path::new("/mnt/tab\tnew\nline"));
+    assert_eq!(entry.fstype, "fuse.my fs");
+    assert_eq!(entry.to_string(), line);
+    assert_eq!(entry.to_string().parse::<MountInfoEntry>().unwrap(), entry);
+
+    assert!("36 35 98:0 / /mnt rw".parse::<MountInfoEntry>().is_err());
+    assert!("36 35 98 / /mnt rw - ext4 /dev/sda rw"
+        .parse::<MountInfoEntry>()
+        .is_err());
+}
+
+#[test]
+fn mountinfo_tree() {
+    let mountinfo = "\
+20 1 8:1 / / rw - ext4 /dev/sda1 rw
+21 20 0:5 / /proc rw shared:2 - proc proc rw
+22 20 8:2 / /data rw - xfs /dev/sda2 rw
+23 22 8:2 /volumes/pvc-1 /var/lib/kubelet/pods/1/volumes/pvc-1 ro master:3 - xfs /dev/sda2 rw
+24 20 0:6 / /data rw unbindable - tmpfs tmpfs rw
+";
+    let tree = MountInfoIter::new_from_reader(mountinfo.as_bytes())
+        .collect::<io::Result<MountTree>>()
+        .unwrap();
+    assert_eq!(tree.entries().len(), 5);
+    assert_eq!(
+        tree.roots().map(|e| e.mount_id).collect::<Vec<_>>(),
+        vec![20]
+    );
+
+    let root = tree.get(20).unwrap();
+    assert_eq!(
+        tree.children(root).map(|e| e.mount_id).collect::<Vec<_>>(),
+        vec![21, 22, 24]
+    );
+    let bind = tree.get(23).unwrap();
+    assert_eq!(tree.parent(bind).map(|e| e.mount_id), Some(22));
+    assert!(bind.is_read_only() && !bind.is_private());
+
+    assert_eq!(
+        tree.by_device(8, 2).map(|e| e.mount_id).collect::<Vec<_>>(),
+        vec![22, 23]
+    );
+    let data = tree.by_mount_point("/data").unwrap();
+    assert_eq!(data.mount_id, 24);
+    assert_eq!(data.optional_fields, vec![OptionalField::Unbindable]);
+}
+
+#[test]
+fn mountinfo_self() {
+    // other tests mount in parallel, so only check what they cannot change
+    let tree = MountTree::read(None).unwrap();
+    let root = tree.by_mount_point("/").unwrap();
+    assert!(tree.children(root).count() > 0);
+    let own = MountTree::read(Some(std::process::id())).unwrap();
+    assert_eq!(own.get(root.mount_id), Some(root));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn mounts_of_loop_device() {
+    use crate::{
+        loop_device::{attach_for_test, LoopOptions},
+        mount::{mount, unmount, MountOptions, UnmountOptions},
+    };
+    use std::process::Command;
+
+    /// Unmounts what is left mounted and removes the directory, also when an
+    /// assertion fails. Declared after the loop device, so it is dropped
+    /// first.
+    struct Cleanup {
+        base: PathBuf,
+        mounts: Vec<PathBuf>,
+    }
+    impl Drop for Cleanup {
+        fn drop(&mut self) {
+            for dest in self.mounts.iter().rev() {
+                let options = UnmountOptions {
+                    lazy: true,
+                    ..Default::default()
+                };
+                let _ = unmount(dest, options);
+            }
+            let _ = std::fs::remove_dir_all(&self.base);
+        }
+    }
+
+    assert!(mounts_of_device("/dev/null").is_err());
+
+    let base = std::env::temp_dir().join("devinfo_mounts_of_device");
+    let (image, mnt, bind) = (base.join("disk.img"), base.join("mnt"), base.join("bind"));
+    std::fs::create_dir_all(&mnt).unwrap();
+    std::fs::create_dir_all(&bind).unwrap();
+    std::fs::File::create(&image)
+        .and_then(|f| f.set_len(16 * 1024 * 1024))
+        .unwrap();
+
+    let loop_device = match attach_for_test(&image, &LoopOptions::default()) {
+        Some(device) => device,
+        None => return,
+    };
+    let mut cleanup = Cleanup {
+        base,
+        mounts: Vec::new(),
+    };
+    let device = loop_device.path().to_path_buf();
+    let mkfs = Command::new("mkfs.ext4").arg("-q").arg(&device).status();
+    if !mkfs.map(|s| s.success()).unwrap_or(false) {
+        println!("mkfs.ext4 not available, skipping");
+        return;
+    }
+
+    mount(&device, &mnt, "ext4", &MountOptions::default()).unwrap();
+    cleanup.mounts.push(mnt.clone());
+    std::fs::create_dir(mnt.join("sub")).unwrap();
+    mount(mnt.join("sub"), &bind, "none", &MountOptions::bind(false)).unwrap();
+    cleanup.mounts.push(bind.clone());
+
+    let mounts = mounts_of_device(&device).unwrap();
+    let roots: Vec<_> = mounts.iter().map(|m| m.root.as_path()).collect();
+    assert_eq!(roots, vec![Path::new("/"), Path::new("/sub")]);
+
+    let mounts = device_of_mountpoint(&bind).unwrap();
+    assert_eq!(mounts.len(), 1);
+    assert_eq!(mounts[0].device_path(), Some(device.clone()));
+
+    unmount(&bind, UnmountOptions::default()).unwrap();
+    unmount(&mnt, UnmountOptions::default()).unwrap();
+    cleanup.mounts.clear();
+    assert!(mounts_of_device(&device).unwrap().is_empty());
+}
@@ -2,6 +2,9 @@ use crate::{
     mountinfo::error::{MountInfoError, Result},
     partition::PartitionID,
 };
+pub use entry::{
+    device_of_mountpoint, mounts_of_device, MountInfoEntry, MountInfoIter, MountTree, OptionalField,
+};
 use io_utils::consistent_read;
 use std::{
     ffi::OsString,
@@ -13,11 +16,18 @@ use std::{
     str::FromStr,
     sync::OnceLock,
 };
+#[cfg(target_os = "linux")]
+pub use watcher::{MountTableDiff, MountWatcher};
 
+/// Parser for the mountinfo files.
+mod entry;
 /// Errors for MountInfo affairs.
 pub mod error;
 /// Contains tools to interact with files, etc.
 mod io_utils;
+/// Watch the mount table for changes.
+#[cfg(target_os = "linux")]
+mod watcher;
 
 #[derive(Debug, Default, Clone, Hash, Eq, PartialEq)]
 pub struct MountInfo {
@@ -178,7 +188,9 @@ impl<R: BufRead> MountIter<R> {
 
     /// Iterator-based variant of `source_mounted_at`.
     ///
-    /// Returns true if the `source` is mounted at the given `dest`.
+    /// Returns true if the `source` is mounted at the given `dest`. The
+    /// source is compared as is, see `mounts_of_device` to find the mounts
+    /// of a device by any of its names.
     ///
     /// Due to iterative parsing of the mount file, an error may be returned.
     pub fn source_mounted_at<D: AsRef<Path>, P: AsRef<Path>>(
@@ -188,18 +200,15 @@ impl<R: BufRead> MountIter<R> {
         let source = source.as_ref();
         let path = path.as_ref();
 
-        let mut is_found = false;
-
-        let mounts = MountIter::new()?;
-        for mount in mounts {
+        // the source may be mounted more than once
+        for mount in MountIter::new()? {
             let mount = mount?;
-            if mount.source == source {
-                is_found = mount.dest == path;
-                break;
+            if mount.source == source && mount.dest == path {
+                return Ok(true);
             }
         }
 
-        Ok(is_found)
+        Ok(false)
     }
 }
 
@@ -255,8 +264,33 @@ impl SafeMountIter {
     pub fn get_with_retries(
         retries: Option<u32>,
     ) -> Result<MountIter<BufReader<Box<dyn io::Read>>>> {
-        // Init.
-        let safe_mount_iter = SAFE_MOUNT_ITER.get_or_init(|| {
+        let safe_mount_iter = Self::instance();
+        let file = safe_mount_iter.open(safe_mount_iter.mounts_filepath.as_path(), retries)?;
+        Ok(MountIter::new_from_readable(file))
+    }
+
+    /// Get a Result<MountInfoIter> over `/proc/<pid>/mountinfo`, or over
+    /// `/proc/self/mountinfo` if no pid is given. Retry default no. of times.
+    pub fn get_mountinfo(pid: Option<u32>) -> Result<MountInfoIter<BufReader<Box<dyn io::Read>>>> {
+        Self::get_mountinfo_with_retries(pid, None)
+    }
+
+    /// Get a Result<MountInfoIter>, with the given no. of retries.
+    pub fn get_mountinfo_with_retries(
+        pid: Option<u32>,
+        retries: Option<u32>,
+    ) -> Result<MountInfoIter<BufReader<Box<dyn io::Read>>>> {
+        let path = match pid {
+            Some(pid) => PathBuf::from(format!("/proc/{pid}/mountinfo")),
+            None => PathBuf::from("/proc/self/mountinfo"),
+        };
+        let file = Self::instance().open(&path, retries)?;
+        Ok(MountInfoIter::new_from_reader(BufReader::new(file)))
+    }
+
+    /// Initialize (if not done already) and get the global instance.
+    fn instance() -> &'static Self {
+        SAFE_MOUNT_ITER.get_or_init(|| {
             use nix::sys::utsname::uname;
             use semver::Version;
 
@@ -292,17 +326,17 @@ impl SafeMountIter {
                 use_safe_mount,
                 mounts_filepath: PathBuf::from("/proc/mounts"),
             }
-        });
+        })
+    }
 
+    /// Open the mount file, with a consistent read if required.
+    fn open(&self, path: &Path, retries: Option<u32>) -> Result<Box<dyn io::Read>> {
         // Decide if consistent read is required.
-        if safe_mount_iter.use_safe_mount {
-            let buf = consistent_read(safe_mount_iter.mounts_filepath.as_path(), retries)?;
-            let buf: Box<dyn io::Read> = Box::new(std::io::Cursor::new(buf));
-            return Ok(MountIter::new_from_readable(buf));
+        if self.use_safe_mount {
+            let buf = consistent_read(path, retries)?;
+            return Ok(Box::new(std::io::Cursor::new(buf)));
         }
 
-        let file = File::open(safe_mount_iter.mounts_filepath.as_path())?;
-        let file: Box<dyn io::Read> = Box::new(file);
-        Ok(MountIter::new_from_readable(file))
+        Ok(Box::new(File::open(path)?))
     }
 }
@@ -0,0 +1,197 @@
+use crate::mountinfo::{error::Result, MountInfo, SafeMountIter};
+use futures::Stream;
+use nix::poll::{poll, PollFd, PollFlags};
+use std::{
+    collections::HashMap,
+    fs::File,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+use tokio::sync::mpsc;
+
+/// How long the watcher thread blocks before checking if it should stop.
+const POLL_TIMEOUT_MS: i32 = 100;
+/// Changes which are queued before the watcher thread blocks.
+const CHANGE_QUEUE_DEPTH: usize = 64;
+
+/// The mounts which were added and removed between two reads of the mount
+/// table. A mount which is stacked more than once is reported once per
+/// instance.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MountTableDiff {
+    pub added: Vec<MountInfo>,
+    pub removed: Vec<MountInfo>,
+}
+
+impl MountTableDiff {
+    /// Compare the previous mount table to the current one.
+    pub fn new(previous: &[MountInfo], current: &[MountInfo]) -> Self {
+        let mut counts = HashMap::<&MountInfo, isize>::new();
+        for mount in current {
+            *counts.entry(mount).or_default() += 1;
+        }
+        for mount in previous {
+            *counts.entry(mount).or_default() -= 1;
+        }
+
+        let mut diff = Self::default();
+        // keep the order of the mount table, rather than of the hash map
+        for mount in current {
+            if let Some(count) = counts.get_mut(mount).filter(|count| **count > 0) {
+                *count -= 1;
+                diff.added.push(mount.clone());
+            }
+        }
+        for mount in previous {
+            if let Some(count) = counts.get_mut(mount).filter(|count| **count < 0) {
+                *count += 1;
+                diff.removed.push(mount.clone());
+            }
+        }
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Watch the mount table of the current mount namespace for changes. The
+/// kernel flags `/proc/self/mountinfo` with POLLPRI whenever a mount is
+/// added, removed or changed, after which the mount table is read again
+/// (consistently, on kernels which need it) and compared to the last read.
+pub struct MountWatcher {
+    changes: mpsc::Receiver<Result<MountTableDiff>>,
+    stop: Arc<AtomicBool>,
+}
+
+fn read_mounts() -> Result<Vec<MountInfo>> {
+    Ok(SafeMountIter::get()?.flatten().collect())
+}
+
+impl MountWatcher {
+    /// Start watching, only the changes made after this returns are
+    /// yielded.
+    pub fn new() -> Result<Self> {
+        let (tx, changes) = mpsc::channel(CHANGE_QUEUE_DEPTH);
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+
+        // open the file before reading the table, so no change is missed
+        let file = File::open("/proc/self/mountinfo")?;
+        let mut mounts = read_mounts()?;
+
+        std::thread::Builder::new()
+            .name("mount-watcher".to_string())
+            .spawn(move || {
+                while !stopped.load(Ordering::Relaxed) {
+                    let mut fds = [PollFd::new(&file, PollFlags::POLLPRI)];
+                    match poll(&mut fds, POLL_TIMEOUT_MS) {
+                        Ok(0) | Err(nix::errno::Errno::EINTR) => continue,
+                        Ok(_) => {}
+                        Err(errno) => {
+                            let _ = tx.blocking_send(Err(errno.into()));
+                            return;
+                        }
+                    }
+
+                    let current = match read_mounts() {
+                        Ok(current) => current,
+                        Err(error) => {
+                            let _ = tx.blocking_send(Err(error));
+                            return;
+                        }
+                    };
+                    let diff = MountTableDiff::new(&mounts, &current);
+                    mounts = current;
+                    if !diff.is_empty() && tx.blocking_send(Ok(diff)).is_err() {
+                        return;
+                    }
+                }
+            })?;
+
+        Ok(Self { changes, stop })
+    }
+
+    /// Wait for the next change, None is returned when the watcher failed.
+    pub async fn next_change(&mut self) -> Option<Result<MountTableDiff>> {
+        self.changes.recv().await
+    }
+}
+
+impl Stream for MountWatcher {
+    type Item = Result<MountTableDiff>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.changes.poll_recv(cx)
+    }
+}
+
+impl Drop for MountWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn mount_table_diff() {
+    let mount = |dest: &str| MountInfo {
+        source: "tmpfs".into(),
+        dest: dest.into(),
+        fstype: "tmpfs".to_string(),
+        options: vec!["rw".to_string()],
+    };
+    let previous = [mount("/a"), mount("/b"), mount("/b")];
+    let current = [mount("/b"), mount("/c"), mount("/a"), mount("/c")];
+
+    let diff = MountTableDiff::new(&previous, &current);
+    assert_eq!(diff.added, vec![mount("/c"), mount("/c")]);
+    assert_eq!(diff.removed, vec![mount("/b")]);
+    assert!(MountTableDiff::new(&current, &current).is_empty());
+}
+
+#[tokio::test]
+async fn mount_watcher() {
+    use crate::mount::{ensure_mounted, unmount, MountError, MountOptions, UnmountOptions};
+    use std::time::Duration;
+
+    let dest = std::env::temp_dir().join("devinfo_mount_watcher");
+    std::fs::create_dir_all(&dest).unwrap();
+    let mut watcher = MountWatcher::new().unwrap();
+
+    match ensure_mounted("tmpfs", &dest, "tmpfs", &MountOptions::default()) {
+        Err(MountError::Mount {
+            source: nix::errno::Errno::EPERM,
+            ..
+        }) => {
+            println!("not allowed to mount, skipping");
+            return;
+        }
+        result => assert!(result.unwrap()),
+    }
+
+    // other tests may be mounting at the same time
+    async fn wait_for(watcher: &mut MountWatcher, dest: &std::path::Path, added: bool) {
+        loop {
+            let diff = watcher.next_change().await.unwrap().unwrap();
+            let mounts = if added { diff.added } else { diff.removed };
+            if mounts.iter().any(|mount| mount.dest == dest) {
+                return;
+            }
+        }
+    }
+    let timeout = Duration::from_secs(5);
+    tokio::time::timeout(timeout, wait_for(&mut watcher, &dest, true))
+        .await
+        .unwrap();
+
+    unmount(&dest, UnmountOptions::default()).unwrap();
+    tokio::time::timeout(timeout, wait_for(&mut watcher, &dest, false))
+        .await
+        .unwrap();
+    let _ = std::fs::remove_dir(dest);
+}
@@ -0,0 +1,346 @@
+//! Find the block devices of NVMe (over fabrics) namespaces through sysfs, by
+//! the NQN of their subsystem, their namespace id or one of their unique
+//! identifiers. With native NVMe multipath, a namespace has a single
+//! namespace head (nvme0n1) which is backed by a hidden device per path
+//! (nvme0c0n1, nvme0c1n1), only the head is ever returned by a lookup.
+
+use crate::{
+    topology::{is_nvme_path, DeviceKind},
+    DevInfoError,
+};
+use std::{
+    fmt::{Display, Formatter},
+    fs,
+    path::{Path, PathBuf},
+};
+use uuid::Uuid;
+
+const SYS_CLASS_BLOCK: &str = "/sys/class/block";
+
+/// A namespace as found in sysfs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NvmeNamespace {
+    /// kernel name, i.e nvme0n1 or nvme0c1n1
+    pub name: String,
+    /// NvmeHead, NvmePath or Disk when the namespace is not multipathed
+    pub kind: DeviceKind,
+    pub nsid: u32,
+    /// the NQN of the subsystem the namespace belongs to
+    pub subsysnqn: String,
+    pub uuid: Option<Uuid>,
+    /// the NGUID as 32 lowercase hex digits
+    pub nguid: Option<String>,
+    /// the EUI64 as 16 lowercase hex digits
+    pub eui64: Option<String>,
+    /// i.e uuid.9bcc7abd-5cd2-4d6e-a1d0-e4a2b9e5d2b7 or eui.0025388b91b2c3d4
+    pub wwid: Option<String>,
+}
+
+/// Keep only the hex digits of an identifier, which sysfs formats with
+/// dashes (NGUID) or spaces (EUI64), in lowercase. All zero identifiers are
+/// not set.
+fn normalize_id(id: &str) -> Option<String> {
+    let id = id
+        .chars()
+        .filter(char::is_ascii_hexdigit)
+        .collect::<String>()
+        .to_ascii_lowercase();
+    Some(id).filter(|id| id.chars().any(|c| c != '0'))
+}
+
+fn read_attr(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+impl NvmeNamespace {
+    /// Read the namespace from its sysfs directory, None when the device is
+    /// not an NVMe namespace.
+    fn from_sys(sys: &Path) -> Option<Self> {
+        let name = sys.file_name()?.to_str()?.to_string();
+        if !name.starts_with("nvme") || sys.join("partition").exists() {
+            return None;
+        }
+        let kind = if sys.join("multipath").exists() {
+            DeviceKind::NvmeHead
+        } else if is_nvme_path(&name) {
+            DeviceKind::NvmePath
+        } else {
+            DeviceKind::Disk
+        };
+
+        // the device of a namespace head is the subsystem, otherwise it is
+        // the controller, both of which have the NQN of the subsystem
+        Some(Self {
+            nsid: read_attr(&sys.join("nsid"))?.parse().ok()?,
+            subsysnqn: read_attr(&sys.join("device/subsysnqn"))?,
+            uuid: read_attr(&sys.join("uuid")).and_then(|uuid| uuid.parse().ok()),
+            nguid: read_attr(&sys.join("nguid")).and_then(|nguid| normalize_id(&nguid)),
+            eui64: read_attr(&sys.join("eui")).and_then(|eui| normalize_id(&eui)),
+            wwid: read_attr(&sys.join("wwid")),
+            name,
+            kind,
+        })
+    }
+
+    /// The device node, None for the per path devices which are hidden.
+    pub fn devnode(&self) -> Option<PathBuf> {
+        match self.kind {
+            DeviceKind::NvmePath => None,
+            _ => Some(Path::new("/dev").join(&self.name)),
+        }
+    }
+}
+
+/// All NVMe namespaces, including the per path devices.
+pub fn namespaces() -> Vec<NvmeNamespace> {
+    namespaces_in(Path::new(SYS_CLASS_BLOCK))
+}
+
+fn namespaces_in(sys_class_block: &Path) -> Vec<NvmeNamespace> {
+    let mut namespaces = fs::read_dir(sys_class_block)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| NvmeNamespace::from_sys(&entry.ok()?.path()))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    namespaces.sort_by(|a, b| a.name.cmp(&b.name));
+    namespaces
+}
+
+/// Which namespaces to look for, a namespace has to match all of the fields
+/// which are set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NvmeQuery {
+    /// the full NQN of the subsystem
+    pub nqn: Option<String>,
+    pub nsid: Option<u32>,
+    /// the namespace UUID, which is also matched against the WWID
+    pub uuid: Option<Uuid>,
+    /// in any format, only the hex digits are compared, all zeros matches
+    /// nothing
+    pub nguid: Option<String>,
+    /// in any format, only the hex digits are compared, all zeros matches
+    /// nothing
+    pub eui64: Option<String>,
+}
+
+impl Display for NvmeQuery {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut fields = Vec::new();
+        if let Some(nqn) = &self.nqn {
+            fields.push(format!("nqn={nqn}"));
+        }
+        if let Some(nsid) = self.nsid {
+            fields.push(format!("nsid={nsid}"));
+        }
+        if let Some(uuid) = self.uuid {
+            fields.push(format!("uuid={uuid}"));
+        }
+        if let Some(nguid) = &self.nguid {
+            fields.push(format!("nguid={nguid}"));
+        }
+        if let Some(eui64) = &self.eui64 {
+            fields.push(format!("eui64={eui64}"));
+        }
+        write!(fmt, "nvme namespace {}", fields.join(","))
+    }
+}
+
+impl NvmeQuery {
+    /// Query for the namespaces of the subsystem.
+    pub fn nqn(nqn: &str) -> Self {
+        Self {
+            nqn: Some(nqn.to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub fn matches(&self, namespace: &NvmeNamespace) -> bool {
+        // an identifier which is not set, or has no hex digits, matches
+        // no namespace at all
+        let id_matches = |query: &Option<String>, id: &Option<String>| match query {
+            Some(query) => normalize_id(query).is_some_and(|query| id.as_ref() == Some(&query)),
+            None => true,
+        };
+        let uuid_matches = |uuid: &Uuid| {
+            namespace.uuid.as_ref() == Some(uuid)
+                || namespace.wwid.as_deref() == Some(format!("uuid.{uuid}").as_str())
+        };
+
+        self.nqn.iter().all(|nqn| *nqn == namespace.subsysnqn)
+            && self.nsid.iter().all(|nsid| *nsid == namespace.nsid)
+            && self.uuid.iter().all(uuid_matches)
+            && id_matches(&self.nguid, &namespace.nguid)
+            && id_matches(&self.eui64, &namespace.eui64)
+    }
+
+    /// All matching namespaces, including the per path devices.
+    pub fn find(&self) -> Vec<NvmeNamespace> {
+        self.find_in(Path::new(SYS_CLASS_BLOCK))
+    }
+
+    fn find_in(&self, sys_class_block: &Path) -> Vec<NvmeNamespace> {
+        namespaces_in(sys_class_block)
+            .into_iter()
+            .filter(|namespace| self.matches(namespace))
+            .collect()
+    }
+
+    /// The device node of the single matching namespace. The per path
+    /// devices are skipped in favour of their namespace head, more than one
+    /// remaining match is an error as is the case when, for example, the
+    /// same namespace is connected through several controllers without
+    /// native multipath.
+    pub fn lookup(&self) -> Result<PathBuf, DevInfoError> {
+        self.lookup_in(Path::new(SYS_CLASS_BLOCK))
+    }
+
+    pub(crate) fn lookup_in(&self, sys_class_block: &Path) -> Result<PathBuf, DevInfoError> {
+        let mut devnodes = self
+            .find_in(sys_class_block)
+            .iter()
+            .filter_map(NvmeNamespace::devnode)
+            .collect::<Vec<_>>();
+        match devnodes.len() {
+            0 => Err(DevInfoError::NotFound {
+                path: self.to_string(),
+            }),
+            1 => Ok(devnodes.remove(0)),
+            _ => Err(DevInfoError::Ambiguous {
+                value: self.to_string(),
+                devices: devnodes.iter().map(|d| d.display().to_string()).collect(),
+            }),
+        }
+    }
+}
+
+#[test]
+fn nvme_namespace_lookup() {
+    use std::convert::TryFrom;
+
+    let sys = std::env::temp_dir().join(format!("devinfo_nvme_sysfs_{}", std::process::id()));
+    let nqn = "nqn.2019-05.io.openebs:00000000-76b6-4fcf-864d-1027d4038756";
+    let uuid = "9bcc7abd-5cd2-4d6e-a1d0-e4a2b9e5d2b7";
+    let namespace = |name: &str, nqn: &str, nsid: u32, uuid: Option<&str>| {
+        let dir = sys.join(name);
+        fs::create_dir_all(dir.join("device")).unwrap();
+        fs::write(dir.join("device/subsysnqn"), format!("{nqn}\n")).unwrap();
+        fs::write(dir.join("nsid"), format!("{nsid}\n")).unwrap();
+        fs::write(dir.join("eui"), "00 25 38 8b 91 b2 c3 d4\n").unwrap();
+        fs::write(dir.join("nguid"), "00000000-0000-0000-0000-000000000000\n").unwrap();
+        if let Some(uuid) = uuid {
+            fs::write(dir.join("uuid"), format!("{uuid}\n")).unwrap();
+            fs::write(dir.join("wwid"), format!("uuid.{uuid}\n")).unwrap();
+        }
+        dir
+    };
+    let _ = fs::remove_dir_all(&sys);
+    // a multipath namespace with two paths
+    fs::create_dir_all(namespace("nvme0n1", nqn, 1, Some(uuid)).join("multipath")).unwrap();
+    namespace("nvme0c0n1", nqn, 1, Some(uuid));
+    namespace("nvme0c1n1", nqn, 1, Some(uuid));
+    fs::write(
+        namespace("nvme0n1p1", nqn, 1, None).join("partition"),
+        "1\n",
+    )
+    .unwrap();
+    // the same subsystem, without multipath
+    namespace("nvme1n2", nqn, 2, None);
+    let local = (
+        "nqn.2014-08.org.nvmexpress:local",
+        "5d0c5b1e-3b43-4f4e-9d2c-6a1e0f7c8b21",
+    );
+    namespace("nvme2n1", local.0, 1, Some(local.1));
+    fs::create_dir_all(sys.join("sda")).unwrap();
+
+    let namespaces = namespaces_in(&sys);
+    let names = namespaces
+        .iter()
+        .map(|n| n.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        ["nvme0c0n1", "nvme0c1n1", "nvme0n1", "nvme1n2", "nvme2n1"]
+    );
+    assert_eq!(namespaces[0].kind, DeviceKind::NvmePath);
+    assert_eq!(namespaces[2].kind, DeviceKind::NvmeHead);
+    assert_eq!(namespaces[2].eui64.as_deref(), Some("0025388b91b2c3d4"));
+    assert_eq!(namespaces[2].nguid, None);
+    assert_eq!(namespaces[3].kind, DeviceKind::Disk);
+
+    let query = NvmeQuery {
+        nsid: Some(1),
+        ..NvmeQuery::nqn(nqn)
+    };
+    assert_eq!(query.find_in(&sys).len(), 3);
+    assert_eq!(query.lookup_in(&sys).unwrap(), Path::new("/dev/nvme0n1"));
+    let query = NvmeQuery {
+        uuid: uuid.parse().ok(),
+        ..Default::default()
+    };
+    assert_eq!(query.lookup_in(&sys).unwrap(), Path::new("/dev/nvme0n1"));
+    let query = NvmeQuery {
+        nsid: Some(2),
+        ..NvmeQuery::nqn(nqn)
+    };
+    assert_eq!(query.lookup_in(&sys).unwrap(), Path::new("/dev/nvme1n2"));
+
+    // the NQN of a URI with the uuid parameter is not the subsystem NQN
+    let uri = format!("nvmf://host/nqn.2019-05.io.openebs?uuid={uuid}");
+    match crate::BlkDev::try_from(uri.as_str()).unwrap() {
+        crate::BlkDev::Nvmf(nqn, params) => {
+            let query = crate::BlkDev::nvmf_query(&nqn, &params);
+            assert_eq!(query.nqn, None);
+            assert_eq!(query.lookup_in(&sys).unwrap(), Path::new("/dev/nvme0n1"));
+        }
+        dev => panic!("unexpected device {:?}", dev),
+    }
+    let query = crate::BlkDev::nvmf_query(nqn, &Default::default());
+    assert_eq!(query.nqn.as_deref(), Some(nqn));
+    // an NQN with a name rather than a uuid is the subsystem NQN
+    for (uuid, path) in [(local.1, Some("/dev/nvme2n1")), (uuid, None)] {
+        let uri = format!("nvmf://host/{}?uuid={uuid}", local.0);
+        match crate::BlkDev::try_from(uri.as_str()).unwrap() {
+            crate::BlkDev::Nvmf(nqn, params) => {
+                let query = crate::BlkDev::nvmf_query(&nqn, &params);
+                assert_eq!(query.nqn.as_deref(), Some(local.0));
+                assert_eq!(query.lookup_in(&sys).ok(), path.map(PathBuf::from));
+            }
+            dev => panic!("unexpected device {:?}", dev),
+        }
+    }
+
+    match NvmeQuery::nqn(nqn).lookup_in(&sys) {
+        Err(DevInfoError::Ambiguous { devices, .. }) => {
+            assert_eq!(devices, ["/dev/nvme0n1", "/dev/nvme1n2"])
+        }
+        result => panic!("unexpected result {:?}", result),
+    }
+    let query = NvmeQuery {
+        eui64: Some("00:25:38:8B:91:B2:C3:D4".to_string()),
+        nsid: Some(1),
+        ..Default::default()
+    };
+    assert!(matches!(
+        query.lookup_in(&sys),
+        Err(DevInfoError::Ambiguous { .. })
+    ));
+    for (nguid, eui64) in [(None, Some("zz")), (Some("0000-0000"), None)] {
+        let query = NvmeQuery {
+            nguid: nguid.map(str::to_string),
+            eui64: eui64.map(str::to_string),
+            ..NvmeQuery::nqn(nqn)
+        };
+        assert!(query.find_in(&sys).is_empty());
+    }
+    let query = NvmeQuery {
+        nsid: Some(3),
+        ..NvmeQuery::nqn(nqn)
+    };
+    assert!(matches!(query.lookup_in(&sys), Err(e) if e.is_transient()));
+    let _ = fs::remove_dir_all(&sys);
+}
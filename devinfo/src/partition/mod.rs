@@ -7,6 +7,7 @@ use std::{
     path::{Path, PathBuf},
     str::FromStr,
 };
+use uuid::Uuid;
 
 /// Describes a partition identity.
 ///
@@ -58,12 +59,23 @@ impl PartitionID {
         Self::new(SourcePath, id)
     }
 
+    /// Construct a new `PartitionID` as a `DiskSeq` source.
+    pub fn new_diskseq(id: String) -> Self {
+        Self::new(DiskSeq, id)
+    }
+
+    /// Construct a new `PartitionID` as a `WWN` source.
+    pub fn new_wwn(id: String) -> Self {
+        Self::new(WWN, id)
+    }
+
     /// Find the device path of this ID.
     pub fn get_device_path(&self) -> Option<PathBuf> {
         if self.variant == PartitionSource::Path && self.id.starts_with('/') {
             Some(PathBuf::from(&self.id))
         } else {
-            from_id(&self.id, &self.variant.disk_by_path())
+            let name = [self.variant.link_prefix(), &self.id].concat();
+            from_id(&name, &self.variant.disk_by_path())
         }
     }
 
@@ -71,10 +83,23 @@ impl PartitionID {
     pub fn get_source<P: AsRef<Path>>(variant: PartitionSource, path: P) -> Option<Self> {
         Some(Self {
             variant,
-            id: find_id(path.as_ref(), &variant.disk_by_path())?,
+            id: find_id(
+                path.as_ref(),
+                &variant.disk_by_path(),
+                variant.link_prefix(),
+            )?,
         })
     }
 
+    /// Find every ID of the device at the given path, one for each of its
+    /// `/dev/disk/by-*` symlinks.
+    pub fn get_all<P: AsRef<Path>>(path: P) -> Vec<Self> {
+        device_symlinks(path)
+            .iter()
+            .filter_map(|link| Self::from_disk_by_path(link.to_str()?).ok())
+            .collect()
+    }
+
     /// Find the UUID of the device at the given path.
     pub fn get_uuid<P: AsRef<Path>>(path: P) -> Option<Self> {
         Self::get_source(UUID, path)
@@ -95,7 +120,9 @@ impl PartitionID {
             return Err(Error::new(ErrorKind::NotFound, path));
         };
 
-        let id = if let Some(id) = path.strip_prefix("id/") {
+        let id = if let Some(id) = path.strip_prefix("id/wwn-") {
+            Self::new(WWN, id.into())
+        } else if let Some(id) = path.strip_prefix("id/") {
             Self::new(ID, id.into())
         } else if let Some(path) = path.strip_prefix("label/") {
             Self::new(Label, path.into())
@@ -104,15 +131,104 @@ impl PartitionID {
         } else if let Some(path) = path.strip_prefix("partuuid/") {
             Self::new(PartUUID, path.into())
         } else if let Some(path) = path.strip_prefix("path/") {
-            Self::new(PartUUID, path.into())
+            Self::new(SourcePath, path.into())
         } else if let Some(path) = path.strip_prefix("uuid/") {
-            Self::new(PartUUID, path.into())
+            Self::new(UUID, path.into())
+        } else if let Some(path) = path.strip_prefix("diskseq/") {
+            Self::new(DiskSeq, path.into())
+        } else if let Some(path) = path.strip_prefix("loop-ref/") {
+            Self::new(LoopRef, path.into())
+        } else if let Some(path) = path.strip_prefix("loop-inode/") {
+            Self::new(LoopInode, path.into())
         } else {
             return Err(Error::new(ErrorKind::InvalidData, path));
         };
 
         Ok(id)
     }
+
+    /// The structured form of a `/dev/disk/by-id` name, for the names which
+    /// carry a globally unique identifier.
+    pub fn disk_identifier(&self) -> Option<DiskIdentifier> {
+        match self.variant {
+            ID => self.id.parse().ok(),
+            WWN => ["wwn-", &self.id].concat().parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// The partition number of a `/dev/disk/by-id` name, i.e 1 for
+    /// wwn-0x5000c500a1b2c3d4-part1.
+    pub fn partition_number(&self) -> Option<u32> {
+        match self.variant {
+            ID | WWN => split_partition(&self.id).1,
+            _ => None,
+        }
+    }
+}
+
+/// Split the -partN suffix from a `/dev/disk/by-id` name.
+fn split_partition(name: &str) -> (&str, Option<u32>) {
+    match name.rsplit_once("-part") {
+        Some((disk, number)) => match number.parse() {
+            Ok(number) => (disk, Some(number)),
+            Err(_) => (name, None),
+        },
+        None => (name, None),
+    }
+}
+
+/// A globally unique disk identifier, parsed from a `/dev/disk/by-id` name.
+/// The partition suffix of the name, if any, is ignored.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub enum DiskIdentifier {
+    /// The World Wide Name of a SCSI or ATA disk, in lowercase hex without
+    /// the 0x prefix, from wwn-0x<wwn>.
+    Wwn(String),
+    /// The EUI64 or NGUID of an NVMe namespace, in lowercase hex, from
+    /// nvme-eui.<eui>.
+    NvmeEui(String),
+    /// The UUID of an NVMe namespace, from nvme-uuid.<uuid>.
+    NvmeUuid(Uuid),
+}
+
+impl Display for DiskIdentifier {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Wwn(wwn) => write!(fmt, "wwn-0x{}", wwn),
+            Self::NvmeEui(eui) => write!(fmt, "nvme-eui.{}", eui),
+            Self::NvmeUuid(uuid) => write!(fmt, "nvme-uuid.{}", uuid),
+        }
+    }
+}
+
+impl FromStr for DiskIdentifier {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::new(ErrorKind::InvalidData, input);
+        let hex = |value: &str, lengths: &[usize]| {
+            if lengths.contains(&value.len()) && value.chars().all(|c| c.is_ascii_hexdigit()) {
+                Ok(value.to_ascii_lowercase())
+            } else {
+                Err(invalid())
+            }
+        };
+
+        let (name, _) = split_partition(input);
+        if let Some(wwn) = name.strip_prefix("wwn-0x") {
+            // NAA 5 identifiers are 64 bits and NAA 6 identifiers 128 bits
+            Ok(Self::Wwn(hex(wwn, &[16, 32])?))
+        } else if let Some(eui) = name.strip_prefix("nvme-eui.") {
+            Ok(Self::NvmeEui(hex(eui, &[16, 32])?))
+        } else if let Some(uuid) = name.strip_prefix("nvme-uuid.") {
+            Ok(Self::NvmeUuid(
+                Uuid::parse_str(uuid).map_err(|_| invalid())?,
+            ))
+        } else {
+            Err(invalid())
+        }
+    }
 }
 
 impl Display for PartitionID {
@@ -159,6 +275,26 @@ impl FromStr for PartitionID {
                 variant: UUID,
                 id: s.to_owned(),
             })
+        } else if let Some(s) = input.strip_prefix("DISKSEQ=") {
+            Ok(PartitionID {
+                variant: DiskSeq,
+                id: s.to_owned(),
+            })
+        } else if let Some(s) = input.strip_prefix("LOOP_REF=") {
+            Ok(PartitionID {
+                variant: LoopRef,
+                id: s.to_owned(),
+            })
+        } else if let Some(s) = input.strip_prefix("LOOP_INODE=") {
+            Ok(PartitionID {
+                variant: LoopInode,
+                id: s.to_owned(),
+            })
+        } else if let Some(s) = input.strip_prefix("WWN=") {
+            Ok(PartitionID {
+                variant: WWN,
+                id: s.to_owned(),
+            })
         } else {
             Err(Error::new(ErrorKind::InvalidData, input))
         }
@@ -174,6 +310,14 @@ pub enum PartitionSource {
     PartUUID,
     Path,
     UUID,
+    /// The disk sequence number, unique until reboot.
+    DiskSeq,
+    /// The backing file of a loop device, as given when it was set up.
+    LoopRef,
+    /// The device and inode number of the backing file of a loop device.
+    LoopInode,
+    /// The World Wide Name, from a wwn- link in `/dev/disk/by-id`.
+    WWN,
 }
 
 impl Display for PartitionSource {
@@ -191,13 +335,36 @@ impl From<PartitionSource> for &'static str {
             PartitionSource::PartUUID => "PARTUUID",
             PartitionSource::Path => "PATH",
             PartitionSource::UUID => "UUID",
+            PartitionSource::DiskSeq => "DISKSEQ",
+            PartitionSource::LoopRef => "LOOP_REF",
+            PartitionSource::LoopInode => "LOOP_INODE",
+            PartitionSource::WWN => "WWN",
         }
     }
 }
 
 impl PartitionSource {
     fn disk_by_path(self) -> PathBuf {
-        PathBuf::from(["/dev/disk/by-", &<&'static str>::from(self).to_lowercase()].concat())
+        let dir = match self {
+            PartitionSource::ID | PartitionSource::WWN => "id",
+            PartitionSource::Label => "label",
+            PartitionSource::PartLabel => "partlabel",
+            PartitionSource::PartUUID => "partuuid",
+            PartitionSource::Path => "path",
+            PartitionSource::UUID => "uuid",
+            PartitionSource::DiskSeq => "diskseq",
+            PartitionSource::LoopRef => "loop-ref",
+            PartitionSource::LoopInode => "loop-inode",
+        };
+        PathBuf::from(["/dev/disk/by-", dir].concat())
+    }
+
+    /// The prefix of the symlink names which hold this source.
+    fn link_prefix(self) -> &'static str {
+        match self {
+            PartitionSource::WWN => "wwn-",
+            _ => "",
+        }
     }
 }
 
@@ -210,6 +377,10 @@ pub struct PartitionIdentifiers {
     pub part_uuid: Option<String>,
     pub path: Option<String>,
     pub uuid: Option<String>,
+    pub diskseq: Option<String>,
+    pub loop_ref: Option<String>,
+    pub loop_inode: Option<String>,
+    pub wwn: Option<String>,
 }
 
 impl PartitionIdentifiers {
@@ -225,6 +396,10 @@ impl PartitionIdentifiers {
             part_label: PartitionID::get_source(PartLabel, path).map(|id| id.id),
             part_uuid: PartitionID::get_source(PartUUID, path).map(|id| id.id),
             uuid: PartitionID::get_source(UUID, path).map(|id| id.id),
+            diskseq: PartitionID::get_source(DiskSeq, path).map(|id| id.id),
+            loop_ref: PartitionID::get_source(LoopRef, path).map(|id| id.id),
+            loop_inode: PartitionID::get_source(LoopInode, path).map(|id| id.id),
+            wwn: PartitionID::get_source(WWN, path).map(|id| id.id),
         }
     }
 
@@ -237,6 +412,10 @@ impl PartitionIdentifiers {
             PartUUID => self.part_uuid.as_ref().map_or(false, |s| &id.id == s),
             SourcePath => self.path.as_ref().map_or(false, |s| &id.id == s),
             UUID => self.uuid.as_ref().map_or(false, |s| &id.id == s),
+            DiskSeq => self.diskseq.as_ref().map_or(false, |s| &id.id == s),
+            LoopRef => self.loop_ref.as_ref().map_or(false, |s| &id.id == s),
+            LoopInode => self.loop_inode.as_ref().map_or(false, |s| &id.id == s),
+            WWN => self.wwn.as_ref().map_or(false, |s| &id.id == s),
         }
     }
 }
@@ -264,16 +443,41 @@ fn canonicalize(path: &Path) -> Cow<'_, Path> {
     }
 }
 
-/// Attempts to find the ID from the given path.
-fn find_id(path: &Path, uuid_dir: &Path) -> Option<String> {
+/// Attempts to find the ID from the given path, amongst the symlinks whose
+/// name starts with the prefix.
+fn find_id(path: &Path, uuid_dir: &Path, prefix: &str) -> Option<String> {
     // NOTE: It seems that the kernel may sometimes intermittently skip
     // directories.
     attempt(10, 1, move || {
         let dir = uuid_dir.read_dir().ok()?;
-        find_id_(path, dir)
+        find_id_(path, dir, prefix)
     })
 }
 
+/// All the `/dev/disk/by-*` symlinks of the device at the given path.
+pub fn device_symlinks<P: AsRef<Path>>(path: P) -> Vec<PathBuf> {
+    symlinks_in(Path::new("/dev/disk"), path.as_ref())
+}
+
+fn symlinks_in(disk_dir: &Path, path: &Path) -> Vec<PathBuf> {
+    let path = canonicalize(path);
+    let dirs = match disk_dir.read_dir() {
+        Ok(dirs) => dirs,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut links = dirs
+        .filter_map(|dir| dir.ok())
+        .filter(|dir| dir.file_name().to_string_lossy().starts_with("by-"))
+        .filter_map(|dir| dir.path().read_dir().ok())
+        .flat_map(|links| links.filter_map(|link| link.ok()))
+        .map(|link| link.path())
+        .filter(|link| canonicalize(link) == path)
+        .collect::<Vec<_>>();
+    links.sort();
+    links
+}
+
 fn from_id(uuid: &str, uuid_dir: &Path) -> Option<PathBuf> {
     // NOTE: It seems that the kernel may sometimes intermittently skip
     // directories.
@@ -283,14 +487,16 @@ fn from_id(uuid: &str, uuid_dir: &Path) -> Option<PathBuf> {
     })
 }
 
-fn find_id_(path: &Path, uuid_dir: fs::ReadDir) -> Option<String> {
+fn find_id_(path: &Path, uuid_dir: fs::ReadDir, prefix: &str) -> Option<String> {
     let path = canonicalize(path);
     for uuid_entry in uuid_dir.filter_map(|entry| entry.ok()) {
         let uuid_path = uuid_entry.path();
         let uuid_path = canonicalize(&uuid_path);
         if uuid_path == path {
             if let Some(uuid_entry) = uuid_entry.file_name().to_str() {
-                return Some(uuid_entry.into());
+                if let Some(id) = uuid_entry.strip_prefix(prefix) {
+                    return Some(id.into());
+                }
             }
         }
     }
@@ -312,3 +518,92 @@ fn from_id_(uuid: &str, uuid_dir: fs::ReadDir) -> Option<PathBuf> {
 
     None
 }
+
+#[test]
+fn partition_id_from_disk_by_path() {
+    let id = |path: &str| PartitionID::from_disk_by_path(path).unwrap();
+    assert_eq!(
+        id("/dev/disk/by-path/pci-0000:00:1f.2-ata-1"),
+        PartitionID::new_path("pci-0000:00:1f.2-ata-1".into())
+    );
+    assert_eq!(
+        id("/dev/disk/by-uuid/d5f1a2c3-0b0e-4c56-9d3a-7e5b1c2d3e4f"),
+        PartitionID::new_uuid("d5f1a2c3-0b0e-4c56-9d3a-7e5b1c2d3e4f".into())
+    );
+    assert_eq!(
+        id("/dev/disk/by-diskseq/12"),
+        PartitionID::new_diskseq("12".into())
+    );
+    assert_eq!(id("/dev/disk/by-loop-inode/0:49-1234").variant, LoopInode);
+    assert_eq!(id("/dev/disk/by-loop-ref/disk.img").variant, LoopRef);
+    assert!(PartitionID::from_disk_by_path("/dev/disk/by-foo/bar").is_err());
+    assert!(PartitionID::from_disk_by_path("/dev/sda").is_err());
+
+    let wwn = id("/dev/disk/by-id/wwn-0x5000C500A1B2C3D4-part2");
+    assert_eq!(wwn, PartitionID::new_wwn("0x5000C500A1B2C3D4-part2".into()));
+    assert_eq!(wwn.to_string(), "WWN=0x5000C500A1B2C3D4-part2");
+    assert_eq!(wwn.to_string().parse::<PartitionID>().unwrap(), wwn);
+    assert_eq!(
+        wwn.disk_identifier(),
+        Some(DiskIdentifier::Wwn("5000c500a1b2c3d4".into()))
+    );
+    assert_eq!(wwn.partition_number(), Some(2));
+
+    let eui = id("/dev/disk/by-id/nvme-eui.0025388b91b2c3d4");
+    assert_eq!(
+        eui.disk_identifier(),
+        Some(DiskIdentifier::NvmeEui("0025388b91b2c3d4".into()))
+    );
+    assert_eq!(eui.partition_number(), None);
+    let uuid = id("/dev/disk/by-id/nvme-uuid.0f3c5e48-9b1f-4d5e-8a2b-1c3d4e5f6a7b-part1");
+    assert_eq!(
+        uuid.disk_identifier().unwrap().to_string(),
+        "nvme-uuid.0f3c5e48-9b1f-4d5e-8a2b-1c3d4e5f6a7b"
+    );
+    assert_eq!(
+        id("/dev/disk/by-id/nvme-Samsung_SSD_970_S1234").disk_identifier(),
+        None
+    );
+    assert!("nvme-eui.xyz".parse::<DiskIdentifier>().is_err());
+}
+
+#[test]
+fn partition_id_symlinks() {
+    let base = std::env::temp_dir().join("devinfo_partition_symlinks");
+    let _ = fs::remove_dir_all(&base);
+    let device = base.join("sda1");
+    fs::create_dir_all(base.join("by-id")).unwrap();
+    fs::create_dir_all(base.join("by-uuid")).unwrap();
+    fs::write(&device, "").unwrap();
+    fs::write(base.join("sdb"), "").unwrap();
+
+    let link = |dir: &str, name: &str, target: &str| {
+        std::os::unix::fs::symlink(base.join(target), base.join(dir).join(name)).unwrap();
+    };
+    link("by-id", "wwn-0x5000c500a1b2c3d4-part1", "sda1");
+    link("by-id", "ata-Disk_S1234-part1", "sda1");
+    link("by-id", "ata-Disk_S5678", "sdb");
+    link("by-uuid", "d5f1a2c3", "sda1");
+
+    let links = symlinks_in(&base, &device);
+    assert_eq!(
+        links,
+        vec![
+            base.join("by-id/ata-Disk_S1234-part1"),
+            base.join("by-id/wwn-0x5000c500a1b2c3d4-part1"),
+            base.join("by-uuid/d5f1a2c3"),
+        ]
+    );
+
+    let id_dir = base.join("by-id");
+    assert_eq!(
+        find_id(&device, &id_dir, WWN.link_prefix()).as_deref(),
+        Some("0x5000c500a1b2c3d4-part1")
+    );
+    assert_eq!(find_id(&base.join("sdb"), &id_dir, WWN.link_prefix()), None);
+    assert_eq!(
+        from_id("wwn-0x5000c500a1b2c3d4-part1", &id_dir),
+        Some(device.canonicalize().unwrap())
+    );
+    let _ = fs::remove_dir_all(base);
+}
@@ -0,0 +1,158 @@
+//! On disk format of the GUID partition table, see UEFI 2.10 section 5.3.
+
+use super::{PartitionEntry, PartitionType};
+use std::convert::TryInto;
+use uuid::Uuid;
+
+pub(super) const SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Size of a partition entry, larger entries are allowed but never used.
+pub(super) const ENTRY_SIZE: usize = 128;
+/// Number of entries in the partition entry array.
+pub(super) const NUM_ENTRIES: usize = 128;
+/// Number of UTF-16 code units of the partition name.
+pub(super) const NAME_LEN: usize = 36;
+const HEADER_SIZE: usize = 92;
+const REVISION: u32 = 0x0001_0000;
+
+/// CRC32 as used by GPT (IEEE 802.3, reflected).
+pub(super) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0 .. 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset .. offset + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset .. offset + 8].try_into().unwrap())
+}
+
+fn guid_at(buf: &[u8], offset: usize) -> Uuid {
+    Uuid::from_bytes_le(buf[offset .. offset + 16].try_into().unwrap())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Header {
+    pub(super) my_lba: u64,
+    pub(super) alternate_lba: u64,
+    pub(super) first_usable: u64,
+    pub(super) last_usable: u64,
+    pub(super) disk_guid: Uuid,
+    pub(super) entries_lba: u64,
+    pub(super) num_entries: u32,
+    pub(super) entry_size: u32,
+    pub(super) entries_crc: u32,
+}
+
+impl Header {
+    /// Encode the header into a buffer of one sector.
+    pub(super) fn encode(&self, sector_size: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; sector_size];
+        buf[0 .. 8].copy_from_slice(SIGNATURE);
+        buf[8 .. 12].copy_from_slice(&REVISION.to_le_bytes());
+        buf[12 .. 16].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+        buf[24 .. 32].copy_from_slice(&self.my_lba.to_le_bytes());
+        buf[32 .. 40].copy_from_slice(&self.alternate_lba.to_le_bytes());
+        buf[40 .. 48].copy_from_slice(&self.first_usable.to_le_bytes());
+        buf[48 .. 56].copy_from_slice(&self.last_usable.to_le_bytes());
+        buf[56 .. 72].copy_from_slice(&self.disk_guid.to_bytes_le());
+        buf[72 .. 80].copy_from_slice(&self.entries_lba.to_le_bytes());
+        buf[80 .. 84].copy_from_slice(&self.num_entries.to_le_bytes());
+        buf[84 .. 88].copy_from_slice(&self.entry_size.to_le_bytes());
+        buf[88 .. 92].copy_from_slice(&self.entries_crc.to_le_bytes());
+        let crc = crc32(&buf[.. HEADER_SIZE]);
+        buf[16 .. 20].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// Decode and verify the header, None is returned when the signature
+    /// or the checksum does not match.
+    pub(super) fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() < HEADER_SIZE || &buf[0 .. 8] != SIGNATURE {
+            return None;
+        }
+        let size = u32_at(buf, 12) as usize;
+        if size < HEADER_SIZE || size > buf.len() {
+            return None;
+        }
+        let mut header = buf[.. size].to_vec();
+        header[16 .. 20].copy_from_slice(&[0; 4]);
+        if crc32(&header) != u32_at(buf, 16) {
+            return None;
+        }
+
+        Some(Self {
+            my_lba: u64_at(buf, 24),
+            alternate_lba: u64_at(buf, 32),
+            first_usable: u64_at(buf, 40),
+            last_usable: u64_at(buf, 48),
+            disk_guid: guid_at(buf, 56),
+            entries_lba: u64_at(buf, 72),
+            num_entries: u32_at(buf, 80),
+            entry_size: u32_at(buf, 84),
+            entries_crc: u32_at(buf, 88),
+        })
+    }
+}
+
+/// Encode the partition entry array, the slot of an entry is given by its
+/// partition number.
+pub(super) fn encode_entries(entries: &[Option<PartitionEntry>]) -> Vec<u8> {
+    let mut buf = vec![0u8; NUM_ENTRIES * ENTRY_SIZE];
+    for entry in entries.iter().flatten() {
+        let e = &mut buf[(entry.number as usize - 1) * ENTRY_SIZE ..][.. ENTRY_SIZE];
+        let type_guid = match entry.part_type {
+            PartitionType::Guid(guid) => guid,
+            PartitionType::Mbr(_) => Uuid::nil(),
+        };
+        e[0 .. 16].copy_from_slice(&type_guid.to_bytes_le());
+        e[16 .. 32].copy_from_slice(&entry.uuid.unwrap_or_default().to_bytes_le());
+        e[32 .. 40].copy_from_slice(&entry.start.to_le_bytes());
+        e[40 .. 48].copy_from_slice(&(entry.start + entry.size - 1).to_le_bytes());
+        e[48 .. 56].copy_from_slice(&entry.attributes.to_le_bytes());
+        for (i, unit) in entry.name.encode_utf16().take(NAME_LEN).enumerate() {
+            e[56 + 2 * i .. 58 + 2 * i].copy_from_slice(&unit.to_le_bytes());
+        }
+    }
+    buf
+}
+
+/// Decode the partition entry array, unused entries are None.
+pub(super) fn decode_entries(buf: &[u8], entry_size: usize) -> Vec<Option<PartitionEntry>> {
+    buf.chunks_exact(entry_size)
+        .enumerate()
+        .map(|(i, e)| {
+            let part_type = guid_at(e, 0);
+            if part_type.is_nil() {
+                return None;
+            }
+            let start = u64_at(e, 32);
+            let end = u64_at(e, 40);
+            let name = e[56 .. 56 + 2 * NAME_LEN]
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .take_while(|c| *c != 0)
+                .collect::<Vec<_>>();
+            Some(PartitionEntry {
+                number: i as u32 + 1,
+                start,
+                size: end.saturating_sub(start) + 1,
+                part_type: PartitionType::Guid(part_type),
+                uuid: Some(guid_at(e, 16)),
+                name: String::from_utf16_lossy(&name),
+                attributes: u64_at(e, 48),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,119 @@
+//! On disk format of the DOS master boot record, only the primary
+//! partitions are handled.
+
+use super::{PartitionEntry, PartitionType, ATTR_LEGACY_BIOS_BOOTABLE};
+use std::convert::TryInto;
+
+pub(super) const NUM_ENTRIES: usize = 4;
+/// Partition type of the single partition of a protective MBR.
+pub(super) const PROTECTIVE_TYPE: u8 = 0xee;
+const SIGNATURE_OFFSET: usize = 440;
+const ENTRIES_OFFSET: usize = 446;
+const ENTRY_SIZE: usize = 16;
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const STATUS_BOOTABLE: u8 = 0x80;
+
+/// CHS address of the LBA, using the usual 255 heads and 63 sectors per
+/// track geometry. Addresses which do not fit are capped at 1023/254/63.
+fn chs(lba: u64) -> [u8; 3] {
+    let cylinder = lba / (255 * 63);
+    if cylinder > 1023 {
+        return [0xfe, 0xff, 0xff];
+    }
+    let head = (lba / 63) % 255;
+    let sector = lba % 63 + 1;
+    [
+        head as u8,
+        (sector as u8 & 0x3f) | ((cylinder >> 2) as u8 & 0xc0),
+        cylinder as u8,
+    ]
+}
+
+fn record(status: u8, part_type: u8, start: u64, size: u64) -> [u8; ENTRY_SIZE] {
+    let mut e = [0u8; ENTRY_SIZE];
+    e[0] = status;
+    e[1 .. 4].copy_from_slice(&chs(start));
+    e[4] = part_type;
+    e[5 .. 8].copy_from_slice(&chs(start + size - 1));
+    e[8 .. 12].copy_from_slice(&(start as u32).to_le_bytes());
+    e[12 .. 16].copy_from_slice(&(size as u32).to_le_bytes());
+    e
+}
+
+/// Whether the sector holds a MBR, which may be a protective one.
+pub(super) fn is_valid(sector: &[u8]) -> bool {
+    sector.len() >= 512 && sector[510 .. 512] == BOOT_SIGNATURE
+}
+
+/// Whether the sector holds the protective MBR of a GPT disk.
+pub(super) fn is_protective(sector: &[u8]) -> bool {
+    is_valid(sector) && sector[ENTRIES_OFFSET + 4] == PROTECTIVE_TYPE
+}
+
+/// Update the first sector with the given entries, the boot code is kept.
+pub(super) fn encode(sector: &mut [u8], disk_signature: u32, entries: &[Option<PartitionEntry>]) {
+    sector[SIGNATURE_OFFSET .. SIGNATURE_OFFSET + 4].copy_from_slice(&disk_signature.to_le_bytes());
+    for (i, entry) in entries.iter().enumerate().take(NUM_ENTRIES) {
+        let e = match entry {
+            Some(entry) => {
+                let part_type = match entry.part_type {
+                    PartitionType::Mbr(part_type) => part_type,
+                    PartitionType::Guid(_) => 0,
+                };
+                let status = if entry.attributes & ATTR_LEGACY_BIOS_BOOTABLE != 0 {
+                    STATUS_BOOTABLE
+                } else {
+                    0
+                };
+                record(status, part_type, entry.start, entry.size)
+            }
+            None => [0u8; ENTRY_SIZE],
+        };
+        sector[ENTRIES_OFFSET + i * ENTRY_SIZE ..][.. ENTRY_SIZE].copy_from_slice(&e);
+    }
+    sector[510 .. 512].copy_from_slice(&BOOT_SIGNATURE);
+}
+
+/// Update the first sector with a protective MBR covering the whole disk.
+pub(super) fn encode_protective(sector: &mut [u8], total_sectors: u64) {
+    let size = (total_sectors - 1).min(u32::MAX as u64);
+    sector[ENTRIES_OFFSET .. ENTRIES_OFFSET + NUM_ENTRIES * ENTRY_SIZE].copy_from_slice(&[0; 64]);
+    let mut e = record(0, PROTECTIVE_TYPE, 1, size);
+    // the end address of the protective partition is always capped
+    e[5 .. 8].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    sector[ENTRIES_OFFSET .. ENTRIES_OFFSET + ENTRY_SIZE].copy_from_slice(&e);
+    sector[510 .. 512].copy_from_slice(&BOOT_SIGNATURE);
+}
+
+/// Decode the disk signature and the primary partitions.
+pub(super) fn decode(sector: &[u8]) -> (u32, Vec<Option<PartitionEntry>>) {
+    let signature = u32::from_le_bytes(
+        sector[SIGNATURE_OFFSET .. SIGNATURE_OFFSET + 4]
+            .try_into()
+            .unwrap(),
+    );
+    let entries = (0 .. NUM_ENTRIES)
+        .map(|i| {
+            let e = &sector[ENTRIES_OFFSET + i * ENTRY_SIZE ..][.. ENTRY_SIZE];
+            let start = u32::from_le_bytes(e[8 .. 12].try_into().unwrap()) as u64;
+            let size = u32::from_le_bytes(e[12 .. 16].try_into().unwrap()) as u64;
+            if e[4] == 0 || size == 0 {
+                return None;
+            }
+            Some(PartitionEntry {
+                number: i as u32 + 1,
+                start,
+                size,
+                part_type: PartitionType::Mbr(e[4]),
+                uuid: None,
+                name: String::new(),
+                attributes: if e[0] & STATUS_BOOTABLE != 0 {
+                    ATTR_LEGACY_BIOS_BOOTABLE
+                } else {
+                    0
+                },
+            })
+        })
+        .collect();
+    (signature, entries)
+}
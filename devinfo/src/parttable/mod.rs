@@ -0,0 +1,713 @@
+//! Create and edit GPT and MBR partition tables on block devices or regular
+//! image files.
+//!
+//! All changes are made in memory, `write` stores the table on the device
+//! and `reread` makes the kernel pick up the new partitions of a block
+//! device.
+//!
+//! # Example
+//! ```no_run
+//! use devinfo::parttable::{PartitionSpec, PartitionTable, TableKind};
+//!
+//! let mut table = PartitionTable::create("/dev/sdb", TableKind::Gpt).unwrap();
+//! table
+//!     .add_partition(PartitionSpec {
+//!         name: "data".to_string(),
+//!         ..Default::default()
+//!     })
+//!     .unwrap();
+//! table.write().unwrap();
+//! table.reread().unwrap();
+//! ```
+
+use crate::{ioctl::BlockDevice, DevInfoError};
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::{File, OpenOptions},
+    os::unix::fs::{FileExt, FileTypeExt},
+    path::{Path, PathBuf},
+};
+use uuid::Uuid;
+
+mod gpt;
+mod mbr;
+
+/// GPT attribute: the partition is required for the platform to function.
+pub const ATTR_REQUIRED: u64 = 1;
+/// GPT attribute: the firmware must not produce a block I/O protocol.
+pub const ATTR_NO_BLOCK_IO_PROTOCOL: u64 = 1 << 1;
+/// GPT attribute: legacy BIOS bootable, the boot flag of a MBR partition.
+pub const ATTR_LEGACY_BIOS_BOOTABLE: u64 = 1 << 2;
+/// Default alignment of new partitions in bytes.
+pub const DEFAULT_ALIGNMENT: u64 = 1024 * 1024;
+
+fn table_error(value: String) -> DevInfoError {
+    DevInfoError::PartitionTable { value }
+}
+
+/// The type of partition table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableKind {
+    Gpt,
+    /// DOS master boot record
+    Mbr,
+}
+
+impl Display for TableKind {
+    /// The names as used by libblkid (PTTYPE).
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Gpt => write!(f, "gpt"),
+            Self::Mbr => write!(f, "dos"),
+        }
+    }
+}
+
+/// The type of a partition, which depends on the table kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    Guid(Uuid),
+    Mbr(u8),
+}
+
+impl PartitionType {
+    /// GPT type of a Linux filesystem partition.
+    pub const LINUX_FILESYSTEM: Self =
+        Self::Guid(Uuid::from_u128(0x0fc6_3daf_8483_4772_8e79_3d69_d847_7de4));
+    /// GPT type of a Linux LVM partition.
+    pub const LINUX_LVM: Self =
+        Self::Guid(Uuid::from_u128(0xe6d6_d379_f507_44c2_a23c_238f_2a3d_f928));
+    /// MBR type of a Linux partition.
+    pub const MBR_LINUX: Self = Self::Mbr(0x83);
+    /// MBR type of a Linux LVM partition.
+    pub const MBR_LINUX_LVM: Self = Self::Mbr(0x8e);
+
+    fn default_for(kind: TableKind) -> Self {
+        match kind {
+            TableKind::Gpt => Self::LINUX_FILESYSTEM,
+            TableKind::Mbr => Self::MBR_LINUX,
+        }
+    }
+
+    fn matches(&self, kind: TableKind) -> bool {
+        matches!(
+            (self, kind),
+            (Self::Guid(_), TableKind::Gpt) | (Self::Mbr(_), TableKind::Mbr)
+        )
+    }
+}
+
+/// A partition of the table, start and size are in logical sectors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionEntry {
+    /// partition number, starting at 1
+    pub number: u32,
+    pub start: u64,
+    pub size: u64,
+    pub part_type: PartitionType,
+    /// unique partition GUID, GPT only
+    pub uuid: Option<Uuid>,
+    /// partition name, GPT only
+    pub name: String,
+    /// GPT attribute bits, only the bootable flag exists for MBR
+    pub attributes: u64,
+}
+
+impl PartitionEntry {
+    /// The last sector of the partition.
+    pub fn end(&self) -> u64 {
+        self.start + self.size - 1
+    }
+}
+
+/// Parameters of a new partition, start and size are in logical sectors.
+#[derive(Debug, Clone, Default)]
+pub struct PartitionSpec {
+    /// first sector, by default the first aligned free sector
+    pub start: Option<u64>,
+    /// by default the partition fills the free space it is placed in
+    pub size: Option<u64>,
+    /// by default a Linux filesystem partition
+    pub part_type: Option<PartitionType>,
+    pub name: String,
+    pub attributes: u64,
+}
+
+/// A partition table of a block device or image file.
+#[derive(Debug)]
+pub struct PartitionTable {
+    file: File,
+    path: PathBuf,
+    /// None for image files
+    device: Option<BlockDevice>,
+    sector_size: u64,
+    total_sectors: u64,
+    kind: TableKind,
+    disk_guid: Uuid,
+    disk_signature: u32,
+    entries: Vec<Option<PartitionEntry>>,
+    alignment: u64,
+    created: bool,
+}
+
+impl PartitionTable {
+    fn open_device(path: &Path, kind: TableKind) -> Result<Self, DevInfoError> {
+        let io_error = |source| DevInfoError::Io { source };
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(io_error)?;
+        let metadata = file.metadata().map_err(io_error)?;
+        let device = if metadata.file_type().is_block_device() {
+            let file = file.try_clone().map_err(io_error)?;
+            Some(BlockDevice::from_file(file, path)?)
+        } else {
+            None
+        };
+
+        let (sector_size, size) = match &device {
+            Some(device) => (device.logical_block_size()? as u64, device.size()?),
+            None => (512, metadata.len()),
+        };
+
+        let total_sectors = size / sector_size;
+        if total_sectors < 2 * (Self::gpt_entry_sectors(sector_size) + 2) {
+            return Err(table_error(format!(
+                "{} is too small to hold a partition table",
+                path.display()
+            )));
+        }
+
+        let signature = Uuid::new_v4();
+        let num_entries = match kind {
+            TableKind::Gpt => gpt::NUM_ENTRIES,
+            TableKind::Mbr => mbr::NUM_ENTRIES,
+        };
+        Ok(Self {
+            file,
+            path: path.to_path_buf(),
+            device,
+            sector_size,
+            total_sectors,
+            kind,
+            disk_guid: signature,
+            disk_signature: u32::from_le_bytes([
+                signature.as_bytes()[0],
+                signature.as_bytes()[1],
+                signature.as_bytes()[2],
+                signature.as_bytes()[3],
+            ]),
+            entries: vec![None; num_entries],
+            alignment: (DEFAULT_ALIGNMENT / sector_size).max(1),
+            created: true,
+        })
+    }
+
+    /// Create a new and empty partition table, any existing table is
+    /// replaced once the table is written.
+    pub fn create<P: AsRef<Path>>(path: P, kind: TableKind) -> Result<Self, DevInfoError> {
+        Self::open_device(path.as_ref(), kind)
+    }
+
+    /// Open the existing partition table. A GPT with a corrupt primary
+    /// header is read from the backup header.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, DevInfoError> {
+        let mut table = Self::open_device(path.as_ref(), TableKind::Gpt)?;
+        table.created = false;
+
+        let sector0 = table.read_sector(0)?;
+        if mbr::is_protective(&sector0) {
+            let header = [1, table.total_sectors - 1]
+                .iter()
+                .find_map(|lba| gpt::Header::decode(&table.read_sector(*lba).ok()?))
+                .ok_or_else(|| {
+                    table_error(format!("{} has no valid GPT header", table.path.display()))
+                })?;
+            if header.entry_size as usize != gpt::ENTRY_SIZE {
+                return Err(DevInfoError::NotSupported {
+                    value: format!("GPT entries of {} bytes", header.entry_size),
+                });
+            }
+
+            // the header is untrusted, the entry array has to fit on the disk
+            // before it is allocated
+            let disk_size = table.total_sectors * table.sector_size;
+            let entries_size = (header.num_entries as u64).checked_mul(gpt::ENTRY_SIZE as u64);
+            let entries_offset = header.entries_lba.checked_mul(table.sector_size);
+            let (size, offset) = match (entries_size, entries_offset) {
+                (Some(size), Some(offset))
+                    if offset.checked_add(size).is_some_and(|end| end <= disk_size) =>
+                {
+                    (size, offset)
+                }
+                _ => {
+                    return Err(table_error(format!(
+                        "{} has a GPT partition entry array beyond the end of the disk",
+                        table.path.display()
+                    )))
+                }
+            };
+
+            let mut buf = vec![0u8; size as usize];
+            table
+                .file
+                .read_exact_at(&mut buf, offset)
+                .map_err(|source| DevInfoError::Io { source })?;
+            if gpt::crc32(&buf) != header.entries_crc {
+                return Err(table_error(format!(
+                    "{} has a corrupt GPT partition entry array",
+                    table.path.display()
+                )));
+            }
+
+            let mut entries = gpt::decode_entries(&buf, gpt::ENTRY_SIZE);
+            if entries.iter().skip(gpt::NUM_ENTRIES).any(Option::is_some) {
+                return Err(DevInfoError::NotSupported {
+                    value: format!("more than {} GPT partitions", gpt::NUM_ENTRIES),
+                });
+            }
+            entries.resize(gpt::NUM_ENTRIES, None);
+            table.entries = entries;
+            table.disk_guid = header.disk_guid;
+        } else if mbr::is_valid(&sector0) {
+            let (signature, entries) = mbr::decode(&sector0);
+            table.kind = TableKind::Mbr;
+            table.entries = entries;
+            table.disk_signature = signature;
+        } else {
+            return Err(table_error(format!(
+                "{} has no partition table",
+                table.path.display()
+            )));
+        }
+
+        Ok(table)
+    }
+
+    fn gpt_entry_sectors(sector_size: u64) -> u64 {
+        ((gpt::NUM_ENTRIES * gpt::ENTRY_SIZE) as u64).div_ceil(sector_size)
+    }
+
+    fn read_sector(&self, lba: u64) -> Result<Vec<u8>, DevInfoError> {
+        let mut buf = vec![0u8; self.sector_size as usize];
+        self.file
+            .read_exact_at(&mut buf, lba * self.sector_size)
+            .map_err(|source| DevInfoError::Io { source })?;
+        Ok(buf)
+    }
+
+    fn write_at(&self, buf: &[u8], lba: u64) -> Result<(), DevInfoError> {
+        self.file
+            .write_all_at(buf, lba * self.sector_size)
+            .map_err(|source| DevInfoError::Io { source })
+    }
+
+    pub fn kind(&self) -> TableKind {
+        self.kind
+    }
+
+    /// Logical sector size of the device in bytes.
+    pub fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    pub fn total_sectors(&self) -> u64 {
+        self.total_sectors
+    }
+
+    /// The GPT disk GUID, or the MBR disk signature.
+    pub fn disk_id(&self) -> String {
+        match self.kind {
+            TableKind::Gpt => self.disk_guid.to_string(),
+            TableKind::Mbr => format!("{:08x}", self.disk_signature),
+        }
+    }
+
+    /// The first sector which may be used by a partition.
+    pub fn first_usable(&self) -> u64 {
+        match self.kind {
+            TableKind::Gpt => 2 + Self::gpt_entry_sectors(self.sector_size),
+            TableKind::Mbr => 1,
+        }
+    }
+
+    /// The last sector which may be used by a partition.
+    pub fn last_usable(&self) -> u64 {
+        match self.kind {
+            TableKind::Gpt => self.total_sectors - 2 - Self::gpt_entry_sectors(self.sector_size),
+            TableKind::Mbr => (self.total_sectors - 1).min(u32::MAX as u64),
+        }
+    }
+
+    /// Alignment of new partitions in sectors.
+    pub fn alignment(&self) -> u64 {
+        self.alignment
+    }
+
+    /// Set the alignment of new partitions in bytes, which is rounded up to
+    /// a whole number of sectors.
+    pub fn set_alignment(&mut self, bytes: u64) {
+        self.alignment = bytes.div_ceil(self.sector_size).max(1);
+    }
+
+    pub fn partitions(&self) -> impl Iterator<Item = &PartitionEntry> {
+        self.entries.iter().flatten()
+    }
+
+    pub fn partition(&self, number: u32) -> Option<&PartitionEntry> {
+        self.partitions().find(|p| p.number == number)
+    }
+
+    fn partition_mut(&mut self, number: u32) -> Result<&mut PartitionEntry, DevInfoError> {
+        self.entries
+            .iter_mut()
+            .flatten()
+            .find(|p| p.number == number)
+            .ok_or_else(|| table_error(format!("partition {} does not exist", number)))
+    }
+
+    /// The unused regions of the disk as inclusive (first, last) sectors.
+    pub fn free_regions(&self) -> Vec<(u64, u64)> {
+        let mut used = self
+            .partitions()
+            .map(|p| (p.start, p.end()))
+            .collect::<Vec<_>>();
+        used.sort_unstable();
+
+        let mut regions = Vec::new();
+        let mut next = self.first_usable();
+        for (start, end) in used {
+            if start > next {
+                regions.push((next, start - 1));
+            }
+            next = next.max(end + 1);
+        }
+        if next <= self.last_usable() {
+            regions.push((next, self.last_usable()));
+        }
+        regions
+    }
+
+    /// Check that the range is free, ignoring the given partition.
+    fn check_free(&self, start: u64, end: u64, ignore: u32) -> Result<(), DevInfoError> {
+        if start < self.first_usable() || end > self.last_usable() || end < start {
+            return Err(table_error(format!(
+                "sectors {}-{} are outside of the usable range {}-{}",
+                start,
+                end,
+                self.first_usable(),
+                self.last_usable()
+            )));
+        }
+        match self
+            .partitions()
+            .find(|p| p.number != ignore && p.start <= end && start <= p.end())
+        {
+            Some(p) => Err(table_error(format!(
+                "sectors {}-{} overlap with partition {}",
+                start, end, p.number
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Add a partition and return its number.
+    pub fn add_partition(&mut self, spec: PartitionSpec) -> Result<u32, DevInfoError> {
+        let part_type = spec
+            .part_type
+            .unwrap_or_else(|| PartitionType::default_for(self.kind));
+        if !part_type.matches(self.kind) {
+            return Err(table_error(format!(
+                "partition type {:?} can not be used in a {} table",
+                part_type, self.kind
+            )));
+        }
+        if self.kind == TableKind::Mbr && !spec.name.is_empty() {
+            return Err(DevInfoError::NotSupported {
+                value: "partition names in a dos table".to_string(),
+            });
+        }
+        Self::check_name(&spec.name)?;
+        self.check_attributes(spec.attributes)?;
+
+        let slot = self
+            .entries
+            .iter()
+            .position(Option::is_none)
+            .ok_or_else(|| table_error("the partition table is full".to_string()))?;
+
+        let align = self.alignment;
+        let (start, end) = self
+            .free_regions()
+            .into_iter()
+            .find_map(|(first, last)| {
+                let start = match spec.start {
+                    Some(start) if start < first || start > last => return None,
+                    Some(start) => start,
+                    None => first.div_ceil(align) * align,
+                };
+                let end = match spec.size {
+                    Some(0) => return None,
+                    Some(size) => start.checked_add(size - 1)?,
+                    None => last,
+                };
+                (start <= end && end <= last).then_some((start, end))
+            })
+            .ok_or_else(|| table_error("no free space for the partition".to_string()))?;
+
+        let number = slot as u32 + 1;
+        self.entries[slot] = Some(PartitionEntry {
+            number,
+            start,
+            size: end - start + 1,
+            part_type,
+            uuid: match self.kind {
+                TableKind::Gpt => Some(Uuid::new_v4()),
+                TableKind::Mbr => None,
+            },
+            name: spec.name,
+            attributes: spec.attributes,
+        });
+        Ok(number)
+    }
+
+    pub fn delete_partition(&mut self, number: u32) -> Result<(), DevInfoError> {
+        self.partition_mut(number)?;
+        self.entries[number as usize - 1] = None;
+        Ok(())
+    }
+
+    /// Change the size of the partition in sectors, the start is unchanged.
+    pub fn resize_partition(&mut self, number: u32, size: u64) -> Result<(), DevInfoError> {
+        let start = self.partition_mut(number)?.start;
+        if size == 0 {
+            return Err(table_error("the partition size can not be 0".to_string()));
+        }
+        self.check_free(start, start + size - 1, number)?;
+        self.partition_mut(number)?.size = size;
+        Ok(())
+    }
+
+    fn check_name(name: &str) -> Result<(), DevInfoError> {
+        if name.encode_utf16().count() > gpt::NAME_LEN {
+            return Err(table_error(format!(
+                "the partition name {} is longer than {} characters",
+                name,
+                gpt::NAME_LEN
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_attributes(&self, attributes: u64) -> Result<(), DevInfoError> {
+        if self.kind == TableKind::Mbr && attributes & !ATTR_LEGACY_BIOS_BOOTABLE != 0 {
+            return Err(DevInfoError::NotSupported {
+                value: format!("attributes {:#x} in a dos table", attributes),
+            });
+        }
+        Ok(())
+    }
+
+    /// Set the partition name, GPT only.
+    pub fn set_name(&mut self, number: u32, name: &str) -> Result<(), DevInfoError> {
+        if self.kind != TableKind::Gpt {
+            return Err(DevInfoError::NotSupported {
+                value: "partition names in a dos table".to_string(),
+            });
+        }
+        Self::check_name(name)?;
+        self.partition_mut(number)?.name = name.to_string();
+        Ok(())
+    }
+
+    pub fn set_type(&mut self, number: u32, part_type: PartitionType) -> Result<(), DevInfoError> {
+        if !part_type.matches(self.kind) {
+            return Err(table_error(format!(
+                "partition type {:?} can not be used in a {} table",
+                part_type, self.kind
+            )));
+        }
+        self.partition_mut(number)?.part_type = part_type;
+        Ok(())
+    }
+
+    /// Set the attribute bits of the partition, a dos table only supports
+    /// ATTR_LEGACY_BIOS_BOOTABLE.
+    pub fn set_attributes(&mut self, number: u32, attributes: u64) -> Result<(), DevInfoError> {
+        self.check_attributes(attributes)?;
+        self.partition_mut(number)?.attributes = attributes;
+        Ok(())
+    }
+
+    /// Write the table to the device. When the table was created, rather
+    /// than opened, the remains of any other kind of table are removed.
+    pub fn write(&mut self) -> Result<(), DevInfoError> {
+        let mut sector0 = self.read_sector(0)?;
+        let last = self.total_sectors - 1;
+
+        match self.kind {
+            TableKind::Gpt => {
+                let entries = gpt::encode_entries(&self.entries);
+                let entry_sectors = Self::gpt_entry_sectors(self.sector_size);
+                let backup_entries_lba = last - entry_sectors;
+                let mut header = gpt::Header {
+                    my_lba: 1,
+                    alternate_lba: last,
+                    first_usable: self.first_usable(),
+                    last_usable: self.last_usable(),
+                    disk_guid: self.disk_guid,
+                    entries_lba: 2,
+                    num_entries: gpt::NUM_ENTRIES as u32,
+                    entry_size: gpt::ENTRY_SIZE as u32,
+                    entries_crc: gpt::crc32(&entries),
+                };
+
+                if self.created {
+                    sector0.iter_mut().for_each(|b| *b = 0);
+                }
+                mbr::encode_protective(&mut sector0, self.total_sectors);
+                self.write_at(&sector0, 0)?;
+                self.write_at(&header.encode(self.sector_size as usize), 1)?;
+                self.write_at(&entries, 2)?;
+
+                header.my_lba = last;
+                header.alternate_lba = 1;
+                header.entries_lba = backup_entries_lba;
+                self.write_at(&entries, backup_entries_lba)?;
+                self.write_at(&header.encode(self.sector_size as usize), last)?;
+            }
+            TableKind::Mbr => {
+                if self.created && mbr::is_protective(&sector0) {
+                    sector0.iter_mut().for_each(|b| *b = 0);
+                }
+                mbr::encode(&mut sector0, self.disk_signature, &self.entries);
+                self.write_at(&sector0, 0)?;
+
+                if self.created {
+                    // a stale GPT would take precedence over the new table
+                    let zero = vec![0u8; self.sector_size as usize];
+                    for lba in [1, last] {
+                        if self.read_sector(lba)?.starts_with(gpt::SIGNATURE) {
+                            self.write_at(&zero, lba)?;
+                        }
+                    }
+                }
+            }
+        }
+
+        self.created = false;
+        self.file
+            .sync_all()
+            .map_err(|source| DevInfoError::Io { source })
+    }
+
+    /// Make the kernel re-read the partition table of a block device, which
+    /// fails with EBUSY when any of its partitions is in use. This is a
+    /// no-op for image files.
+    pub fn reread(&self) -> Result<(), DevInfoError> {
+        match &self.device {
+            Some(device) => device.reread_partitions(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn partition_table_edit() {
+    use crate::blkid::probe::Probe;
+
+    let path = std::env::temp_dir().join("devinfo_partition_table.img");
+    let file = File::create(&path).unwrap();
+    file.set_len(64 * 1024 * 1024).unwrap();
+
+    for kind in [TableKind::Gpt, TableKind::Mbr] {
+        let mut table = PartitionTable::create(&path, kind).unwrap();
+        let p1 = table
+            .add_partition(PartitionSpec {
+                size: Some(16 * 2048),
+                ..Default::default()
+            })
+            .unwrap();
+        let p2 = table.add_partition(PartitionSpec::default()).unwrap();
+        assert_eq!((p1, p2), (1, 2));
+        assert_eq!(table.partition(1).unwrap().start, 2048);
+        assert_eq!(table.partition(2).unwrap().start, 2048 + 16 * 2048);
+        assert_eq!(table.partition(2).unwrap().end(), table.last_usable());
+        assert!(table.resize_partition(1, 17 * 2048).is_err());
+        assert!(table.add_partition(PartitionSpec::default()).is_err());
+        if kind == TableKind::Gpt {
+            table.set_name(2, "data").unwrap();
+            table.set_type(2, PartitionType::LINUX_LVM).unwrap();
+        }
+        table.set_attributes(1, ATTR_LEGACY_BIOS_BOOTABLE).unwrap();
+        table.write().unwrap();
+        table.reread().unwrap();
+
+        // libblkid must agree with what we wrote
+        let mut probe = Probe::new_from_filename(&path).unwrap();
+        probe.enable_partitions(true).unwrap();
+        assert_eq!(probe.do_safe_probe().unwrap(), 0);
+        assert_eq!(probe.lookup_value("PTTYPE").unwrap(), kind.to_string());
+        assert_eq!(probe.partitions().unwrap().numof_partitions().unwrap(), 2);
+
+        let mut table = PartitionTable::open(&path).unwrap();
+        assert_eq!(table.kind(), kind);
+        assert_eq!(table.partitions().count(), 2);
+        assert_eq!(
+            table.partition(1).unwrap().attributes,
+            ATTR_LEGACY_BIOS_BOOTABLE
+        );
+        if kind == TableKind::Gpt {
+            assert_eq!(table.partition(2).unwrap().name, "data");
+            assert_eq!(
+                table.partition(2).unwrap().part_type,
+                PartitionType::LINUX_LVM
+            );
+        }
+
+        table.delete_partition(2).unwrap();
+        table.resize_partition(1, 32 * 2048).unwrap();
+        table.write().unwrap();
+        let table = PartitionTable::open(&path).unwrap();
+        assert_eq!(table.partitions().count(), 1);
+        assert_eq!(table.partition(1).unwrap().size, 32 * 2048);
+    }
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn partition_table_invalid_gpt_header() {
+    let path = std::env::temp_dir().join("devinfo_partition_table_header.img");
+    let file = File::create(&path).unwrap();
+    file.set_len(8 * 1024 * 1024).unwrap();
+    PartitionTable::create(&path, TableKind::Gpt)
+        .unwrap()
+        .write()
+        .unwrap();
+
+    let last = 8 * 2048 - 1;
+    let header = |num_entries, entries_lba| gpt::Header {
+        my_lba: 1,
+        alternate_lba: last,
+        first_usable: 34,
+        last_usable: last - 33,
+        disk_guid: Uuid::new_v4(),
+        entries_lba,
+        num_entries,
+        entry_size: gpt::ENTRY_SIZE as u32,
+        entries_crc: 0,
+    };
+    for (num_entries, entries_lba) in [(u32::MAX, 2), (128, u64::MAX / 2), (128, last)] {
+        let sector = header(num_entries, entries_lba).encode(512);
+        file.write_all_at(&sector, 512).unwrap();
+        file.write_all_at(&sector, last * 512).unwrap();
+        assert!(matches!(
+            PartitionTable::open(&path),
+            Err(DevInfoError::PartitionTable { .. })
+        ));
+    }
+
+    let _ = std::fs::remove_file(path);
+}
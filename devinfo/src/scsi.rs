@@ -0,0 +1,480 @@
+//! Find the SCSI devices of iSCSI sessions through sysfs, and rescan or
+//! delete them. Every iSCSI session has a SCSI host of its own, the devices
+//! of the session are the LUNs of that host.
+
+use crate::{DevInfoError, InvalidPath, InvalidUri, InvalidUuid};
+use snafu::ResultExt;
+use std::{
+    convert::TryFrom,
+    fmt::{Display, Formatter},
+    fs,
+    net::IpAddr,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+use url::{Host, Url};
+use uuid::Uuid;
+
+const SYS: &str = "/sys";
+/// The port of the portal when the URI does not have one.
+pub const ISCSI_PORT: u16 = 3260;
+
+fn io_error(path: &Path, source: std::io::Error) -> DevInfoError {
+    match source.kind() {
+        std::io::ErrorKind::NotFound => DevInfoError::NotFound {
+            path: path.display().to_string(),
+        },
+        _ => DevInfoError::Io { source },
+    }
+}
+
+fn read_attr(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn write_attr(path: &Path, value: &str) -> Result<(), DevInfoError> {
+    fs::write(path, value).map_err(|source| io_error(path, source))
+}
+
+fn read_names(path: &Path) -> Vec<String> {
+    let mut names = fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+/// The address of a SCSI device, i.e 2:0:0:1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Hctl {
+    pub host: u32,
+    pub channel: u32,
+    pub target: u32,
+    pub lun: u64,
+}
+
+impl FromStr for Hctl {
+    type Err = DevInfoError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let parse = || {
+            let mut parts = value.split(':');
+            let hctl = Self {
+                host: parts.next()?.parse().ok()?,
+                channel: parts.next()?.parse().ok()?,
+                target: parts.next()?.parse().ok()?,
+                lun: parts.next()?.parse().ok()?,
+            };
+            Some(hctl).filter(|_| parts.next().is_none())
+        };
+        parse().ok_or_else(|| DevInfoError::ParseError {
+            value: value.to_string(),
+        })
+    }
+}
+
+impl Display for Hctl {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            fmt,
+            "{}:{}:{}:{}",
+            self.host, self.channel, self.target, self.lun
+        )
+    }
+}
+
+/// A device in /sys/class/scsi_device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScsiDevice {
+    pub hctl: Hctl,
+    pub vendor: Option<String>,
+    pub model: Option<String>,
+    /// i.e running, offline or blocked
+    pub state: Option<String>,
+    /// kernel name of the block device, None for devices which are not
+    /// disks or which are still being probed
+    pub block: Option<String>,
+    sys: PathBuf,
+}
+
+impl ScsiDevice {
+    fn from_sys(sys: &Path) -> Option<Self> {
+        let hctl = sys.file_name()?.to_str()?.parse().ok()?;
+        let device = sys.join("device");
+        Some(Self {
+            hctl,
+            vendor: read_attr(&device.join("vendor")),
+            model: read_attr(&device.join("model")),
+            state: read_attr(&device.join("state")),
+            block: read_names(&device.join("block")).into_iter().next(),
+            sys: device,
+        })
+    }
+
+    /// The device node of the block device.
+    pub fn devnode(&self) -> Option<PathBuf> {
+        self.block.as_ref().map(|name| Path::new("/dev").join(name))
+    }
+
+    /// Have the kernel read the capacity of the device again, i.e after the
+    /// volume was resized.
+    pub fn rescan(&self) -> Result<(), DevInfoError> {
+        write_attr(&self.sys.join("rescan"), "1")
+    }
+
+    /// Remove the device from the kernel, which should be done after the
+    /// LUN is unmapped and before logging out of the session.
+    pub fn delete(&self) -> Result<(), DevInfoError> {
+        write_attr(&self.sys.join("delete"), "1")
+    }
+}
+
+/// All SCSI devices.
+pub fn devices() -> Vec<ScsiDevice> {
+    devices_in(Path::new(SYS))
+}
+
+fn devices_in(sys: &Path) -> Vec<ScsiDevice> {
+    let class = sys.join("class/scsi_device");
+    let mut devices = read_names(&class)
+        .iter()
+        .filter_map(|name| ScsiDevice::from_sys(&class.join(name)))
+        .collect::<Vec<_>>();
+    devices.sort_by_key(|device| device.hctl);
+    devices
+}
+
+/// Scan all channels, targets and LUNs of the SCSI host for new devices.
+pub fn rescan_host(host: u32) -> Result<(), DevInfoError> {
+    rescan_host_in(Path::new(SYS), host)
+}
+
+fn rescan_host_in(sys: &Path, host: u32) -> Result<(), DevInfoError> {
+    let scan = sys.join(format!("class/scsi_host/host{host}/scan"));
+    write_attr(&scan, "- - -")
+}
+
+/// A session in /sys/class/iscsi_session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IscsiSession {
+    /// the number of the session, 1 for session1
+    pub id: u32,
+    /// the IQN of the target
+    pub target_name: String,
+    /// i.e LOGGED_IN or FAILED
+    pub state: Option<String>,
+    /// the SCSI host of the session
+    pub host: Option<u32>,
+    /// the portal of the leading connection
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    /// the root of sysfs, i.e /sys
+    sys_root: PathBuf,
+}
+
+impl IscsiSession {
+    fn from_sys(sys: &Path, id: u32) -> Option<Self> {
+        let session = sys.join(format!("class/iscsi_session/session{id}"));
+        // the session sits below its host in the device tree
+        let host = session
+            .join("device")
+            .canonicalize()
+            .ok()
+            .and_then(|device| {
+                device
+                    .components()
+                    .filter_map(|c| match c {
+                        Component::Normal(name) => {
+                            name.to_str()?.strip_prefix("host")?.parse().ok()
+                        }
+                        _ => None,
+                    })
+                    .next_back()
+            });
+        let connection = sys.join(format!("class/iscsi_connection/connection{id}:0"));
+        let connection_attr = |name: &str| {
+            read_attr(&connection.join(format!("persistent_{name}")))
+                .or_else(|| read_attr(&connection.join(name)))
+        };
+
+        Some(Self {
+            id,
+            target_name: read_attr(&session.join("targetname"))?,
+            state: read_attr(&session.join("state")),
+            host,
+            address: connection_attr("address"),
+            port: connection_attr("port").and_then(|port| port.parse().ok()),
+            sys_root: sys.to_path_buf(),
+        })
+    }
+
+    /// The devices (LUNs) of the session.
+    pub fn devices(&self) -> Vec<ScsiDevice> {
+        devices_in(&self.sys_root)
+            .into_iter()
+            .filter(|device| Some(device.hctl.host) == self.host)
+            .collect()
+    }
+
+    /// Scan the session for new LUNs.
+    pub fn rescan(&self) -> Result<(), DevInfoError> {
+        let host = self.host.ok_or_else(|| DevInfoError::NotFound {
+            path: format!("SCSI host of iSCSI session{}", self.id),
+        })?;
+        rescan_host_in(&self.sys_root, host)
+    }
+}
+
+/// All iSCSI sessions.
+pub fn sessions() -> Vec<IscsiSession> {
+    sessions_in(Path::new(SYS))
+}
+
+fn sessions_in(sys: &Path) -> Vec<IscsiSession> {
+    let mut sessions = read_names(&sys.join("class/iscsi_session"))
+        .iter()
+        .filter_map(|name| IscsiSession::from_sys(sys, name.strip_prefix("session")?.parse().ok()?))
+        .collect::<Vec<_>>();
+    sessions.sort_by_key(|session| session.id);
+    sessions
+}
+
+/// A LUN of an iSCSI target, as given by a URI of the form
+/// iscsi://address[:port]/target_name[/lun]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IscsiTarget {
+    /// the address of the portal, which has to be the same address the
+    /// session was logged in to, i.e an IP address and not a host name.
+    /// IPv6 addresses are without brackets
+    pub address: Option<String>,
+    pub port: u16,
+    /// the IQN of the target
+    pub target_name: String,
+    pub lun: u64,
+    /// the UUID of the volume, i.e from the target name
+    pub uuid: Option<Uuid>,
+}
+
+impl TryFrom<&Url> for IscsiTarget {
+    type Error = DevInfoError;
+
+    fn try_from(url: &Url) -> Result<Self, Self::Error> {
+        let uri = url.as_str();
+        let invalid = |expected: &str| InvalidPath { uri, expected }.build();
+        let mut segments = url.path_segments().ok_or_else(|| invalid("target name"))?;
+
+        let target_name = segments
+            .next()
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| invalid("target name"))?
+            .to_string();
+        let lun = match segments.next().filter(|lun| !lun.is_empty()) {
+            Some(lun) => lun.parse().map_err(|_| invalid("LUN"))?,
+            None => 0,
+        };
+        let uuid = match url.query_pairs().find(|(key, _)| key == "uuid") {
+            Some((_, uuid)) => Some(Uuid::parse_str(&uuid).context(InvalidUuid { uri })?),
+            None => target_name
+                .rsplit_once(':')
+                .and_then(|(_, uuid)| Uuid::parse_str(uuid).ok()),
+        };
+
+        Ok(Self {
+            // sysfs has IPv6 addresses without the brackets of the URI
+            address: url.host().map(|host| match host {
+                Host::Ipv6(address) => address.to_string(),
+                host => host.to_string(),
+            }),
+            port: url.port().unwrap_or(ISCSI_PORT),
+            target_name,
+            lun,
+            uuid,
+        })
+    }
+}
+
+impl TryFrom<&str> for IscsiTarget {
+    type Error = DevInfoError;
+
+    fn try_from(uri: &str) -> Result<Self, Self::Error> {
+        Self::try_from(&Url::parse(uri).context(InvalidUri { uri })?)
+    }
+}
+
+impl Display for IscsiTarget {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> std::fmt::Result {
+        let address = self.address.as_deref().unwrap_or_default();
+        let address = match address.parse() {
+            Ok(IpAddr::V6(_)) => format!("[{address}]"),
+            _ => address.to_string(),
+        };
+        write!(
+            fmt,
+            "iscsi://{}:{}/{}/{}",
+            address, self.port, self.target_name, self.lun
+        )
+    }
+}
+
+impl IscsiTarget {
+    /// Whether the session is logged in to the target, through the portal
+    /// when the target has an address.
+    pub fn matches(&self, session: &IscsiSession) -> bool {
+        // compare IP addresses rather than their notation
+        let same_address = |address: &str| match session.address.as_deref() {
+            Some(other) => match (address.parse::<IpAddr>(), other.parse::<IpAddr>()) {
+                (Ok(address), Ok(other)) => address == other,
+                _ => address == other,
+            },
+            None => false,
+        };
+        session.target_name == self.target_name
+            && self
+                .address
+                .iter()
+                .all(|address| same_address(address) && session.port == Some(self.port))
+    }
+
+    /// The sessions to the target.
+    pub fn sessions(&self) -> Vec<IscsiSession> {
+        self.sessions_in(Path::new(SYS))
+    }
+
+    fn sessions_in(&self, sys: &Path) -> Vec<IscsiSession> {
+        sessions_in(sys)
+            .into_iter()
+            .filter(|session| self.matches(session))
+            .collect()
+    }
+
+    /// The device node of the LUN. When there are sessions through several
+    /// portals the LUN has a device for every session, which is ambiguous as
+    /// the multipath device on top of them should be used instead.
+    pub fn lookup(&self) -> Result<PathBuf, DevInfoError> {
+        self.lookup_in(Path::new(SYS))
+    }
+
+    fn lookup_in(&self, sys: &Path) -> Result<PathBuf, DevInfoError> {
+        let mut devnodes = self
+            .sessions_in(sys)
+            .iter()
+            .flat_map(IscsiSession::devices)
+            .filter(|device| device.hctl.lun == self.lun)
+            .filter_map(|device| device.devnode())
+            .collect::<Vec<_>>();
+        match devnodes.len() {
+            0 => Err(DevInfoError::NotFound {
+                path: self.to_string(),
+            }),
+            1 => Ok(devnodes.remove(0)),
+            _ => Err(DevInfoError::Ambiguous {
+                value: self.to_string(),
+                devices: devnodes.iter().map(|d| d.display().to_string()).collect(),
+            }),
+        }
+    }
+}
+
+#[test]
+fn iscsi_session_devices() {
+    use std::os::unix::fs::symlink;
+
+    let sys = std::env::temp_dir().join(format!("devinfo_scsi_sysfs_{}", std::process::id()));
+    let iqn = "iqn.2019-05.io.openebs:00000000-76b6-4fcf-864d-1027d4038756";
+    let _ = fs::remove_dir_all(&sys);
+    let write = |path: &str, value: &str| {
+        let path = sys.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, format!("{value}\n")).unwrap();
+    };
+    let link = |path: &str, target: &str| {
+        fs::create_dir_all(sys.join(target)).unwrap();
+        symlink(sys.join(target), sys.join(path)).unwrap();
+    };
+    write("class/iscsi_session/session1/targetname", iqn);
+    write("class/iscsi_session/session1/state", "LOGGED_IN");
+    link(
+        "class/iscsi_session/session1/device",
+        "devices/platform/host2/session1",
+    );
+    write(
+        "class/iscsi_connection/connection1:0/persistent_address",
+        "10.1.0.4",
+    );
+    write(
+        "class/iscsi_connection/connection1:0/persistent_port",
+        "3260",
+    );
+    write("class/scsi_host/host2/scan", "");
+    write("class/iscsi_session/session2/targetname", iqn);
+    link(
+        "class/iscsi_session/session2/device",
+        "devices/platform/host4/session2",
+    );
+    write(
+        "class/iscsi_connection/connection2:0/persistent_address",
+        "fd00:0:0:0:0:0:0:1",
+    );
+    write(
+        "class/iscsi_connection/connection2:0/persistent_port",
+        "3260",
+    );
+    for (hctl, block) in [("2:0:0:0", "sdb"), ("2:0:0:1", "sdc"), ("3:0:0:0", "sda")] {
+        let device = format!("class/scsi_device/{hctl}/device");
+        write(&format!("{device}/vendor"), "OpenEBS");
+        write(&format!("{device}/state"), "running");
+        write(&format!("{device}/delete"), "");
+        fs::create_dir_all(sys.join(format!("{device}/block/{block}"))).unwrap();
+    }
+
+    let sessions = sessions_in(&sys);
+    assert_eq!(sessions.len(), 2);
+    assert_eq!(sessions[0].host, Some(2));
+    assert_eq!(sessions[0].address.as_deref(), Some("10.1.0.4"));
+    let devices = sessions[0].devices();
+    let hctls = devices
+        .iter()
+        .map(|d| d.hctl.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(hctls, ["2:0:0:0", "2:0:0:1"]);
+    assert_eq!(devices[1].devnode(), Some(PathBuf::from("/dev/sdc")));
+
+    let target = IscsiTarget::try_from(format!("iscsi://10.1.0.4/{iqn}/1").as_str()).unwrap();
+    assert_eq!(target.port, ISCSI_PORT);
+    assert_eq!(
+        target.uuid.unwrap().to_string(),
+        "00000000-76b6-4fcf-864d-1027d4038756"
+    );
+    assert_eq!(target.lookup_in(&sys).unwrap(), Path::new("/dev/sdc"));
+    let target = IscsiTarget::try_from(format!("iscsi://[fd00::1]/{iqn}").as_str()).unwrap();
+    assert_eq!(target.address.as_deref(), Some("fd00::1"));
+    let ids = target
+        .sessions_in(&sys)
+        .iter()
+        .map(|s| s.id)
+        .collect::<Vec<_>>();
+    assert_eq!(ids, [2]);
+    assert!(target.to_string().starts_with("iscsi://[fd00::1]:3260/"));
+    let target = IscsiTarget::try_from(format!("iscsi://10.1.0.5/{iqn}").as_str()).unwrap();
+    assert!(matches!(target.lookup_in(&sys), Err(e) if e.is_transient()));
+    assert!(matches!(
+        IscsiTarget::try_from(format!("iscsi://10.1.0.4/{iqn}/x").as_str()),
+        Err(DevInfoError::InvalidPath { .. })
+    ));
+
+    sessions[0].rescan().unwrap();
+    let scan = fs::read_to_string(sys.join("class/scsi_host/host2/scan")).unwrap();
+    assert_eq!(scan, "- - -");
+    devices[0].delete().unwrap();
+    let delete = fs::read_to_string(sys.join("class/scsi_device/2:0:0:0/device/delete")).unwrap();
+    assert_eq!(delete, "1");
+    assert!("2:0:0".parse::<Hctl>().is_err());
+    let _ = fs::remove_dir_all(&sys);
+}
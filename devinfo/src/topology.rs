@@ -0,0 +1,420 @@
+//! Walk the holders and slaves of block devices in sysfs, to find the stack
+//! of devices a block device is part of. Partitions are placed above their
+//! disk, and the per path devices of an NVMe multipath namespace below its
+//! namespace head.
+
+use crate::DevInfoError;
+use nix::sys::stat::{major, minor};
+use std::{
+    collections::VecDeque,
+    fs,
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+const SYS_CLASS_BLOCK: &str = "/sys/class/block";
+
+/// The kind of a block device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeviceKind {
+    Disk,
+    Partition,
+    Loop,
+    /// an LVM logical volume
+    Lvm,
+    /// a dm-crypt (LUKS) device
+    Crypt,
+    /// a dm-multipath device
+    Multipath,
+    /// a device-mapper device of any other kind
+    DeviceMapper,
+    /// an md (software RAID) device
+    Md,
+    /// the namespace head of an NVMe multipath namespace
+    NvmeHead,
+    /// a path to an NVMe multipath namespace, which has no device node
+    NvmePath,
+}
+
+/// A block device in the stack.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BlockNode {
+    /// kernel name, i.e sda1 or dm-0
+    pub name: String,
+    pub major: u32,
+    pub minor: u32,
+    /// size in bytes
+    pub size: u64,
+    pub kind: DeviceKind,
+    /// the device-mapper name, i.e vg0-lv0
+    pub dm_name: Option<String>,
+}
+
+fn io_error(path: &Path, source: std::io::Error) -> DevInfoError {
+    match source.kind() {
+        std::io::ErrorKind::NotFound => DevInfoError::NotFound {
+            path: path.display().to_string(),
+        },
+        _ => DevInfoError::Io { source },
+    }
+}
+
+fn read_attr(path: &Path) -> Result<String, DevInfoError> {
+    fs::read_to_string(path)
+        .map(|value| value.trim().to_string())
+        .map_err(|source| io_error(path, source))
+}
+
+/// The names of the entries in the sysfs directory, which is empty when it
+/// does not exist.
+fn read_names(path: &Path) -> Vec<String> {
+    let mut names = fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+/// Whether the name is that of a per path device, i.e nvme0c1n1.
+pub(crate) fn is_nvme_path(name: &str) -> bool {
+    let parse = || {
+        let (controller, rest) = name.strip_prefix("nvme")?.split_once('c')?;
+        let (path, namespace) = rest.split_once('n')?;
+        Some(
+            [controller, path, namespace]
+                .iter()
+                .all(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit())),
+        )
+    };
+    parse().unwrap_or(false)
+}
+
+impl BlockNode {
+    /// Look up the block device by its kernel name (sda), device node
+    /// (/dev/sda) or any symlink to it (/dev/mapper/vg0-lv0).
+    pub fn new<P: AsRef<Path>>(device: P) -> Result<Self, DevInfoError> {
+        let device = device.as_ref();
+        if device.components().count() > 1 {
+            let metadata = fs::metadata(device).map_err(|source| io_error(device, source))?;
+            if !metadata.file_type().is_block_device() {
+                return Err(DevInfoError::NotSupported {
+                    value: format!("{} is not a block device", device.display()),
+                });
+            }
+            let rdev = metadata.rdev();
+            let sys = PathBuf::from(format!("/sys/dev/block/{}:{}", major(rdev), minor(rdev)));
+            let name = fs::read_link(&sys).map_err(|source| io_error(&sys, source))?;
+            return Self::from_name(&name.file_name().unwrap_or_default().to_string_lossy());
+        }
+        Self::from_name(&device.to_string_lossy())
+    }
+
+    fn from_name(name: &str) -> Result<Self, DevInfoError> {
+        let sys = Path::new(SYS_CLASS_BLOCK).join(name);
+        let dev = read_attr(&sys.join("dev"))?;
+        let (major, minor) = dev
+            .split_once(':')
+            .and_then(|(major, minor)| Some((major.parse().ok()?, minor.parse().ok()?)))
+            .ok_or_else(|| DevInfoError::ParseError { value: dev.clone() })?;
+        let size = read_attr(&sys.join("size"))?;
+        let size = size
+            .parse::<u64>()
+            .map_err(|_| DevInfoError::ParseError { value: size })?;
+
+        let dm_name = read_attr(&sys.join("dm/name")).ok();
+        let kind = if dm_name.is_some() {
+            let uuid = read_attr(&sys.join("dm/uuid")).unwrap_or_default();
+            match uuid.split_once('-').map(|(prefix, _)| prefix) {
+                Some("LVM") => DeviceKind::Lvm,
+                Some("CRYPT") => DeviceKind::Crypt,
+                Some("mpath") => DeviceKind::Multipath,
+                _ => DeviceKind::DeviceMapper,
+            }
+        } else if sys.join("partition").exists() {
+            DeviceKind::Partition
+        } else if sys.join("md").exists() {
+            DeviceKind::Md
+        } else if sys.join("multipath").exists() {
+            DeviceKind::NvmeHead
+        } else if is_nvme_path(name) {
+            DeviceKind::NvmePath
+        } else if name.starts_with("loop") {
+            DeviceKind::Loop
+        } else {
+            DeviceKind::Disk
+        };
+
+        Ok(Self {
+            name: name.to_string(),
+            major,
+            minor,
+            size: size * 512,
+            kind,
+            dm_name,
+        })
+    }
+
+    fn sys_path(&self) -> PathBuf {
+        Path::new(SYS_CLASS_BLOCK).join(&self.name)
+    }
+
+    /// The device node, None for NVMe path devices which are hidden.
+    pub fn devnode(&self) -> Option<PathBuf> {
+        match self.kind {
+            DeviceKind::NvmePath => None,
+            _ => Some(Path::new("/dev").join(&self.name)),
+        }
+    }
+
+    fn nodes(names: Vec<String>) -> Result<Vec<Self>, DevInfoError> {
+        names.iter().map(|name| Self::from_name(name)).collect()
+    }
+
+    /// The devices built directly on top of this one: its holders, the
+    /// partitions of a disk and the namespace head of an NVMe path.
+    pub fn upper(&self) -> Result<Vec<Self>, DevInfoError> {
+        let sys = self.sys_path();
+        let mut names = read_names(&sys.join("holders"));
+        if self.kind != DeviceKind::Partition {
+            let canonical = sys.canonicalize().unwrap_or(sys);
+            names.extend(
+                read_names(&canonical)
+                    .into_iter()
+                    .filter(|name| canonical.join(name).join("partition").exists()),
+            );
+        }
+        if self.kind == DeviceKind::NvmePath {
+            names.extend(
+                read_names(Path::new(SYS_CLASS_BLOCK))
+                    .into_iter()
+                    .filter(|head| {
+                        Path::new(SYS_CLASS_BLOCK)
+                            .join(head)
+                            .join("multipath")
+                            .join(&self.name)
+                            .exists()
+                    }),
+            );
+        }
+        Self::nodes(names)
+    }
+
+    /// The devices this one is built directly on top of: its slaves, the
+    /// disk of a partition and the paths of an NVMe namespace head.
+    pub fn lower(&self) -> Result<Vec<Self>, DevInfoError> {
+        let sys = self.sys_path();
+        let mut names = read_names(&sys.join("slaves"));
+        match self.kind {
+            DeviceKind::Partition => {
+                let canonical = sys
+                    .canonicalize()
+                    .map_err(|source| io_error(&sys, source))?;
+                if let Some(disk) = canonical.parent().and_then(Path::file_name) {
+                    names.push(disk.to_string_lossy().to_string());
+                }
+            }
+            DeviceKind::NvmeHead => names.extend(read_names(&sys.join("multipath"))),
+            _ => {}
+        }
+        Self::nodes(names)
+    }
+}
+
+/// The stack of block devices above and below a device.
+#[derive(Debug, Clone)]
+pub struct Topology {
+    nodes: Vec<BlockNode>,
+    /// pairs of (lower, upper) indexes into the nodes
+    edges: Vec<(usize, usize)>,
+}
+
+impl Topology {
+    /// Build the stack of the block device, see `BlockNode::new`.
+    pub fn new<P: AsRef<Path>>(device: P) -> Result<Self, DevInfoError> {
+        let mut topology = Self {
+            nodes: vec![BlockNode::new(device)?],
+            edges: Vec::new(),
+        };
+        // only walk up from the devices above, and down from the devices
+        // below, so that i.e the other partitions of the disk are left out
+        for up in [true, false] {
+            let mut queue = VecDeque::from([0]);
+            while let Some(index) = queue.pop_front() {
+                let node = &topology.nodes[index];
+                let next = if up { node.upper()? } else { node.lower()? };
+                for node in next {
+                    let (other, added) = topology.insert(node);
+                    let edge = if up { (index, other) } else { (other, index) };
+                    if !topology.edges.contains(&edge) {
+                        topology.edges.push(edge);
+                    }
+                    if added {
+                        queue.push_back(other);
+                    }
+                }
+            }
+        }
+        Ok(topology)
+    }
+
+    fn insert(&mut self, node: BlockNode) -> (usize, bool) {
+        match self.nodes.iter().position(|n| n.name == node.name) {
+            Some(index) => (index, false),
+            None => {
+                self.nodes.push(node);
+                (self.nodes.len() - 1, true)
+            }
+        }
+    }
+
+    /// The device the topology was built for.
+    pub fn device(&self) -> &BlockNode {
+        &self.nodes[0]
+    }
+
+    /// All devices in the stack, starting with the device itself.
+    pub fn nodes(&self) -> &[BlockNode] {
+        &self.nodes
+    }
+
+    fn index(&self, node: &BlockNode) -> Option<usize> {
+        self.nodes.iter().position(|n| n.name == node.name)
+    }
+
+    /// The devices in the stack built directly on top of the node.
+    pub fn upper(&self, node: &BlockNode) -> Vec<&BlockNode> {
+        let index = self.index(node);
+        self.edges
+            .iter()
+            .filter(|(lower, _)| Some(*lower) == index)
+            .map(|(_, upper)| &self.nodes[*upper])
+            .collect()
+    }
+
+    /// The devices in the stack the node is built directly on top of.
+    pub fn lower(&self, node: &BlockNode) -> Vec<&BlockNode> {
+        let index = self.index(node);
+        self.edges
+            .iter()
+            .filter(|(_, upper)| Some(*upper) == index)
+            .map(|(lower, _)| &self.nodes[*lower])
+            .collect()
+    }
+
+    /// All devices built on top of the device, i.e its partitions and the
+    /// LVM volumes or md arrays using it.
+    pub fn users(&self) -> Vec<&BlockNode> {
+        self.walk(true)
+    }
+
+    /// The devices at the bottom of the stack, i.e the disks of an LVM volume
+    /// or the paths of an NVMe namespace. Empty when the device is one.
+    pub fn backing_devices(&self) -> Vec<&BlockNode> {
+        self.walk(false)
+            .into_iter()
+            .filter(|node| self.lower(node).is_empty())
+            .collect()
+    }
+
+    /// Whether the device, or a partition of it, is used by LVM, md,
+    /// multipath or any other device-mapper device.
+    pub fn is_in_use(&self) -> bool {
+        self.users()
+            .iter()
+            .any(|node| node.kind != DeviceKind::Partition)
+    }
+
+    fn walk(&self, up: bool) -> Vec<&BlockNode> {
+        let mut found = Vec::new();
+        let mut queue = VecDeque::from([self.device()]);
+        while let Some(node) = queue.pop_front() {
+            let next = if up {
+                self.upper(node)
+            } else {
+                self.lower(node)
+            };
+            for node in next {
+                if !found.contains(&node) {
+                    found.push(node);
+                    queue.push_back(node);
+                }
+            }
+        }
+        found
+    }
+}
+
+#[test]
+fn topology_of_loop_device() {
+    use crate::{
+        loop_device::{attach_for_test, LoopOptions},
+        parttable::{PartitionSpec, PartitionTable, TableKind},
+    };
+    use std::process::Command;
+
+    assert!(is_nvme_path("nvme0c1n1"));
+    assert!(!is_nvme_path("nvme0n1") && !is_nvme_path("nvme0c1n"));
+    assert!(matches!(
+        Topology::new("/dev/null"),
+        Err(DevInfoError::NotSupported { .. })
+    ));
+
+    let image = std::env::temp_dir().join("devinfo_topology.img");
+    fs::File::create(&image)
+        .and_then(|f| f.set_len(32 * 1024 * 1024))
+        .unwrap();
+    let mut table = PartitionTable::create(&image, TableKind::Gpt).unwrap();
+    for size in [Some(8 * 2048), None] {
+        table
+            .add_partition(PartitionSpec {
+                size,
+                ..Default::default()
+            })
+            .unwrap();
+    }
+    table.write().unwrap();
+
+    let options = LoopOptions {
+        partscan: true,
+        ..Default::default()
+    };
+    let loop_device = match attach_for_test(&image, &options) {
+        Some(device) => device,
+        None => return,
+    };
+    let device = loop_device.path().to_path_buf();
+    let name = device.file_name().unwrap().to_string_lossy().to_string();
+    // not every kernel scans the table of a loop device, add the partitions
+    // which are missing
+    let _ = Command::new("partx").arg("-a").arg(&device).output();
+
+    let topology = Topology::new(&device).unwrap();
+    let disk = topology.device();
+    assert_eq!((disk.kind, disk.size), (DeviceKind::Loop, 32 * 1024 * 1024));
+    assert_eq!(disk.major, 7);
+    let users: Vec<_> = topology.users().iter().map(|n| n.name.clone()).collect();
+    assert_eq!(users, vec![format!("{name}p1"), format!("{name}p2")]);
+    assert!(topology.backing_devices().is_empty());
+    assert!(!topology.is_in_use());
+
+    let topology = Topology::new(format!("{name}p1")).unwrap();
+    let partition = topology.device();
+    assert_eq!(
+        (partition.kind, partition.size),
+        (DeviceKind::Partition, 8 * 1024 * 1024)
+    );
+    assert_eq!(topology.nodes().len(), 2);
+    assert_eq!(topology.lower(partition)[0].name, name);
+    assert_eq!(topology.upper(&topology.nodes()[1])[0], partition);
+    assert_eq!(
+        topology.backing_devices()[0].devnode(),
+        Some(device.clone())
+    );
+
+    drop(loop_device);
+    let _ = fs::remove_file(image);
+}
@@ -26,6 +26,15 @@ pub enum NvmeError {
     NqnNotFound { nqn: String },
     #[snafu(display("No nvmf subsystems found"))]
     NoSubsystems,
+    #[snafu(display(
+        "Timed out after {:?} waiting for the namespaces of nqn: {}",
+        timeout,
+        nqn
+    ))]
+    WaitTimeout {
+        nqn: String,
+        timeout: std::time::Duration,
+    },
     #[snafu(display(
         "Nvmf subsystem with nqn: {}, host: {}, port: {} not found",
         nqn,
@@ -39,6 +48,31 @@ pub enum NvmeError {
     },
     #[snafu(display("Connect in progress"))]
     ConnectInProgress,
+    #[snafu(display("NVMe target unreachable: {}, args: {}", source, args))]
+    TargetUnreachable {
+        source: std::io::Error,
+        args: String,
+    },
+    #[snafu(display("Host NQN rejected by the target: {}, args: {}", source, args))]
+    InvalidHostNqn {
+        source: std::io::Error,
+        args: String,
+    },
+    #[snafu(display("NVMe authentication failed: {}, args: {}", source, args))]
+    AuthFailed {
+        source: std::io::Error,
+        args: String,
+    },
+    #[snafu(display("Subsystem NQN not found on the target: {}, args: {}", source, args))]
+    ConnectNqnNotFound {
+        source: std::io::Error,
+        args: String,
+    },
+    #[snafu(display("Too many controllers: {}, args: {}", source, args))]
+    TooManyControllers {
+        source: std::io::Error,
+        args: String,
+    },
     #[snafu(display("NVMe connect failed: {}, {}", filename, source))]
     ConnectFailed {
         source: std::io::Error,
@@ -46,6 +80,8 @@ pub enum NvmeError {
     },
     #[snafu(display("IO error during NVMe discovery"))]
     NvmeDiscoveryFailed { source: nix::Error },
+    #[snafu(display("Invalid discovery log page: {}", text))]
+    InvalidDiscoveryLog { text: String },
     #[snafu(display("Controller with nqn: {} not found", text))]
     CtlNotFound { text: String },
     #[snafu(display("Invalid path {}: {}", path, source))]
@@ -66,6 +102,51 @@ pub enum NvmeError {
     InvalidParam { text: String },
 }
 
+impl NvmeError {
+    /// Classify a connect failure based on the errno returned by the kernel
+    /// when writing the connect string to /dev/nvme-fabrics. The args must
+    /// already be stripped of any secrets.
+    pub(crate) fn from_connect(source: std::io::Error, args: String) -> Self {
+        match source.raw_os_error() {
+            Some(libc::EALREADY | libc::EEXIST) => NvmeError::ConnectInProgress,
+            Some(
+                libc::ECONNREFUSED
+                | libc::ECONNRESET
+                | libc::EHOSTUNREACH
+                | libc::ENETUNREACH
+                | libc::EADDRNOTAVAIL
+                | libc::ETIMEDOUT,
+            ) => NvmeError::TargetUnreachable { source, args },
+            Some(libc::EPERM | libc::EACCES) => NvmeError::InvalidHostNqn { source, args },
+            Some(libc::EKEYREJECTED | libc::EKEYEXPIRED | libc::ENOKEY) => {
+                NvmeError::AuthFailed { source, args }
+            }
+            Some(libc::ENODEV | libc::ENOENT) => NvmeError::ConnectNqnNotFound { source, args },
+            Some(libc::EBUSY | libc::EUSERS) => NvmeError::TooManyControllers { source, args },
+            _ => NvmeError::IoFailed { source, args },
+        }
+    }
+
+    /// Returns true if the failed operation may succeed when tried again,
+    /// for example because the target is temporarily unreachable.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            NvmeError::ConnectInProgress
+            | NvmeError::TargetUnreachable { .. }
+            | NvmeError::TooManyControllers { .. } => true,
+            NvmeError::IoFailed { source, .. }
+            | NvmeError::FileIoFailed { source, .. }
+            | NvmeError::ConnectFailed { source, .. } => matches!(
+                source.kind(),
+                std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::WouldBlock
+                    | std::io::ErrorKind::TimedOut
+            ),
+            _ => false,
+        }
+    }
+}
+
 impl From<std::io::Error> for NvmeError {
     fn from(source: std::io::Error) -> NvmeError {
         NvmeError::IoFailed {
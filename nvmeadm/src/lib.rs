@@ -33,6 +33,8 @@ use std::{fs, path::Path, str::FromStr};
 pub mod error;
 pub mod nvme_namespaces;
 mod nvme_page;
+pub mod nvme_stats;
+pub mod nvmet;
 pub mod nvmf_discovery;
 pub mod nvmf_subsystem;
 
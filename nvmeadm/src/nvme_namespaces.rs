@@ -8,7 +8,6 @@ use std::{os::unix::fs::FileTypeExt, path::Path};
 /// nvme device, for the post part is a subsystem + nsid.
 
 #[derive(Debug, Default)]
-#[allow(dead_code)]
 pub struct NvmeDevice {
     /// device path of the device
     pub path: String,
@@ -16,7 +15,7 @@ pub struct NvmeDevice {
     model: String,
     /// serial number of the device
     serial: String,
-    /// the size in bytes
+    /// the size in 512 byte sectors
     size: u64,
     /// the UUID for the device
     uuid: String,
@@ -57,6 +56,41 @@ impl NvmeDevice {
             nsid: parse_value(source, "nsid")?,
         })
     }
+
+    /// The device model defined by the manufacturer.
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// Serial number of the device.
+    pub fn serial(&self) -> &str {
+        &self.serial
+    }
+
+    /// The size of the namespace in bytes.
+    pub fn size(&self) -> u64 {
+        self.size * 512
+    }
+
+    /// The UUID of the namespace, N/A if the device does not report one.
+    pub fn uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    /// The world wide name of the device.
+    pub fn wwid(&self) -> &str {
+        &self.wwid
+    }
+
+    /// The namespace id.
+    pub fn nsid(&self) -> u64 {
+        self.nsid
+    }
+
+    /// Firmware revision.
+    pub fn fw_rev(&self) -> &str {
+        &self.fw_rev
+    }
 }
 /// The DeviceList of all NVMe devices found that provide all properties as
 /// defined in the [struct.NvmeDevice]
@@ -89,4 +123,13 @@ impl NvmeDeviceList {
         }
         list
     }
+
+    /// All devices which belong to the subsystem with the given nqn. Devices
+    /// which do not (yet) provide all properties are skipped.
+    pub fn for_nqn(nqn: &str) -> Vec<NvmeDevice> {
+        Self::new()
+            .filter_map(Result::ok)
+            .filter(|d| d.subsysnqn == nqn)
+            .collect()
+    }
 }
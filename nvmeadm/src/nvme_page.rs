@@ -1,56 +1,143 @@
-use crate::nvme_page;
-use libc::{c_char, c_uchar};
-use std::fmt;
+use crate::error::NvmeError;
+use libc::c_uchar;
 
-#[repr(C)]
-#[derive(Default)]
-pub struct ZeroSizeArray<T>(::core::marker::PhantomData<T>, [T; 0]);
+/// Size of the discovery log page header, the first entry starts right after
+/// it. See NVMe-oF 1.1 Figure 41.
+pub const DISC_LOG_HDR_LEN: usize = 1024;
+/// Size of a single discovery log page entry. See NVMe-oF 1.1 Figure 42.
+pub const DISC_LOG_ENTRY_LEN: usize = 1024;
+/// Upper bound on the number of records we are willing to fetch, a target
+/// reporting more than this is considered to be broken.
+pub const DISC_LOG_MAX_ENTRIES: u64 = 4096;
+
+/// Bounds checked little endian accessors over a byte buffer as returned by
+/// the target. None of the accessors can read past the end of the buffer.
+#[derive(Debug, Clone, Copy)]
+struct LeBytes<'a>(&'a [u8]);
+
+impl<'a> LeBytes<'a> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&'a [u8], NvmeError> {
+        offset
+            .checked_add(len)
+            .and_then(|end| self.0.get(offset .. end))
+            .ok_or_else(|| NvmeError::InvalidDiscoveryLog {
+                text: format!(
+                    "{} bytes at offset {} exceed the buffer length {}",
+                    len,
+                    offset,
+                    self.0.len()
+                ),
+            })
+    }
 
-impl<T> ZeroSizeArray<T> {
-    pub unsafe fn as_ptr(&self) -> *const T {
-        self as *const nvme_page::ZeroSizeArray<T> as *const T
+    fn u8(&self, offset: usize) -> Result<u8, NvmeError> {
+        Ok(self.bytes(offset, 1)?[0])
     }
-    pub unsafe fn as_slice(&self, len: usize) -> &[T] {
-        ::core::slice::from_raw_parts(self.as_ptr(), len)
+
+    fn u16(&self, offset: usize) -> Result<u16, NvmeError> {
+        let mut v = [0u8; 2];
+        v.copy_from_slice(self.bytes(offset, 2)?);
+        Ok(u16::from_le_bytes(v))
     }
-}
 
-impl<T> ::core::fmt::Debug for ZeroSizeArray<T> {
-    fn fmt(&self, fmt: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {
-        fmt.write_str("ZeroSizeArray")
+    fn u64(&self, offset: usize) -> Result<u64, NvmeError> {
+        let mut v = [0u8; 8];
+        v.copy_from_slice(self.bytes(offset, 8)?);
+        Ok(u64::from_le_bytes(v))
+    }
+
+    /// Fixed width ASCII fields are either NUL terminated or padded with
+    /// spaces, we handle both and never look beyond the field width.
+    fn string(&self, offset: usize, len: usize) -> Result<String, NvmeError> {
+        let field = self.bytes(offset, len)?;
+        let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+        Ok(String::from_utf8_lossy(&field[.. end]).trim().to_string())
     }
 }
 
-#[repr(C)]
+/// The discovery log page header.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct NvmfDiscRspPageHdr {
     pub genctr: u64,
     pub numrec: u64,
     pub recfmt: u16,
-    pub resv14: [c_uchar; 1006usize],
-    pub entries: ZeroSizeArray<NvmfDiscRspPageEntry>,
 }
 
-impl fmt::Debug for NvmfDiscRspPageHdr {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("NvmfDiscRspPageHdr")
-            .field("genctr", &self.genctr)
-            .field("numrec", &self.numrec)
-            .field("recfmt", &self.recfmt)
-            .finish()
+impl NvmfDiscRspPageHdr {
+    /// Decode the header from the start of the given buffer.
+    pub fn decode(buf: &[u8]) -> Result<Self, NvmeError> {
+        let b = LeBytes(LeBytes(buf).bytes(0, DISC_LOG_HDR_LEN)?);
+        Ok(Self {
+            genctr: b.u64(0)?,
+            numrec: b.u64(8)?,
+            recfmt: b.u16(16)?,
+        })
     }
 }
 
-impl Default for NvmfDiscRspPageHdr {
-    fn default() -> Self {
-        Self::new()
+/// A single discovery log page entry, the transport specific address
+/// subtype (tsas) is not used and therefore not decoded.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct NvmfDiscRspPageEntry {
+    pub trtype: u8,
+    pub adrfam: u8,
+    pub subtype: u8,
+    pub treq: u8, // 0:2 secure channel, reserved
+    pub portid: u16,
+    pub cntlid: u16,
+    pub asqsz: u16, // admin queue size
+    pub trsvcid: String,
+    pub subnqn: String,
+    pub traddr: String,
+}
+
+impl NvmfDiscRspPageEntry {
+    /// Decode a single entry from the start of the given buffer.
+    pub fn decode(buf: &[u8]) -> Result<Self, NvmeError> {
+        let b = LeBytes(LeBytes(buf).bytes(0, DISC_LOG_ENTRY_LEN)?);
+        Ok(Self {
+            trtype: b.u8(0)?,
+            adrfam: b.u8(1)?,
+            subtype: b.u8(2)?,
+            treq: b.u8(3)?,
+            portid: b.u16(4)?,
+            cntlid: b.u16(6)?,
+            asqsz: b.u16(8)?,
+            trsvcid: b.string(32, 32)?,
+            subnqn: b.string(256, 256)?,
+            traddr: b.string(512, 256)?,
+        })
     }
 }
 
-impl NvmfDiscRspPageHdr {
-    fn new() -> Self {
-        Self {
-            ..unsafe { std::mem::zeroed() }
-        }
+/// A decoded discovery log page.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct NvmfDiscRspPage {
+    pub hdr: NvmfDiscRspPageHdr,
+    pub entries: Vec<NvmfDiscRspPageEntry>,
+}
+
+impl NvmfDiscRspPage {
+    /// Decode the header and the entries which are contained within the
+    /// buffer. The number of records may have grown between fetching the
+    /// header and fetching the page, so we only decode the records which we
+    /// actually received.
+    pub fn decode(buf: &[u8]) -> Result<Self, NvmeError> {
+        let hdr = NvmfDiscRspPageHdr::decode(buf)?;
+        let available = buf.len().saturating_sub(DISC_LOG_HDR_LEN) / DISC_LOG_ENTRY_LEN;
+        let count = usize::try_from(hdr.numrec)
+            .unwrap_or(usize::MAX)
+            .min(available);
+
+        let entries = buf
+            .get(DISC_LOG_HDR_LEN ..)
+            .unwrap_or_default()
+            .chunks_exact(DISC_LOG_ENTRY_LEN)
+            .take(count)
+            .map(NvmfDiscRspPageEntry::decode)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self { hdr, entries })
     }
 }
 
@@ -77,60 +164,68 @@ pub struct NvmeAdminCmd {
     pub result: u32,
 }
 
-#[repr(C)]
-#[derive(Copy, Clone)]
-pub struct NvmfDiscRspPageEntry {
-    pub trtype: c_uchar,
-    pub adrfam: c_uchar,
-    pub subtype: c_uchar,
-    pub treq: c_uchar, // 0:2 secure channel, reserved
-    pub portid: u16,
-    pub cntlid: u16,
-    pub asqsz: u16, // admin queue size
-    pub resv8: [c_uchar; 22usize],
-    pub trsvcid: [c_char; 32usize],
-    pub resv64: [c_uchar; 192usize],
-    pub subnqn: [c_char; 256usize],
-    pub traddr: [c_char; 256usize],
-    pub tsas: NvmfDiscRspPageEntryTsas,
-}
+#[test]
+fn disc_log_page_decode() {
+    let mut buf = vec![0u8; DISC_LOG_HDR_LEN + 2 * DISC_LOG_ENTRY_LEN];
+    buf[0 .. 8].copy_from_slice(&7u64.to_le_bytes());
+    // claim more records than we have room for
+    buf[8 .. 16].copy_from_slice(&3u64.to_le_bytes());
 
-impl Default for NvmfDiscRspPageEntry {
-    fn default() -> Self {
-        Self::new()
-    }
-}
+    let entry = &mut buf[DISC_LOG_HDR_LEN ..];
+    entry[0] = 3;
+    entry[1] = 1;
+    entry[2] = 2;
+    entry[4 .. 6].copy_from_slice(&0x1234u16.to_le_bytes());
+    // space padded, no NUL terminator
+    entry[32 .. 64].copy_from_slice(&[b' '; 32]);
+    entry[32 .. 36].copy_from_slice(b"4420");
+    // NUL terminated with garbage after it
+    entry[256 .. 262].copy_from_slice(b"nqn.a\0");
+    entry[262 .. 512].copy_from_slice(&[b'x'; 250]);
+    // fills the entire field
+    entry[512 .. 768].copy_from_slice(&[b'1'; 256]);
 
-impl NvmfDiscRspPageEntry {
-    fn new() -> Self {
-        Self {
-            ..unsafe { std::mem::zeroed() }
-        }
-    }
-}
+    let page = NvmfDiscRspPage::decode(&buf).unwrap();
+    assert_eq!(page.hdr.genctr, 7);
+    assert_eq!(page.hdr.numrec, 3);
+    assert_eq!(page.entries.len(), 2);
+    let e = &page.entries[0];
+    assert_eq!((e.trtype, e.adrfam, e.subtype, e.portid), (3, 1, 2, 0x1234));
+    assert_eq!(e.trsvcid, "4420");
+    assert_eq!(e.subnqn, "nqn.a");
+    assert_eq!(e.traddr.len(), 256);
 
-#[repr(C)]
-#[derive(Copy, Clone)]
-pub union NvmfDiscRspPageEntryTsas {
-    pub common: [::std::os::raw::c_char; 256usize],
-    pub rdma: nvmf_disc_rsp_page_entry_tsas_rdma,
-    pub tcp: NvmfDiscRspPageEntryTsasTcp,
-    __union_align: [u16; 128usize],
+    assert!(NvmfDiscRspPageHdr::decode(&buf[.. 17]).is_err());
+    assert!(NvmfDiscRspPageEntry::decode(&buf[.. DISC_LOG_ENTRY_LEN - 1]).is_err());
 }
 
-#[repr(C)]
-#[derive(Debug, Copy, Clone)]
-pub struct NvmfDiscRspPageEntryTsasTcp {
-    pub sectype: c_uchar,
-}
+#[test]
+fn disc_log_page_fuzz() {
+    // xorshift, so that failures are reproducible without extra dependencies
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
 
-#[repr(C)]
-#[derive(Copy, Clone)]
-pub struct nvmf_disc_rsp_page_entry_tsas_rdma {
-    pub qptype: c_uchar,
-    pub prtype: c_uchar,
-    pub cms: c_uchar,
-    pub resv3: [c_uchar; 5usize],
-    pub pkey: u16,
-    pub resv10: [c_uchar; 246usize],
+    for _ in 0 .. 2000 {
+        let len =
+            (next() % (DISC_LOG_HDR_LEN as u64 + 4 * DISC_LOG_ENTRY_LEN as u64 + 64)) as usize;
+        let mut buf = (0 .. len).map(|_| next() as u8).collect::<Vec<_>>();
+        if len >= 16 && next() % 2 == 0 {
+            // make the record count plausible some of the time
+            buf[8 .. 16].copy_from_slice(&(next() % 8).to_le_bytes());
+        }
+
+        match NvmfDiscRspPage::decode(&buf) {
+            Ok(page) => {
+                assert!(page.entries.len() as u64 <= page.hdr.numrec);
+                assert!(DISC_LOG_HDR_LEN + page.entries.len() * DISC_LOG_ENTRY_LEN <= len);
+            }
+            Err(_) => assert!(len < DISC_LOG_HDR_LEN),
+        }
+        let _ = NvmfDiscRspPageEntry::decode(&buf);
+    }
 }
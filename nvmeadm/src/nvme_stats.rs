@@ -0,0 +1,330 @@
+use crate::{
+    error::{nvme_error::FileIoFailed, NvmeError},
+    nvme_namespaces::NvmeDevice,
+};
+use glob::glob;
+use snafu::ResultExt;
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// The kernel always reports sectors in the block layer stat file in units of
+/// 512 bytes, regardless of the logical block size of the device.
+const SECTOR_SIZE: u64 = 512;
+
+/// Raw counters of the block layer as found in `/sys/block/<dev>/stat`.
+/// See Documentation/block/stat.rst in the kernel tree for the details.
+/// Older kernels do not report the discard and flush counters, in which case
+/// they are left at zero.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BlockStat {
+    /// number of read I/Os processed
+    pub read_ios: u64,
+    /// number of read I/Os merged with in-queue I/O
+    pub read_merges: u64,
+    /// number of sectors read
+    pub read_sectors: u64,
+    /// total wait time for read requests in milliseconds
+    pub read_ticks: u64,
+    /// number of write I/Os processed
+    pub write_ios: u64,
+    /// number of write I/Os merged with in-queue I/O
+    pub write_merges: u64,
+    /// number of sectors written
+    pub write_sectors: u64,
+    /// total wait time for write requests in milliseconds
+    pub write_ticks: u64,
+    /// number of I/Os currently in flight
+    pub in_flight: u64,
+    /// total time this block device has been active in milliseconds
+    pub io_ticks: u64,
+    /// total wait time for all requests in milliseconds
+    pub time_in_queue: u64,
+    /// number of discard I/Os processed
+    pub discard_ios: u64,
+    /// number of discard I/Os merged with in-queue I/O
+    pub discard_merges: u64,
+    /// number of sectors discarded
+    pub discard_sectors: u64,
+    /// total wait time for discard requests in milliseconds
+    pub discard_ticks: u64,
+    /// number of flush I/Os processed
+    pub flush_ios: u64,
+    /// total wait time for flush requests in milliseconds
+    pub flush_ticks: u64,
+}
+
+impl FromStr for BlockStat {
+    type Err = NvmeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split_whitespace()
+            .map(|v| {
+                v.parse::<u64>().map_err(|e| NvmeError::ValueParseFailed {
+                    path: "stat".to_string(),
+                    contents: s.to_string(),
+                    error: e.to_string(),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // the first 11 fields have been there since the 2.6 days
+        if values.len() < 11 {
+            return Err(NvmeError::ValueParseFailed {
+                path: "stat".to_string(),
+                contents: s.to_string(),
+                error: format!("expected at least 11 fields, found {}", values.len()),
+            });
+        }
+        let field = |i: usize| values.get(i).copied().unwrap_or_default();
+
+        Ok(BlockStat {
+            read_ios: field(0),
+            read_merges: field(1),
+            read_sectors: field(2),
+            read_ticks: field(3),
+            write_ios: field(4),
+            write_merges: field(5),
+            write_sectors: field(6),
+            write_ticks: field(7),
+            in_flight: field(8),
+            io_ticks: field(9),
+            time_in_queue: field(10),
+            discard_ios: field(11),
+            discard_merges: field(12),
+            discard_sectors: field(13),
+            discard_ticks: field(14),
+            flush_ios: field(15),
+            flush_ticks: field(16),
+        })
+    }
+}
+
+/// I/O counters using the same fields as the io-engine `NvmeControllerIoStats`
+/// message, such that host side and target side numbers can be compared.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NvmeControllerIoStats {
+    pub num_read_ops: u64,
+    pub num_write_ops: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub num_unmap_ops: u64,
+    pub bytes_unmapped: u64,
+}
+
+impl From<&BlockStat> for NvmeControllerIoStats {
+    fn from(stat: &BlockStat) -> Self {
+        Self {
+            num_read_ops: stat.read_ios,
+            num_write_ops: stat.write_ios,
+            bytes_read: stat.read_sectors * SECTOR_SIZE,
+            bytes_written: stat.write_sectors * SECTOR_SIZE,
+            num_unmap_ops: stat.discard_ios,
+            bytes_unmapped: stat.discard_sectors * SECTOR_SIZE,
+        }
+    }
+}
+
+/// A single sample of the block layer counters of an NVMe block device.
+#[derive(Debug, Clone)]
+pub struct IoStatsSample {
+    /// kernel name of the device i.e nvme0n1 or nvme0c1n1
+    pub device: String,
+    /// the raw counters
+    pub stat: BlockStat,
+    /// when the counters were read
+    pub timestamp: Instant,
+}
+
+impl IoStatsSample {
+    /// Sample the counters of the given device, which may either be the
+    /// kernel name (nvme0n1) or the device path (/dev/nvme0n1).
+    pub fn sample(device: &str) -> Result<Self, NvmeError> {
+        let device = device.trim_start_matches("/dev/");
+        let filename = format!("/sys/block/{device}/stat");
+        let contents = std::fs::read_to_string(&filename).context(FileIoFailed {
+            filename: &filename,
+        })?;
+
+        Ok(Self {
+            device: device.to_string(),
+            stat: contents.parse()?,
+            timestamp: Instant::now(),
+        })
+    }
+
+    /// Sample the counters of every path of a multipath namespace. The kernel
+    /// links the per-path devices (nvmeXcYnZ) of the ns_head device under its
+    /// multipath directory. When native multipath is disabled this returns
+    /// an empty list.
+    pub fn sample_paths(device: &str) -> Result<Vec<Self>, NvmeError> {
+        let device = device.trim_start_matches("/dev/");
+        let pattern = format!("/sys/block/{device}/multipath/nvme*");
+        let paths = glob(&pattern).map_err(|e| NvmeError::InvalidParam {
+            text: e.to_string(),
+        })?;
+
+        paths
+            .flatten()
+            .filter_map(|p| p.file_name().map(|n| n.to_string_lossy().to_string()))
+            .map(|name| Self::sample(&name))
+            .collect()
+    }
+
+    /// The cumulative counters of this sample.
+    pub fn io_stats(&self) -> NvmeControllerIoStats {
+        NvmeControllerIoStats::from(&self.stat)
+    }
+
+    /// Compute the rates between this sample and a later sample of the same
+    /// device. Counters which went backwards, for example because the device
+    /// was removed and added again, are treated as zero.
+    pub fn rate(&self, later: &IoStatsSample) -> IoStatsRate {
+        let elapsed = later.timestamp.saturating_duration_since(self.timestamp);
+        let (old, new) = (&self.stat, &later.stat);
+        let delta = BlockStat {
+            read_ios: new.read_ios.saturating_sub(old.read_ios),
+            read_merges: new.read_merges.saturating_sub(old.read_merges),
+            read_sectors: new.read_sectors.saturating_sub(old.read_sectors),
+            read_ticks: new.read_ticks.saturating_sub(old.read_ticks),
+            write_ios: new.write_ios.saturating_sub(old.write_ios),
+            write_merges: new.write_merges.saturating_sub(old.write_merges),
+            write_sectors: new.write_sectors.saturating_sub(old.write_sectors),
+            write_ticks: new.write_ticks.saturating_sub(old.write_ticks),
+            in_flight: new.in_flight,
+            io_ticks: new.io_ticks.saturating_sub(old.io_ticks),
+            time_in_queue: new.time_in_queue.saturating_sub(old.time_in_queue),
+            discard_ios: new.discard_ios.saturating_sub(old.discard_ios),
+            discard_merges: new.discard_merges.saturating_sub(old.discard_merges),
+            discard_sectors: new.discard_sectors.saturating_sub(old.discard_sectors),
+            discard_ticks: new.discard_ticks.saturating_sub(old.discard_ticks),
+            flush_ios: new.flush_ios.saturating_sub(old.flush_ios),
+            flush_ticks: new.flush_ticks.saturating_sub(old.flush_ticks),
+        };
+
+        IoStatsRate::new(later.device.clone(), elapsed, &delta)
+    }
+}
+
+impl NvmeDevice {
+    /// Sample the block layer counters of this device.
+    pub fn io_stats_sample(&self) -> Result<IoStatsSample, NvmeError> {
+        IoStatsSample::sample(&self.path)
+    }
+}
+
+/// Rates computed between two samples of the same device.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct IoStatsRate {
+    /// kernel name of the device
+    pub device: String,
+    /// time between the two samples
+    pub interval: Duration,
+    /// the counters which changed during the interval
+    pub stats: NvmeControllerIoStats,
+    /// read operations per second
+    pub read_iops: f64,
+    /// write operations per second
+    pub write_iops: f64,
+    /// unmap operations per second
+    pub unmap_iops: f64,
+    /// bytes read per second
+    pub read_bytes_per_sec: f64,
+    /// bytes written per second
+    pub write_bytes_per_sec: f64,
+    /// bytes unmapped per second
+    pub unmap_bytes_per_sec: f64,
+    /// average latency of a read in milliseconds
+    pub read_latency_ms: f64,
+    /// average latency of a write in milliseconds
+    pub write_latency_ms: f64,
+    /// average number of requests queued during the interval
+    pub avg_queue_depth: f64,
+    /// requests in flight at the time of the last sample
+    pub in_flight: u64,
+    /// percentage of the interval the device was busy
+    pub utilization: f64,
+}
+
+impl IoStatsRate {
+    fn new(device: String, interval: Duration, delta: &BlockStat) -> Self {
+        let secs = interval.as_secs_f64();
+        let millis = secs * 1000.0;
+        let per_sec = |v: u64| if secs > 0.0 { v as f64 / secs } else { 0.0 };
+        let per_op = |ticks: u64, ops: u64| {
+            if ops > 0 {
+                ticks as f64 / ops as f64
+            } else {
+                0.0
+            }
+        };
+        let per_ms = |v: u64| if millis > 0.0 { v as f64 / millis } else { 0.0 };
+        let stats = NvmeControllerIoStats::from(delta);
+
+        Self {
+            device,
+            interval,
+            stats,
+            read_iops: per_sec(stats.num_read_ops),
+            write_iops: per_sec(stats.num_write_ops),
+            unmap_iops: per_sec(stats.num_unmap_ops),
+            read_bytes_per_sec: per_sec(stats.bytes_read),
+            write_bytes_per_sec: per_sec(stats.bytes_written),
+            unmap_bytes_per_sec: per_sec(stats.bytes_unmapped),
+            read_latency_ms: per_op(delta.read_ticks, delta.read_ios),
+            write_latency_ms: per_op(delta.write_ticks, delta.write_ios),
+            avg_queue_depth: per_ms(delta.time_in_queue),
+            in_flight: delta.in_flight,
+            utilization: (per_ms(delta.io_ticks) * 100.0).min(100.0),
+        }
+    }
+}
+
+/// Sample the given device twice, `interval` apart, and return the rates.
+pub fn sample_rate(device: &str, interval: Duration) -> Result<IoStatsRate, NvmeError> {
+    let first = IoStatsSample::sample(device)?;
+    std::thread::sleep(interval);
+    let second = IoStatsSample::sample(device)?;
+    Ok(first.rate(&second))
+}
+
+#[test]
+fn nvme_stat_rate() {
+    let old =
+        "    1000        0    80000      500     2000        0   160000     4000        0     \
+               3000     4500      10        0     2048       20        0        0";
+    let new =
+        "    2000        0   160000     1500     4000        0   320000    10000        2     \
+               4000     9500      20        0     4096       40        0        0";
+    let t0 = Instant::now();
+    let first = IoStatsSample {
+        device: "nvme0n1".into(),
+        stat: old.parse().unwrap(),
+        timestamp: t0,
+    };
+    let second = IoStatsSample {
+        device: "nvme0n1".into(),
+        stat: new.parse().unwrap(),
+        timestamp: t0 + Duration::from_secs(2),
+    };
+    assert_eq!(first.io_stats().bytes_read, 80000 * 512);
+
+    let rate = first.rate(&second);
+    assert_eq!(rate.stats.num_read_ops, 1000);
+    assert_eq!(rate.stats.bytes_written, 160000 * 512);
+    assert_eq!(rate.stats.bytes_unmapped, 2048 * 512);
+    assert_eq!(rate.read_iops, 500.0);
+    assert_eq!(rate.write_iops, 1000.0);
+    assert_eq!(rate.read_latency_ms, 1.0);
+    assert_eq!(rate.write_latency_ms, 3.0);
+    assert_eq!(rate.avg_queue_depth, 2.5);
+    assert_eq!(rate.utilization, 50.0);
+    assert_eq!(rate.in_flight, 2);
+
+    // pre 4.18 kernels do not report discards
+    let stat: BlockStat = "1 2 3 4 5 6 7 8 9 10 11".parse().unwrap();
+    assert_eq!(stat.discard_ios, 0);
+    assert!("1 2 3".parse::<BlockStat>().is_err());
+}
@@ -89,10 +89,7 @@ impl NvmeTarget {
         loop {
             std::thread::sleep(Duration::from_millis(1000));
 
-            all_nvme_devices = NvmeDeviceList::new()
-                .filter_map(Result::ok)
-                .filter(|b| b.subsysnqn == self.subsysnqn)
-                .collect::<Vec<_>>();
+            all_nvme_devices = NvmeDeviceList::for_nqn(&self.subsysnqn);
 
             retries -= 1;
             if retries == 0 || !all_nvme_devices.is_empty() {
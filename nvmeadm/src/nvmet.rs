@@ -0,0 +1,340 @@
+//! Configure the in-kernel NVMe target (nvmet) through configfs. This allows
+//! testing discovery, connect, multipath and ANA flows on any Linux machine
+//! which has the nvmet, nvmet-tcp and/or nvme-loop modules loaded.
+//!
+//! Every object removes itself from configfs when dropped. Ports should be
+//! dropped before the subsystems they export, which happens naturally when
+//! they are declared after the subsystems.
+//!
+//! # Example
+//! ```no_run
+//! use nvmeadm::nvmet::{NvmetPort, NvmetSubsystem, NvmetTransport};
+//!
+//! let mut subsys = NvmetSubsystem::new("nqn.2019-05.io.openebs:test").unwrap();
+//! subsys.add_namespace(1, "/tmp/disk.img").unwrap();
+//!
+//! let mut port = NvmetPort::new(1, NvmetTransport::Tcp, "127.0.0.1", 4420).unwrap();
+//! port.add_subsystem(&subsys).unwrap();
+//! ```
+
+use crate::error::{nvme_error::FileIoFailed, NvmeError};
+use snafu::ResultExt;
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+/// Root of the nvmet configfs hierarchy.
+pub const NVMET_CONFIGFS: &str = "/sys/kernel/config/nvmet";
+/// The ANA group which every port has by default.
+const DEFAULT_ANA_GROUP: u32 = 1;
+
+/// Check whether the nvmet module is loaded and configfs is mounted.
+pub fn is_available() -> bool {
+    Path::new(NVMET_CONFIGFS).is_dir()
+}
+
+fn write_attr(dir: &Path, attr: &str, value: &str) -> Result<(), NvmeError> {
+    let path = dir.join(attr);
+    fs::write(&path, value).context(FileIoFailed {
+        filename: path.display().to_string(),
+    })
+}
+
+fn create_dir(path: &Path) -> Result<(), NvmeError> {
+    fs::create_dir(path).context(FileIoFailed {
+        filename: path.display().to_string(),
+    })
+}
+
+fn symlink(target: &Path, link: &Path) -> Result<(), NvmeError> {
+    std::os::unix::fs::symlink(target, link).context(FileIoFailed {
+        filename: link.display().to_string(),
+    })
+}
+
+/// Transports supported by the kernel target.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NvmetTransport {
+    Tcp,
+    Rdma,
+    Loop,
+}
+
+impl fmt::Display for NvmetTransport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Tcp => write!(f, "tcp"),
+            Self::Rdma => write!(f, "rdma"),
+            Self::Loop => write!(f, "loop"),
+        }
+    }
+}
+
+/// Asymmetric namespace access state of an ANA group on a port.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AnaState {
+    Optimized,
+    NonOptimized,
+    Inaccessible,
+    PersistentLoss,
+    Change,
+}
+
+impl fmt::Display for AnaState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Optimized => write!(f, "optimized"),
+            Self::NonOptimized => write!(f, "non-optimized"),
+            Self::Inaccessible => write!(f, "inaccessible"),
+            Self::PersistentLoss => write!(f, "persistent-loss"),
+            Self::Change => write!(f, "change"),
+        }
+    }
+}
+
+/// A host NQN which can be granted access to subsystems.
+#[derive(Debug)]
+pub struct NvmetHost {
+    nqn: String,
+    path: PathBuf,
+}
+
+impl NvmetHost {
+    /// Register the host NQN with the target.
+    pub fn new(nqn: &str) -> Result<Self, NvmeError> {
+        let path = Path::new(NVMET_CONFIGFS).join("hosts").join(nqn);
+        create_dir(&path)?;
+        Ok(Self {
+            nqn: nqn.to_string(),
+            path,
+        })
+    }
+
+    /// The NQN of the host.
+    pub fn nqn(&self) -> &str {
+        &self.nqn
+    }
+}
+
+impl Drop for NvmetHost {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+/// An NVMe subsystem exported by the kernel target.
+#[derive(Debug)]
+pub struct NvmetSubsystem {
+    nqn: String,
+    path: PathBuf,
+    namespaces: Vec<u32>,
+    hosts: Vec<String>,
+}
+
+impl NvmetSubsystem {
+    /// Create a new subsystem, by default any host is allowed to connect.
+    pub fn new(nqn: &str) -> Result<Self, NvmeError> {
+        let path = Path::new(NVMET_CONFIGFS).join("subsystems").join(nqn);
+        create_dir(&path)?;
+        let subsys = Self {
+            nqn: nqn.to_string(),
+            path,
+            namespaces: Vec::new(),
+            hosts: Vec::new(),
+        };
+        subsys.set_allow_any_host(true)?;
+        Ok(subsys)
+    }
+
+    /// The NQN of the subsystem.
+    pub fn nqn(&self) -> &str {
+        &self.nqn
+    }
+
+    /// Allow any host to connect, or only the hosts added with `allow_host`.
+    pub fn set_allow_any_host(&self, allow: bool) -> Result<(), NvmeError> {
+        write_attr(
+            &self.path,
+            "attr_allow_any_host",
+            if allow { "1" } else { "0" },
+        )
+    }
+
+    /// Set the serial number reported by the controllers of this subsystem.
+    pub fn set_serial(&self, serial: &str) -> Result<(), NvmeError> {
+        write_attr(&self.path, "attr_serial", serial)
+    }
+
+    /// Set the model number reported by the controllers of this subsystem.
+    pub fn set_model(&self, model: &str) -> Result<(), NvmeError> {
+        write_attr(&self.path, "attr_model", model)
+    }
+
+    /// Grant the given host access to this subsystem. Only has effect when
+    /// any host is not allowed.
+    pub fn allow_host(&mut self, host: &NvmetHost) -> Result<(), NvmeError> {
+        symlink(&host.path, &self.path.join("allowed_hosts").join(&host.nqn))?;
+        self.hosts.push(host.nqn.clone());
+        Ok(())
+    }
+
+    /// Revoke the access of the given host.
+    pub fn disallow_host(&mut self, nqn: &str) -> Result<(), NvmeError> {
+        let link = self.path.join("allowed_hosts").join(nqn);
+        fs::remove_file(&link).context(FileIoFailed {
+            filename: link.display().to_string(),
+        })?;
+        self.hosts.retain(|h| h != nqn);
+        Ok(())
+    }
+
+    fn ns_path(&self, nsid: u32) -> PathBuf {
+        self.path.join("namespaces").join(nsid.to_string())
+    }
+
+    /// Add and enable a namespace backed by the given block device or
+    /// regular file.
+    pub fn add_namespace<P: AsRef<Path>>(&mut self, nsid: u32, device: P) -> Result<(), NvmeError> {
+        let path = self.ns_path(nsid);
+        create_dir(&path)?;
+        self.namespaces.push(nsid);
+        write_attr(&path, "device_path", &device.as_ref().display().to_string())?;
+        self.enable_namespace(nsid, true)
+    }
+
+    /// Enable or disable the namespace, a namespace cannot be changed
+    /// while it is enabled.
+    pub fn enable_namespace(&self, nsid: u32, enable: bool) -> Result<(), NvmeError> {
+        write_attr(
+            &self.ns_path(nsid),
+            "enable",
+            if enable { "1" } else { "0" },
+        )
+    }
+
+    /// Set the UUID of a disabled namespace.
+    pub fn set_namespace_uuid(&self, nsid: u32, uuid: &uuid::Uuid) -> Result<(), NvmeError> {
+        write_attr(&self.ns_path(nsid), "device_uuid", &uuid.to_string())
+    }
+
+    /// Move the namespace into the given ANA group.
+    pub fn set_namespace_ana_group(&self, nsid: u32, grpid: u32) -> Result<(), NvmeError> {
+        write_attr(&self.ns_path(nsid), "ana_grpid", &grpid.to_string())
+    }
+
+    /// Make the target re-read the size of the backing device, after which
+    /// the hosts are notified of the capacity change.
+    pub fn revalidate_namespace_size(&self, nsid: u32) -> Result<(), NvmeError> {
+        write_attr(&self.ns_path(nsid), "revalidate_size", "1")
+    }
+
+    /// Disable and remove the namespace.
+    pub fn remove_namespace(&mut self, nsid: u32) -> Result<(), NvmeError> {
+        let path = self.ns_path(nsid);
+        self.enable_namespace(nsid, false)?;
+        fs::remove_dir(&path).context(FileIoFailed {
+            filename: path.display().to_string(),
+        })?;
+        self.namespaces.retain(|n| *n != nsid);
+        Ok(())
+    }
+}
+
+impl Drop for NvmetSubsystem {
+    fn drop(&mut self) {
+        for nsid in self.namespaces.clone() {
+            let _ = self.remove_namespace(nsid);
+        }
+        for nqn in self.hosts.clone() {
+            let _ = self.disallow_host(&nqn);
+        }
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+/// A port on which the kernel target listens and exports subsystems.
+#[derive(Debug)]
+pub struct NvmetPort {
+    id: u16,
+    path: PathBuf,
+    subsystems: Vec<String>,
+    ana_groups: Vec<u32>,
+}
+
+impl NvmetPort {
+    /// Create a new port, the address and service id are ignored for the loop
+    /// transport.
+    pub fn new(
+        id: u16,
+        transport: NvmetTransport,
+        traddr: &str,
+        trsvcid: u16,
+    ) -> Result<Self, NvmeError> {
+        let path = Path::new(NVMET_CONFIGFS).join("ports").join(id.to_string());
+        create_dir(&path)?;
+        let port = Self {
+            id,
+            path,
+            subsystems: Vec::new(),
+            ana_groups: Vec::new(),
+        };
+
+        write_attr(&port.path, "addr_trtype", &transport.to_string())?;
+        if transport != NvmetTransport::Loop {
+            let adrfam = if traddr.contains(':') { "ipv6" } else { "ipv4" };
+            write_attr(&port.path, "addr_adrfam", adrfam)?;
+            write_attr(&port.path, "addr_traddr", traddr)?;
+            write_attr(&port.path, "addr_trsvcid", &trsvcid.to_string())?;
+        }
+        Ok(port)
+    }
+
+    /// The port id.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Export the subsystem on this port.
+    pub fn add_subsystem(&mut self, subsys: &NvmetSubsystem) -> Result<(), NvmeError> {
+        symlink(
+            &subsys.path,
+            &self.path.join("subsystems").join(&subsys.nqn),
+        )?;
+        self.subsystems.push(subsys.nqn.clone());
+        Ok(())
+    }
+
+    /// Stop exporting the subsystem on this port.
+    pub fn remove_subsystem(&mut self, nqn: &str) -> Result<(), NvmeError> {
+        let link = self.path.join("subsystems").join(nqn);
+        fs::remove_file(&link).context(FileIoFailed {
+            filename: link.display().to_string(),
+        })?;
+        self.subsystems.retain(|s| s != nqn);
+        Ok(())
+    }
+
+    /// Set the ANA state of the given group on this port, creating the
+    /// group if it does not exist yet.
+    pub fn set_ana_state(&mut self, grpid: u32, state: AnaState) -> Result<(), NvmeError> {
+        let path = self.path.join("ana_groups").join(grpid.to_string());
+        if grpid != DEFAULT_ANA_GROUP && !self.ana_groups.contains(&grpid) {
+            create_dir(&path)?;
+            self.ana_groups.push(grpid);
+        }
+        write_attr(&path, "ana_state", &state.to_string())
+    }
+}
+
+impl Drop for NvmetPort {
+    fn drop(&mut self) {
+        for nqn in self.subsystems.clone() {
+            let _ = self.remove_subsystem(&nqn);
+        }
+        for grpid in &self.ana_groups {
+            let _ = fs::remove_dir(self.path.join("ana_groups").join(grpid.to_string()));
+        }
+        let _ = fs::remove_dir(&self.path);
+    }
+}
@@ -2,7 +2,7 @@ use std::{
     convert::TryFrom,
     fmt,
     fs::OpenOptions,
-    io::{ErrorKind, Read, Write},
+    io::{Read, Write},
     net::IpAddr,
     os::unix::io::AsRawFd,
     path::Path,
@@ -19,7 +19,10 @@ use snafu::ResultExt;
 
 use crate::{
     error,
-    nvme_page::{NvmeAdminCmd, NvmfDiscRspPageEntry, NvmfDiscRspPageHdr},
+    nvme_page::{
+        NvmeAdminCmd, NvmfDiscRspPage, NvmfDiscRspPageHdr, DISC_LOG_ENTRY_LEN, DISC_LOG_HDR_LEN,
+        DISC_LOG_MAX_ENTRIES,
+    },
     nvmf_subsystem::{NvmeSubsystems, Subsystem, SYSFS_NVME_CTRLR_PREFIX},
     NVME_ADMIN_CMD_IOCTL, NVME_FABRICS_PATH,
 };
@@ -232,13 +235,8 @@ impl Discovery {
         file.read_to_string(&mut buf).context(FileIoFailed {
             filename: NVME_FABRICS_PATH,
         })?;
-        // get the ctl=value from the controller
-        let v = buf.split(',').collect::<Vec<_>>()[0]
-            .split('=')
-            .collect::<Vec<_>>()[1];
-
-        let ctl_id = u32::from_str(v).unwrap();
-        self.ctl_id = ctl_id;
+        // get the instance=value from the controller
+        self.ctl_id = parse_instance(&buf)?;
         self.get_discovery_response_pages()?;
         Ok(&self.entries)
     }
@@ -252,12 +250,12 @@ impl Discovery {
             .context(FileIoFailed { filename: target })?;
 
         // See NVM-Express1_3d 5.14
-        let hdr_len = std::mem::size_of::<NvmfDiscRspPageHdr>() as u32;
-        let h = NvmfDiscRspPageHdr::default();
+        let hdr_len = DISC_LOG_HDR_LEN as u32;
+        let mut buffer = vec![0u8; DISC_LOG_HDR_LEN];
         let mut cmd = NvmeAdminCmd {
             opcode: 0x02,
             nsid: 0,
-            dptr: &h as *const _ as u64,
+            dptr: buffer.as_mut_ptr() as u64,
             dptr_len: hdr_len,
             ..Default::default()
         };
@@ -280,7 +278,7 @@ impl Discovery {
             .context(NvmeDiscoveryFailed)?;
         }
 
-        Ok(h.numrec)
+        Ok(NvmfDiscRspPageHdr::decode(&buffer)?.numrec)
     }
 
     // note we can only transfer max_io size. This means that if the number of
@@ -299,17 +297,19 @@ impl Discovery {
             .context(FileIoFailed { filename: target })?;
 
         let count = self.get_discovery_response_page_entries()?;
+        if count > DISC_LOG_MAX_ENTRIES {
+            return Err(NvmeError::InvalidDiscoveryLog {
+                text: format!("target reported {count} records"),
+            });
+        }
 
-        let hdr_len = std::mem::size_of::<NvmfDiscRspPageHdr>();
-        let response_len = std::mem::size_of::<NvmfDiscRspPageEntry>();
-        let total_length = hdr_len + (response_len * count as usize);
-        // TODO: fixme
-        let buffer = unsafe { libc::calloc(1, total_length) };
+        let total_length = DISC_LOG_HDR_LEN + (DISC_LOG_ENTRY_LEN * count as usize);
+        let mut buffer = vec![0u8; total_length];
 
         let mut cmd = NvmeAdminCmd {
             opcode: 0x02,
             nsid: 0,
-            dptr: buffer as _,
+            dptr: buffer.as_mut_ptr() as _,
             dptr_len: total_length as _,
             ..Default::default()
         };
@@ -321,65 +321,40 @@ impl Discovery {
         cmd.cdw10 = 0x70 | u32::from(numdl) << 16_u32;
         cmd.cdw11 = u32::from(numdu);
 
-        let ret = unsafe {
+        unsafe {
             convert_ioctl_res!(nix_ioctl(
                 f.as_raw_fd(),
                 u64::from(NVME_ADMIN_CMD_IOCTL),
                 &cmd
             ))
-        };
-
-        if let Err(e) = ret {
-            unsafe { libc::free(buffer) };
-            return Err(NvmeError::NvmeDiscoveryFailed { source: e });
+            .context(NvmeDiscoveryFailed)?;
         }
 
-        let hdr = unsafe { &mut *(buffer as *mut NvmfDiscRspPageHdr) };
-        let entries = unsafe { hdr.entries.as_slice(hdr.numrec as usize) };
+        let page = NvmfDiscRspPage::decode(&buffer)?;
 
-        for e in entries {
+        for e in page.entries {
             let tr_type = TrType::from_u8(e.trtype);
             let adr_fam = AddressFamily::from_u8(e.adrfam);
             let subtype = SubType::from_u8(e.subtype);
 
-            if tr_type.is_none() || adr_fam.is_none() || subtype.is_none() {
+            let (Some(tr_type), Some(adr_fam), Some(subtype)) = (tr_type, adr_fam, subtype) else {
                 eprintln!("Invalid discovery record, skipping");
                 continue;
-            }
+            };
 
             let record = DiscoveryLogEntry {
-                tr_type: tr_type.unwrap(),
-                adr_fam: adr_fam.unwrap(),
+                tr_type,
+                adr_fam,
                 port_id: u32::from(e.portid),
-                subtype: subtype.unwrap(),
-
-                trsvcid: unsafe {
-                    std::ffi::CStr::from_ptr(&e.trsvcid[0])
-                        .to_string_lossy()
-                        .into_owned()
-                        .trim()
-                        .into()
-                },
-                traddr: unsafe {
-                    std::ffi::CStr::from_ptr(&e.traddr[0])
-                        .to_string_lossy()
-                        .into_owned()
-                        .trim()
-                        .into()
-                },
-                subnqn: unsafe {
-                    std::ffi::CStr::from_ptr(&e.subnqn[0])
-                        .to_string_lossy()
-                        .into_owned()
-                        .trim()
-                        .into()
-                },
+                subtype,
+                trsvcid: e.trsvcid,
+                traddr: e.traddr,
+                subnqn: e.subnqn,
             };
             self.entries.push(record);
         }
 
         self.remove_controller()?;
-        unsafe { libc::free(buffer) };
         Ok(self.entries.len())
     }
 
@@ -454,6 +429,29 @@ impl Discovery {
     }
 }
 
+/// Parse the controller instance out of the nvme-fabrics response, which has
+/// the following format: instance=X,cntlid=Y.
+fn parse_instance(s: &str) -> Result<u32, NvmeError> {
+    s.trim()
+        .split(',')
+        .filter_map(|p| p.split_once('='))
+        .find(|(key, _)| *key == "instance")
+        .ok_or_else(|| NvmeError::ValueParseFailed {
+            path: NVME_FABRICS_PATH.to_string(),
+            contents: s.to_string(),
+            error: "Missing controller instance".to_string(),
+        })
+        .and_then(|(_, value)| {
+            value
+                .parse::<u32>()
+                .map_err(|e| NvmeError::ValueParseFailed {
+                    path: NVME_FABRICS_PATH.to_string(),
+                    contents: s.to_string(),
+                    error: e.to_string(),
+                })
+        })
+}
+
 impl FromStr for Subsystem {
     type Err = NvmeError;
 
@@ -557,6 +555,12 @@ pub struct ConnectArgs {
     hostnqn: Option<String>,
     #[builder(default = "None")]
     hostid: Option<String>,
+    /// DH-HMAC-CHAP secret of the host
+    #[builder(default = "None")]
+    dhchap_secret: Option<String>,
+    /// DH-HMAC-CHAP secret of the controller, for bi-directional auth
+    #[builder(default = "None")]
+    dhchap_ctrl_secret: Option<String>,
 }
 
 impl ConnectArgsBuilder {
@@ -611,6 +615,12 @@ impl fmt::Display for ConnectArgs {
         if let Some(val) = self.nr_io_queues {
             write!(f, ",nr_io_queues={val}")?;
         }
+        if let Some(val) = &self.dhchap_secret {
+            write!(f, ",dhchap_secret={val}")?;
+        }
+        if let Some(val) = &self.dhchap_ctrl_secret {
+            write!(f, ",dhchap_ctrl_secret={val}")?;
+        }
         Ok(())
     }
 }
@@ -652,10 +662,7 @@ impl ConnectArgs {
                 })?;
         let args = format!("{self}");
         if let Err(e) = file.write_all(args.as_bytes()) {
-            return match e.kind() {
-                ErrorKind::AlreadyExists => Err(NvmeError::ConnectInProgress),
-                _ => Err(NvmeError::IoFailed { source: e, args }),
-            };
+            return Err(NvmeError::from_connect(e, redact_secrets(&args)));
         }
         let mut buf = String::new();
         file.read_to_string(&mut buf).context(ConnectFailed {
@@ -665,6 +672,20 @@ impl ConnectArgs {
     }
 }
 
+/// Replace the values of any secrets within a connect string, such that it
+/// can safely be part of an error or log message.
+fn redact_secrets(args: &str) -> String {
+    args.split(',')
+        .map(|token| match token.split_once('=') {
+            Some((key, _)) if key.ends_with("secret") || key.ends_with("key") => {
+                format!("{key}=<redacted>")
+            }
+            _ => token.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
 /// This method disconnects a specific NVMf device, identified by its nqn.
 ///
 ///  # Example
@@ -683,3 +704,56 @@ pub fn disconnect(nqn: &str) -> Result<usize, NvmeError> {
         .collect();
     Ok(subsys?.len())
 }
+
+#[test]
+fn connect_error_classification() {
+    let args = ConnectArgsBuilder::default()
+        .traddr("127.0.0.1")
+        .trsvcid("4420")
+        .nqn("nqn.a")
+        .hostnqn(Some("nqn.host".to_string()))
+        .hostid(Some("id".to_string()))
+        .dhchap_secret(Some("DHHC-1:00:c2VjcmV0:".to_string()))
+        .build()
+        .unwrap()
+        .to_string();
+    let redacted = redact_secrets(&args);
+    assert!(args.contains("DHHC-1"));
+    assert!(!redacted.contains("DHHC-1"));
+    assert!(redacted.contains("dhchap_secret=<redacted>"));
+    assert!(redacted.contains("traddr=127.0.0.1"));
+
+    let error =
+        |errno| NvmeError::from_connect(std::io::Error::from_raw_os_error(errno), redacted.clone());
+    assert!(matches!(
+        error(libc::EALREADY),
+        NvmeError::ConnectInProgress
+    ));
+    assert!(matches!(
+        error(libc::ECONNREFUSED),
+        NvmeError::TargetUnreachable { .. }
+    ));
+    assert!(matches!(
+        error(libc::EPERM),
+        NvmeError::InvalidHostNqn { .. }
+    ));
+    assert!(matches!(
+        error(libc::EKEYREJECTED),
+        NvmeError::AuthFailed { .. }
+    ));
+    assert!(matches!(
+        error(libc::ENODEV),
+        NvmeError::ConnectNqnNotFound { .. }
+    ));
+    assert!(matches!(
+        error(libc::EUSERS),
+        NvmeError::TooManyControllers { .. }
+    ));
+    assert!(matches!(error(libc::EIO), NvmeError::IoFailed { .. }));
+
+    assert!(error(libc::EHOSTUNREACH).is_retryable());
+    assert!(error(libc::EALREADY).is_retryable());
+    assert!(!error(libc::EKEYREJECTED).is_retryable());
+    assert!(!error(libc::EIO).is_retryable());
+    assert!(!error(libc::EPERM).to_string().contains("DHHC-1"));
+}
@@ -1,13 +1,27 @@
-use crate::{error, nvmf_discovery::TrType, parse_value};
+use crate::{
+    error,
+    nvme_namespaces::{NvmeDevice, NvmeDeviceList},
+    nvmf_discovery::TrType,
+    parse_value,
+};
 use error::{
     nvme_error::{FileIoFailed, InvalidPath, SubsystemFailure},
     NvmeError,
 };
 use glob::glob;
 use snafu::ResultExt;
-use std::{collections::HashMap, fs::OpenOptions, io::Write, path::Path, str::FromStr};
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    io::Write,
+    path::Path,
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
 pub const SYSFS_NVME_CTRLR_PREFIX: &str = "/sys/devices/virtual/nvme-fabrics/ctl";
+/// How often the namespaces are checked while waiting for a rescan to complete.
+const RESCAN_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 /// Subsystem struct shows us all the connect fabrics. This does not include
 /// NVMe devices that are connected by trtype=PCIe.
@@ -158,7 +172,13 @@ impl Subsystem {
             })?
             .display()
             .to_string();
-        let instance = u32::from_str(name.trim_start_matches("nvme")).unwrap();
+        let instance = u32::from_str(name.trim_start_matches("nvme")).map_err(|e| {
+            NvmeError::ValueParseFailed {
+                path: format!("{source:?}"),
+                contents: name.clone(),
+                error: e.to_string(),
+            }
+        })?;
         let nqn = parse_value::<String>(source, "subsysnqn")?;
         let state = parse_value::<String>(source, "state")?;
         let transport = parse_value::<String>(source, "transport")?;
@@ -212,6 +232,49 @@ impl Subsystem {
         file.write_all(b"1").context(FileIoFailed { filename })?;
         Ok(())
     }
+
+    /// Issue a rescan to the controller and wait until the namespaces of the
+    /// subsystem satisfy the given predicate, for example until a resized
+    /// namespace reports its new size or a namespace with a given NSID
+    /// appears. Returns the refreshed devices.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # fn wait(subsystem: nvmeadm::nvmf_subsystem::Subsystem) {
+    /// let devices = subsystem.rescan_and_wait(
+    ///     |devices| devices.iter().any(|d| d.size() >= 1 << 30),
+    ///     std::time::Duration::from_secs(10),
+    /// );
+    /// # }
+    /// ```
+    pub fn rescan_and_wait<F>(
+        &self,
+        predicate: F,
+        timeout: Duration,
+    ) -> Result<Vec<NvmeDevice>, NvmeError>
+    where
+        F: Fn(&[NvmeDevice]) -> bool,
+    {
+        self.rescan()?;
+        wait_for_namespaces(&self.nqn, predicate, timeout)
+    }
+
+    /// Same as `rescan_and_wait` but issues the rescan to all controllers
+    /// (paths) connected to the given nqn.
+    pub fn rescan_nqn_and_wait<F>(
+        nqn: &str,
+        predicate: F,
+        timeout: Duration,
+    ) -> Result<Vec<NvmeDevice>, NvmeError>
+    where
+        F: Fn(&[NvmeDevice]) -> bool,
+    {
+        for subsystem in Self::try_from_nqn(nqn)? {
+            subsystem.rescan()?;
+        }
+        wait_for_namespaces(nqn, predicate, timeout)
+    }
+
     /// Disconnects the transport dropping all namespaces.
     pub fn disconnect(&self) -> Result<(), NvmeError> {
         let filename = format!("/sys/class/nvme/{}/delete_controller", self.name);
@@ -287,6 +350,31 @@ impl Subsystem {
     }
 }
 
+/// Poll the namespaces of the given nqn until they satisfy the predicate.
+fn wait_for_namespaces<F>(
+    nqn: &str,
+    predicate: F,
+    timeout: Duration,
+) -> Result<Vec<NvmeDevice>, NvmeError>
+where
+    F: Fn(&[NvmeDevice]) -> bool,
+{
+    let start = Instant::now();
+    loop {
+        let devices = NvmeDeviceList::for_nqn(nqn);
+        if predicate(&devices) {
+            return Ok(devices);
+        }
+        if start.elapsed() >= timeout {
+            return Err(NvmeError::WaitTimeout {
+                nqn: nqn.to_string(),
+                timeout,
+            });
+        }
+        std::thread::sleep(RESCAN_POLL_INTERVAL);
+    }
+}
+
 /// List of subsystems found on the system.
 #[derive(Default, Debug)]
 pub struct NvmeSubsystems {
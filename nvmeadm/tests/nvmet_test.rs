@@ -0,0 +1,161 @@
+use nvmeadm::{
+    nvmet::{is_available, AnaState, NvmetHost, NvmetPort, NvmetSubsystem, NvmetTransport},
+    nvmf_discovery::{disconnect, ConnectArgsBuilder, DiscoveryBuilder},
+    nvmf_subsystem::Subsystem,
+};
+
+use std::{fs::File, time::Duration};
+
+// the tests run in parallel so each of them uses its own nqn, port and file
+const NQN: &str = "nqn.2019-05.io.openebs:nvmet-test";
+
+fn backing_file(name: &str, size: u64) -> String {
+    let path = format!("/tmp/nvmeadm_nvmet_{name}.img");
+    File::create(&path)
+        .and_then(|f| f.set_len(size))
+        .expect("Failed to create the backing file");
+    path
+}
+
+fn connect(nqn: &str, port: u16) -> Subsystem {
+    ConnectArgsBuilder::default()
+        .traddr("127.0.0.1")
+        .trsvcid(port.to_string())
+        .nqn(nqn)
+        .build()
+        .unwrap()
+        .connect()
+        .expect("Failed to connect")
+}
+
+/// Read the ANA state of the paths of the given controller.
+fn ana_state(ctrl: &str) -> Vec<String> {
+    glob::glob(&format!("/sys/class/nvme/{ctrl}/nvme*c*n*/ana_state"))
+        .unwrap()
+        .flatten()
+        .filter_map(|p| std::fs::read_to_string(p).ok())
+        .map(|s| s.trim().to_string())
+        .collect()
+}
+
+#[test]
+fn nvmet_discovery_and_connect() {
+    if !is_available() {
+        println!("nvmet not available, skipping");
+        return;
+    }
+    let nqn = format!("{NQN}:discovery");
+    let file = backing_file("discovery", 64 * 1024 * 1024);
+    let mut subsys = NvmetSubsystem::new(&nqn).unwrap();
+    subsys.add_namespace(1, &file).unwrap();
+    let mut port = NvmetPort::new(11, NvmetTransport::Tcp, "127.0.0.1", 9530).unwrap();
+    port.add_subsystem(&subsys).unwrap();
+
+    let mut explorer = DiscoveryBuilder::default()
+        .transport("tcp".to_string())
+        .traddr("127.0.0.1".to_string())
+        .trsvcid(9530)
+        .build()
+        .unwrap();
+    let entries = explorer.discover().expect("Discovery failed");
+    assert!(entries.iter().any(|e| e.subnqn == nqn));
+
+    let subsystem = explorer.connect(&nqn).expect("Failed to connect");
+    let devices = subsystem
+        .rescan_and_wait(|d| d.len() == 1, Duration::from_secs(5))
+        .unwrap();
+    assert_eq!(devices[0].size(), 64 * 1024 * 1024);
+
+    // grow the namespace and wait for the host to notice
+    backing_file("discovery", 128 * 1024 * 1024);
+    subsys.revalidate_namespace_size(1).unwrap();
+    subsystem
+        .rescan_and_wait(
+            |d| d.iter().any(|d| d.size() == 128 * 1024 * 1024),
+            Duration::from_secs(5),
+        )
+        .expect("Resize was not reflected");
+
+    assert_eq!(disconnect(&nqn).unwrap(), 1);
+    drop(port);
+    drop(subsys);
+    let _ = std::fs::remove_file(file);
+}
+
+#[test]
+fn nvmet_allowed_hosts() {
+    if !is_available() {
+        println!("nvmet not available, skipping");
+        return;
+    }
+    let nqn = format!("{NQN}:hosts");
+    let file = backing_file("hosts", 64 * 1024 * 1024);
+    let host = NvmetHost::new("nqn.2014-08.org.nvmexpress:uuid:nvmet-test").unwrap();
+    let mut subsys = NvmetSubsystem::new(&nqn).unwrap();
+    subsys.add_namespace(1, &file).unwrap();
+    subsys.set_allow_any_host(false).unwrap();
+    let mut port = NvmetPort::new(12, NvmetTransport::Tcp, "127.0.0.1", 9531).unwrap();
+    port.add_subsystem(&subsys).unwrap();
+
+    let args = |hostnqn: &str| {
+        ConnectArgsBuilder::default()
+            .traddr("127.0.0.1")
+            .trsvcid("9531")
+            .nqn(&nqn)
+            .hostnqn(Some(hostnqn.to_string()))
+            .build()
+            .unwrap()
+    };
+    args("nqn.2014-08.org.nvmexpress:uuid:not-allowed")
+        .connect()
+        .expect_err("Host should not be allowed to connect");
+
+    subsys.allow_host(&host).unwrap();
+    args(host.nqn()).connect().expect("Host should be allowed");
+
+    assert_eq!(disconnect(&nqn).unwrap(), 1);
+    drop(port);
+    drop(subsys);
+    let _ = std::fs::remove_file(file);
+}
+
+#[test]
+fn nvmet_multipath_ana() {
+    if !is_available() {
+        println!("nvmet not available, skipping");
+        return;
+    }
+    let nqn = format!("{NQN}:multipath");
+    let file = backing_file("multipath", 64 * 1024 * 1024);
+    let mut subsys = NvmetSubsystem::new(&nqn).unwrap();
+    subsys.add_namespace(1, &file).unwrap();
+    let mut ports = [(13, 9532), (14, 9533)]
+        .iter()
+        .map(|(id, p)| {
+            let mut port = NvmetPort::new(*id, NvmetTransport::Tcp, "127.0.0.1", *p).unwrap();
+            port.add_subsystem(&subsys).unwrap();
+            port
+        })
+        .collect::<Vec<_>>();
+
+    let paths = [9532, 9533]
+        .iter()
+        .map(|p| connect(&nqn, *p))
+        .collect::<Vec<_>>();
+    assert_eq!(Subsystem::try_from_nqn(&nqn).unwrap().len(), 2);
+
+    // make the second path inaccessible and wait for the host to pick it up
+    ports[1].set_ana_state(1, AnaState::Inaccessible).unwrap();
+    let mut retries = 50;
+    while ana_state(&paths[1].name) != vec!["inaccessible".to_string()] && retries > 0 {
+        std::thread::sleep(Duration::from_millis(100));
+        retries -= 1;
+    }
+    assert_eq!(ana_state(&paths[1].name), vec!["inaccessible".to_string()]);
+    assert_eq!(ana_state(&paths[0].name), vec!["optimized".to_string()]);
+
+    assert_eq!(disconnect(&nqn).unwrap(), 2);
+    ports.clear();
+    drop(subsys);
+    let _ = std::fs::remove_file(file);
+}